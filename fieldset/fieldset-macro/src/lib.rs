@@ -1,7 +1,7 @@
 use heck::{ToShoutySnakeCase, ToUpperCamelCase};
 use proc_macro::TokenStream;
 use quote::{format_ident, quote};
-use syn::{parse_macro_input, DeriveInput, Field, FieldsNamed, Ident, Type};
+use syn::{parse_macro_input, DeriveInput, Expr, Field, Fields, Ident, Index, Member, Type};
 
 fn is_fieldset(field: Field) -> bool {
     field
@@ -11,6 +11,8 @@ fn is_fieldset(field: Field) -> bool {
         .any(|i| *i == format_ident!("fieldset"))
 }
 
+/// Identifier of a nested fieldset type, or of the element type of an array
+/// of nested fieldsets.
 fn get_type_identifier(ty: Type) -> Ident {
     match ty {
         Type::Path(p) => {
@@ -20,31 +22,59 @@ fn get_type_identifier(ty: Type) -> Ident {
                 .expect("field type must be a path with an identifier")
                 .clone()
         }
-        _ => panic!("unsupported field type"),
+        Type::Array(a) => get_type_identifier(*a.elem),
+        Type::Paren(p) => get_type_identifier(*p.elem),
+        _ => panic!("`#[fieldset]` fields must be a fieldset or an array of fieldsets"),
     }
 }
 
-fn get_field_identifier(field: Field) -> Ident {
+/// Length of an array of nested fieldsets, `None` for a single one.
+fn get_array_len(ty: Type) -> Option<Expr> {
+    match ty {
+        Type::Array(a) => Some(a.len),
+        Type::Paren(p) => get_array_len(*p.elem),
+        _ => None,
+    }
+}
+
+/// Name of the setter method of a field. Fields of tuple structs are named
+/// after their position, `field_0`, `field_1`, ...
+fn get_field_identifier(field: Field, index: usize) -> Ident {
     field
         .ident
-        .expect("Cannot derive field type from tuple structs")
+        .unwrap_or_else(|| format_ident!("field_{}", index))
+}
+
+/// How a field is accessed on the deriving struct itself.
+fn get_field_member(field: Field, index: usize) -> Member {
+    match field.ident {
+        Some(ident) => Member::Named(ident),
+        None => Member::Unnamed(Index::from(index)),
+    }
+}
+
+fn get_variant_identifier(field: Field, index: usize) -> Ident {
+    format_ident!(
+        "{}",
+        get_field_identifier(field, index)
+            .to_string()
+            .to_upper_camel_case()
+    )
 }
 
-fn derive_field_type(name: String, fields: FieldsNamed) -> TokenStream {
+fn derive_field_type(name: String, fields: Fields) -> TokenStream {
     let derived_field_type_identifier = format_ident!("{}FieldType", name);
     let enum_variants = {
         let mut res = Vec::new();
-        for field in fields.named {
-            let variant_name = format_ident!(
-                "{}",
-                get_field_identifier(field.clone())
-                    .to_string()
-                    .to_upper_camel_case()
-            );
+        for (index, field) in fields.into_iter().enumerate() {
+            let variant_name = get_variant_identifier(field.clone(), index);
             if is_fieldset(field.clone()) {
-                let type_identifier = get_type_identifier(field.ty);
+                let type_identifier = get_type_identifier(field.ty.clone());
                 let field_type_identifier = format_ident!("{}FieldType", type_identifier);
-                res.push(quote!(#variant_name(#field_type_identifier)));
+                match get_array_len(field.ty) {
+                    Some(_) => res.push(quote!(#variant_name(usize, #field_type_identifier))),
+                    None => res.push(quote!(#variant_name(#field_type_identifier))),
+                }
             } else {
                 let ty = field.ty;
                 res.push(quote!(#variant_name(#ty)));
@@ -61,23 +91,21 @@ fn derive_field_type(name: String, fields: FieldsNamed) -> TokenStream {
     .into()
 }
 
-fn derive_apply_method(name: String, fields: FieldsNamed) -> TokenStream {
+fn derive_apply_method(name: String, fields: Fields) -> TokenStream {
     let identifier = format_ident!("{}", name);
     let field_type_identifier = format_ident!("{}FieldType", name);
     let match_arms = {
         let mut res = Vec::new();
-        for field in fields.named {
-            let field_identifier = get_field_identifier(field.clone());
-            let variant_name = format_ident!(
-                "{}",
-                field_identifier.clone().to_string().to_upper_camel_case()
-            );
+        for (index, field) in fields.into_iter().enumerate() {
+            let member = get_field_member(field.clone(), index);
+            let variant_name = get_variant_identifier(field.clone(), index);
             if is_fieldset(field.clone()) {
-                res.push(quote!(#field_type_identifier::#variant_name(x) => self.#field_identifier.apply(x)));
+                match get_array_len(field.ty) {
+                    Some(_) => res.push(quote!(#field_type_identifier::#variant_name(i, x) => self.#member[i].apply(x))),
+                    None => res.push(quote!(#field_type_identifier::#variant_name(x) => self.#member.apply(x))),
+                }
             } else {
-                res.push(
-                    quote!(#field_type_identifier::#variant_name(x) => self.#field_identifier = x),
-                );
+                res.push(quote!(#field_type_identifier::#variant_name(x) => self.#member = x));
             }
         }
         res
@@ -94,17 +122,20 @@ fn derive_apply_method(name: String, fields: FieldsNamed) -> TokenStream {
     .into()
 }
 
-fn derive_setter_trait(name: String, fields: FieldsNamed) -> TokenStream {
+fn derive_setter_trait(name: String, fields: Fields) -> TokenStream {
     let derived_setter_trait_identifier = format_ident!("{}FieldSetters", name);
     let methods = {
         let mut res = Vec::new();
-        for field in fields.named {
-            let method_name = get_field_identifier(field.clone());
+        for (index, field) in fields.into_iter().enumerate() {
+            let method_name = get_field_identifier(field.clone(), index);
             if is_fieldset(field.clone()) {
-                let type_identifier = get_type_identifier(field.ty);
+                let type_identifier = get_type_identifier(field.ty.clone());
                 let field_setter_trait_identifier =
                     format_ident!("{}FieldSetters", type_identifier);
-                res.push(quote!(fn #method_name(&mut self) -> impl #field_setter_trait_identifier));
+                match get_array_len(field.ty) {
+                    Some(_) => res.push(quote!(fn #method_name(&mut self, index: usize) -> impl #field_setter_trait_identifier)),
+                    None => res.push(quote!(fn #method_name(&mut self) -> impl #field_setter_trait_identifier)),
+                }
             } else {
                 let ty = field.ty;
                 res.push(quote!(fn #method_name(&mut self) -> impl FieldSetter<#ty>));
@@ -125,17 +156,24 @@ fn get_variance_identifier(ty: Ident) -> Ident {
     format_ident!("{}_VARIANCE", ty.to_string().to_shouty_snake_case())
 }
 
-fn derive_fieldset_variance(name: String, fields: FieldsNamed) -> TokenStream {
+/// Number of leaf fields a `#[fieldset]` field expands to.
+fn get_nested_variance(ty: Type) -> proc_macro2::TokenStream {
+    let variance_identifier = get_variance_identifier(get_type_identifier(ty.clone()));
+    match get_array_len(ty) {
+        Some(len) => quote!(#variance_identifier * (#len)),
+        None => quote!(#variance_identifier),
+    }
+}
+
+fn derive_fieldset_variance(name: String, fields: Fields) -> TokenStream {
     let identifier = format_ident!("{}", name);
     let variance_identifier = get_variance_identifier(identifier);
     let variance = {
         let mut variances = Vec::new();
         let mut field_count: usize = 0;
-        for field in fields.named {
+        for field in fields {
             if is_fieldset(field.clone()) {
-                let type_identifier = get_type_identifier(field.ty);
-                let variance_identifier = get_variance_identifier(type_identifier);
-                variances.push(quote!(#variance_identifier));
+                variances.push(get_nested_variance(field.ty));
             } else {
                 field_count += 1;
             }
@@ -148,51 +186,71 @@ fn derive_fieldset_variance(name: String, fields: FieldsNamed) -> TokenStream {
     .into()
 }
 
-fn derive_opt_fieldset_type(name: String, fields: FieldsNamed) -> TokenStream {
+fn derive_opt_fieldset_type(name: String, fields: Fields) -> TokenStream {
     let derived_fieldset_identifier = format_ident!("{}OptFieldSet", name);
-    let opt_fields = {
-        let mut res = Vec::new();
-        for field in fields.named {
-            let field_identifier = get_field_identifier(field.clone());
-            if is_fieldset(field.clone()) {
-                let type_identifier = get_type_identifier(field.ty);
-                let fieldset_identifier = format_ident!("{}OptFieldSet", type_identifier);
-                res.push(quote!(#field_identifier : #fieldset_identifier));
-            } else {
-                let ty = field.ty;
-                res.push(quote!(#field_identifier : Option<#ty>))
+    let mut opt_fields = Vec::new();
+    let mut initializers = Vec::new();
+    for (index, field) in fields.into_iter().enumerate() {
+        let field_identifier = get_field_identifier(field.clone(), index);
+        if is_fieldset(field.clone()) {
+            let type_identifier = get_type_identifier(field.ty.clone());
+            let fieldset_identifier = format_ident!("{}OptFieldSet", type_identifier);
+            match get_array_len(field.ty) {
+                Some(len) => {
+                    opt_fields.push(quote!(#field_identifier : [#fieldset_identifier ; #len]));
+                    // `Default` is only implemented for arrays of up to 32 elements.
+                    initializers.push(quote!(#field_identifier : core::array::from_fn(|_| #fieldset_identifier::new())));
+                }
+                None => {
+                    opt_fields.push(quote!(#field_identifier : #fieldset_identifier));
+                    initializers.push(quote!(#field_identifier : #fieldset_identifier::new()));
+                }
             }
+        } else {
+            let ty = field.ty;
+            opt_fields.push(quote!(#field_identifier : Option<#ty>));
+            initializers.push(quote!(#field_identifier : None));
         }
-        res
-    };
+    }
     quote!(
-        #[derive(Debug, Default)]
+        #[derive(Debug)]
         pub struct #derived_fieldset_identifier {
             #(#opt_fields ,)*
         }
 
         impl #derived_fieldset_identifier {
             pub fn new() -> Self {
-                Default::default()
+                Self {
+                    #(#initializers ,)*
+                }
+            }
+        }
+
+        impl Default for #derived_fieldset_identifier {
+            fn default() -> Self {
+                Self::new()
             }
         }
     )
     .into()
 }
 
-fn derive_opt_fieldset_setter_trait_impl(name: String, fields: FieldsNamed) -> TokenStream {
+fn derive_opt_fieldset_setter_trait_impl(name: String, fields: Fields) -> TokenStream {
     let setter_trait_identifier = format_ident!("{}FieldSetters", name);
     let fieldset_identifier = format_ident!("{}OptFieldSet", name);
     let methods = {
         let mut res = Vec::new();
-        for field in fields.named {
-            let field_name = get_field_identifier(field.clone());
+        for (index, field) in fields.into_iter().enumerate() {
+            let field_name = get_field_identifier(field.clone(), index);
             let method_name = field_name.clone();
             if is_fieldset(field.clone()) {
-                let type_identifier = get_type_identifier(field.ty);
+                let type_identifier = get_type_identifier(field.ty.clone());
                 let field_setter_trait_identifier =
                     format_ident!("{}FieldSetters", type_identifier);
-                res.push(quote!(fn #method_name(&mut self) -> impl #field_setter_trait_identifier { &mut self.#field_name }));
+                match get_array_len(field.ty) {
+                    Some(_) => res.push(quote!(fn #method_name(&mut self, index: usize) -> impl #field_setter_trait_identifier { &mut self.#field_name[index] })),
+                    None => res.push(quote!(fn #method_name(&mut self) -> impl #field_setter_trait_identifier { &mut self.#field_name })),
+                }
             } else {
                 let ty = field.ty;
                 res.push(
@@ -215,19 +273,19 @@ fn derive_opt_fieldset_setter_trait_impl(name: String, fields: FieldsNamed) -> T
     .into()
 }
 
-fn derive_opt_fieldset_into_iterator(name: String, fields: FieldsNamed) -> TokenStream {
+fn derive_opt_fieldset_into_iterator(name: String, fields: Fields) -> TokenStream {
     let fieldset_identifier = format_ident!("{}OptFieldSet", name);
     let fieldtype_identifier = format_ident!("{}FieldType", name);
     let iter_chains = {
         let mut res = Vec::new();
-        for field in fields.named {
-            let field_identifier = get_field_identifier(field.clone());
-            let variant_name = format_ident!(
-                "{}",
-                field_identifier.clone().to_string().to_upper_camel_case()
-            );
+        for (index, field) in fields.into_iter().enumerate() {
+            let field_identifier = get_field_identifier(field.clone(), index);
+            let variant_name = get_variant_identifier(field.clone(), index);
             if is_fieldset(field.clone()) {
-                res.push(quote!(let iter = iter.chain(self.#field_identifier.opt_iter().map(|x| x.map(#fieldtype_identifier::#variant_name)))));
+                match get_array_len(field.ty) {
+                    Some(_) => res.push(quote!(let iter = iter.chain(self.#field_identifier.into_iter().enumerate().flat_map(|(i, x)| x.opt_iter().map(move |x| x.map(|x| #fieldtype_identifier::#variant_name(i, x))))))),
+                    None => res.push(quote!(let iter = iter.chain(self.#field_identifier.opt_iter().map(|x| x.map(#fieldtype_identifier::#variant_name))))),
+                }
             } else {
                 res.push(quote!(let iter = iter.chain(once(self.#field_identifier.map(#fieldtype_identifier::#variant_name)))));
             }
@@ -259,78 +317,72 @@ fn derive_opt_fieldset_into_iterator(name: String, fields: FieldsNamed) -> Token
     .into()
 }
 
+/// Setter methods writing into a flat set of fields. Every leaf field has a
+/// fixed index in the set, counted from `offset_expr`, and nested fieldsets
+/// get setters for the range of indices after it.
+#[allow(clippy::too_many_arguments)]
 fn common_trait_impl_methods(
     bitset_expr: proc_macro2::TokenStream,
     fields_expr: proc_macro2::TokenStream,
     len_expr: proc_macro2::TokenStream,
+    offset_expr: proc_macro2::TokenStream,
     fun_expr: proc_macro2::TokenStream,
     is_bitset: bool,
     name: String,
-    fields: FieldsNamed,
+    fields: Fields,
 ) -> proc_macro2::TokenStream {
     let fieldtype_identifier = format_ident!("{}FieldType", name);
     let mut res = Vec::new();
-    let mut prev_expr = None;
-    let mut index: usize = 0;
-    for field in fields.named {
-        let method_name = get_field_identifier(field.clone());
-        let field_name_upper = format_ident!("{}", method_name.to_string().to_upper_camel_case());
-        let index_expr = match (prev_expr.clone(), index) {
-            (None, 0) => None,
-            (None, y) => Some(quote!(#y)),
-            (Some(x), 0) => Some(x),
-            (Some(x), y) => Some(quote!(#x + #y)),
-        };
+    let mut variances = Vec::new();
+    let mut leaf_count: usize = 0;
+    for (index, field) in fields.into_iter().enumerate() {
+        let method_name = get_field_identifier(field.clone(), index);
+        let field_name_upper = get_variant_identifier(field.clone(), index);
+        let index_expr = quote!(#offset_expr + #( #variances +)* #leaf_count);
         if is_fieldset(field.clone()) {
-            let type_identifier = get_type_identifier(field.ty);
-            let start_expr = match index_expr.clone() {
-                Some(x) => quote!(#x),
-                None => quote!(),
-            };
-            let start_bit_expr = if is_bitset {
-                match index_expr.clone() {
-                    Some(x) => quote!((#x) / 32),
-                    None => quote!(),
-                }
-            } else {
-                start_expr.clone()
-            };
-            let variance_identifier = get_variance_identifier(type_identifier.clone());
-            let end_expr = match index_expr.clone() {
-                Some(x) => quote!(#x + #variance_identifier),
-                None => quote!(#variance_identifier),
-            };
-            let end_bit_expr = if is_bitset {
-                match index_expr.clone() {
-                    Some(x) => quote!((#x + #variance_identifier)/32),
-                    None => quote!(#variance_identifier / 32),
-                }
-            } else {
-                end_expr.clone()
-            };
-            prev_expr = Some(end_expr.clone());
-            index = 0;
+            let type_identifier = get_type_identifier(field.ty.clone());
             let field_setter_trait_identifier = format_ident!("{}FieldSetters", type_identifier);
             let setter_name = if is_bitset {
                 format_ident!("BitFieldSetters")
             } else {
                 format_ident!("PerfFieldSetters")
             };
-            res.push(quote!(
-                fn #method_name(&mut self) -> impl #field_setter_trait_identifier {
-                    let f = #fun_expr;
-                    #setter_name(
-                    &mut #bitset_expr[#start_bit_expr..#end_bit_expr],
-                    &mut #fields_expr[#start_expr..#end_expr],
-                    &mut #len_expr,
-                    move |x|
-                            f(#fieldtype_identifier::#field_name_upper(x)))
+            match get_array_len(field.ty.clone()) {
+                Some(len) => {
+                    let variance_identifier = get_variance_identifier(type_identifier);
+                    res.push(quote!(
+                        fn #method_name(&mut self, index: usize) -> impl #field_setter_trait_identifier {
+                            assert!(index < (#len));
+                            let f = #fun_expr;
+                            #setter_name(
+                            &mut #bitset_expr[..],
+                            &mut #fields_expr[..],
+                            &mut #len_expr,
+                            #index_expr + index * #variance_identifier,
+                            move |x|
+                                    f(#fieldtype_identifier::#field_name_upper(index, x)))
+                        }
+                    ));
                 }
-            ));
+                None => {
+                    res.push(quote!(
+                        fn #method_name(&mut self) -> impl #field_setter_trait_identifier {
+                            let f = #fun_expr;
+                            #setter_name(
+                            &mut #bitset_expr[..],
+                            &mut #fields_expr[..],
+                            &mut #len_expr,
+                            #index_expr,
+                            move |x|
+                                    f(#fieldtype_identifier::#field_name_upper(x)))
+                        }
+                    ));
+                }
+            }
+            variances.push(get_nested_variance(field.ty));
         } else {
             let ty = field.ty;
-            let index_expr = index_expr.or_else(|| quote!(0usize).into());
-            index += 1;
+            leaf_count += 1;
             let leaf_setter_name = if is_bitset {
                 format_ident!("BitFieldLeafSetter")
             } else {
@@ -340,8 +392,8 @@ fn common_trait_impl_methods(
                     fn #method_name(&mut self) -> impl FieldSetter<#ty> {
                         let f = #fun_expr;
                         #leaf_setter_name::<#ty, _, _>(
-                            &mut #bitset_expr,
-                            &mut #fields_expr,
+                            &mut #bitset_expr[..],
+                            &mut #fields_expr[..],
                             &mut #len_expr,
                             #index_expr, move |x| f(#fieldtype_identifier::#field_name_upper(x)), PhantomData)
                     }
@@ -354,18 +406,20 @@ fn common_trait_impl_methods(
 fn derive_common_fieldset_setter_trait_impl(
     is_bitset: bool,
     name: String,
-    fields: FieldsNamed,
+    fields: Fields,
 ) -> TokenStream {
     let bitset_expr = quote!(self.0);
     let fields_expr = quote!(self.1);
     let len_expr = quote!(self.2);
-    let fun_expr = quote!(self.3);
+    let offset_expr = quote!(self.3);
+    let fun_expr = quote!(self.4);
     let trait_identifier = format_ident!("{}FieldSetters", name);
     let fieldtype_identifier = format_ident!("{}FieldType", name);
     let methods = common_trait_impl_methods(
         bitset_expr,
         fields_expr,
         len_expr,
+        offset_expr,
         fun_expr,
         is_bitset,
         name,
@@ -383,14 +437,11 @@ fn derive_common_fieldset_setter_trait_impl(
     ).into()
 }
 
-fn derive_common_fieldset_trait_impl(
-    is_bitset: bool,
-    name: String,
-    fields: FieldsNamed,
-) -> TokenStream {
+fn derive_common_fieldset_trait_impl(is_bitset: bool, name: String, fields: Fields) -> TokenStream {
     let bitset_expr = quote!(self.bitset);
     let fields_expr = quote!(self.fields);
     let len_expr = quote!(self.len);
+    let offset_expr = quote!(0usize);
     let fun_expr = quote!(Some);
     let trait_identifier = format_ident!("{}FieldSetters", name);
     let fieldset_identifier = if is_bitset {
@@ -402,6 +453,7 @@ fn derive_common_fieldset_trait_impl(
         bitset_expr,
         fields_expr,
         len_expr,
+        offset_expr,
         fun_expr,
         is_bitset,
         name,
@@ -422,7 +474,7 @@ fn derive_common_fieldset_trait_impl(
 fn derive_common_fieldset_into_iterator(
     is_bitset: bool,
     name: String,
-    _fields: FieldsNamed,
+    _fields: Fields,
 ) -> TokenStream {
     let fieldset_identifier = if is_bitset {
         format_ident!("{}BitFieldSet", name)
@@ -443,7 +495,7 @@ fn derive_common_fieldset_into_iterator(
     .into()
 }
 
-fn derive_bitset_fieldset(name: String, _fields: FieldsNamed) -> TokenStream {
+fn derive_bitset_fieldset(name: String, _fields: Fields) -> TokenStream {
     let identifier = format_ident!("{}", name);
     let fieldset_identifier = format_ident!("{}BitFieldSet", name);
     let fieldtype_identifier = format_ident!("{}FieldType", name);
@@ -476,7 +528,7 @@ fn derive_bitset_fieldset(name: String, _fields: FieldsNamed) -> TokenStream {
     .into()
 }
 
-fn derive_perf_fieldset(name: String, _fields: FieldsNamed) -> TokenStream {
+fn derive_perf_fieldset(name: String, _fields: Fields) -> TokenStream {
     let identifier = format_ident!("{}", name);
     let fieldset_identifier = format_ident!("{}PerfFieldSet", name);
     let fieldtype_identifier = format_ident!("{}FieldType", name);
@@ -505,75 +557,68 @@ fn derive_perf_fieldset(name: String, _fields: FieldsNamed) -> TokenStream {
 #[proc_macro_derive(FieldSet, attributes(fieldset))]
 pub fn derive_fieldset(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
-    if let syn::Data::Struct(ref data) = input.data {
-        if let syn::Fields::Named(ref fields) = data.fields {
-            let mut result = TokenStream::default();
-            result.extend(derive_field_type(input.ident.to_string(), fields.clone()));
-            result.extend(derive_apply_method(input.ident.to_string(), fields.clone()));
-            result.extend(derive_setter_trait(input.ident.to_string(), fields.clone()));
-            result.extend(derive_fieldset_variance(
-                input.ident.to_string(),
-                fields.clone(),
-            ));
-            result.extend(derive_opt_fieldset_type(
-                input.ident.to_string(),
-                fields.clone(),
-            ));
-            result.extend(derive_opt_fieldset_setter_trait_impl(
-                input.ident.to_string(),
-                fields.clone(),
-            ));
-            result.extend(derive_opt_fieldset_into_iterator(
-                input.ident.to_string(),
-                fields.clone(),
-            ));
-            result.extend(derive_bitset_fieldset(
-                input.ident.to_string(),
-                fields.clone(),
-            ));
-            result.extend(derive_common_fieldset_setter_trait_impl(
-                true,
-                input.ident.to_string(),
-                fields.clone(),
-            ));
-            result.extend(derive_common_fieldset_trait_impl(
-                true,
-                input.ident.to_string(),
-                fields.clone(),
-            ));
-            result.extend(derive_common_fieldset_into_iterator(
-                true,
-                input.ident.to_string(),
-                fields.clone(),
-            ));
-            result.extend(derive_perf_fieldset(
-                input.ident.to_string(),
-                fields.clone(),
-            ));
-            result.extend(derive_common_fieldset_setter_trait_impl(
-                false,
-                input.ident.to_string(),
-                fields.clone(),
-            ));
-            result.extend(derive_common_fieldset_trait_impl(
-                false,
-                input.ident.to_string(),
-                fields.clone(),
-            ));
-            result.extend(derive_common_fieldset_into_iterator(
-                false,
-                input.ident.to_string(),
-                fields.clone(),
-            ));
-            return result;
+    let fields = match input.data {
+        syn::Data::Struct(ref data) if !matches!(data.fields, Fields::Unit) => data.fields.clone(),
+        syn::Data::Enum(_) => {
+            return syn::Error::new(
+                input.ident.span(),
+                "enums cannot derive `FieldSet`, use them as plain fields of a struct that does",
+            )
+            .to_compile_error()
+            .into()
         }
-    }
-
-    TokenStream::from(
-        syn::Error::new(
-            input.ident.span(),
-            "Only structs with named fields can derive `FieldEvents`",
-        )
-        .to_compile_error(),
-    )
+        _ => {
+            return syn::Error::new(
+                input.ident.span(),
+                "Only structs with fields can derive `FieldSet`",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+    let name = input.ident.to_string();
+
+    let mut result = TokenStream::default();
+    result.extend(derive_field_type(name.clone(), fields.clone()));
+    result.extend(derive_apply_method(name.clone(), fields.clone()));
+    result.extend(derive_setter_trait(name.clone(), fields.clone()));
+    result.extend(derive_fieldset_variance(name.clone(), fields.clone()));
+    result.extend(derive_opt_fieldset_type(name.clone(), fields.clone()));
+    result.extend(derive_opt_fieldset_setter_trait_impl(
+        name.clone(),
+        fields.clone(),
+    ));
+    result.extend(derive_opt_fieldset_into_iterator(
+        name.clone(),
+        fields.clone(),
+    ));
+    result.extend(derive_bitset_fieldset(name.clone(), fields.clone()));
+    result.extend(derive_common_fieldset_setter_trait_impl(
+        true,
+        name.clone(),
+        fields.clone(),
+    ));
+    result.extend(derive_common_fieldset_trait_impl(
+        true,
+        name.clone(),
+        fields.clone(),
+    ));
+    result.extend(derive_common_fieldset_into_iterator(
+        true,
+        name.clone(),
+        fields.clone(),
+    ));
+    result.extend(derive_perf_fieldset(name.clone(), fields.clone()));
+    result.extend(derive_common_fieldset_setter_trait_impl(
+        false,
+        name.clone(),
+        fields.clone(),
+    ));
+    result.extend(derive_common_fieldset_trait_impl(
+        false,
+        name.clone(),
+        fields.clone(),
+    ));
+    result.extend(derive_common_fieldset_into_iterator(false, name, fields));
+    result
 }
@@ -24,7 +24,15 @@ pub struct BitFieldLeafSetter<'a, V, T, F>(
     pub PhantomData<V>,
 );
 
-pub struct BitFieldSetters<'a, T, F>(pub &'a mut [u32], pub &'a mut [T], pub &'a mut usize, pub F);
+/// Setters of a nested fieldset whose fields start at index `.3` of the
+/// enclosing set.
+pub struct BitFieldSetters<'a, T, F>(
+    pub &'a mut [u32],
+    pub &'a mut [T],
+    pub &'a mut usize,
+    pub usize,
+    pub F,
+);
 
 impl<'a, V, T, F: Fn(V) -> T> FieldSetter<V> for BitFieldLeafSetter<'a, V, T, F> {
     fn set(&mut self, value: V) {
@@ -45,7 +53,15 @@ pub struct PerfFieldLeafSetter<'a, V, T, F>(
     pub PhantomData<V>,
 );
 
-pub struct PerfFieldSetters<'a, T, F>(pub &'a mut [u16], pub &'a mut [T], pub &'a mut usize, pub F);
+/// Setters of a nested fieldset whose fields start at index `.3` of the
+/// enclosing set.
+pub struct PerfFieldSetters<'a, T, F>(
+    pub &'a mut [u16],
+    pub &'a mut [T],
+    pub &'a mut usize,
+    pub usize,
+    pub F,
+);
 
 impl<'a, V, T, F: Fn(V) -> T> FieldSetter<V> for PerfFieldLeafSetter<'a, V, T, F> {
     fn set(&mut self, value: V) {
@@ -0,0 +1,123 @@
+#![feature(impl_trait_in_assoc_type)]
+
+use fieldset::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, FieldSet)]
+struct Voice {
+    pitch: u8,
+    gate: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, FieldSet)]
+struct Pair(u8, bool);
+
+/// Leaf indices: `level` 0, `pair` 1-2, `voices[i]` 3 + 2i and 4 + 2i,
+/// `tail` 83. The voices start in the middle of a bitset word and span three.
+#[derive(Debug, Clone, Copy, PartialEq, FieldSet)]
+struct Synth {
+    level: u8,
+    #[fieldset]
+    pair: Pair,
+    #[fieldset]
+    voices: [Voice; 40],
+    tail: u16,
+}
+
+const VOICE: Voice = Voice {
+    pitch: 0,
+    gate: false,
+};
+
+fn synth() -> Synth {
+    Synth {
+        level: 0,
+        pair: Pair(0, false),
+        voices: [VOICE; 40],
+        tail: 0,
+    }
+}
+
+fn applied(fields: impl IntoIterator<Item = SynthFieldType>) -> Synth {
+    let mut synth = synth();
+    for field in fields {
+        synth.apply(field);
+    }
+    synth
+}
+
+#[test]
+fn tuple_struct_fields_are_numbered() {
+    let mut pair = Pair(0, false);
+    pair.apply(PairFieldType::Field0(5));
+    pair.apply(PairFieldType::Field1(true));
+    assert_eq!(pair, Pair(5, true));
+
+    let mut set = PairOptFieldSet::new();
+    set.field_1().set(true);
+    let fields: Vec<_> = set.into_iter().collect();
+    assert!(matches!(fields[..], [PairFieldType::Field1(true)]));
+}
+
+#[test]
+fn bit_set_keeps_the_first_value_of_each_field() {
+    assert_eq!(SYNTH_VARIANCE, 84);
+    let mut set = SynthBitFieldSet::new();
+    set.level().set(1);
+    set.pair().field_1().set(true);
+    set.voices(14).gate().set(true);
+    set.voices(15).gate().set(true);
+    set.voices(39).pitch().set(9);
+    set.tail().set(7);
+    set.level().set(2);
+
+    assert_eq!(
+        set.bitset,
+        [1 << 0 | 1 << 2, 1 << 0 | 1 << 2, 1 << 17 | 1 << 19]
+    );
+    assert_eq!(set.len, 6);
+
+    let mut expected = synth();
+    expected.level = 1;
+    expected.pair.1 = true;
+    expected.voices[14].gate = true;
+    expected.voices[15].gate = true;
+    expected.voices[39].pitch = 9;
+    expected.tail = 7;
+    assert_eq!(applied(set), expected);
+}
+
+#[test]
+fn perf_set_keeps_the_last_value_of_each_field() {
+    let mut set = SynthPerfFieldSet::new();
+    set.voices(15).gate().set(true);
+    set.tail().set(7);
+    set.voices(15).gate().set(false);
+    set.voices(14).pitch().set(3);
+
+    // Each slot holds the position of its field in the set, plus one.
+    assert_eq!(set.bitset[34], 1);
+    assert_eq!(set.bitset[83], 2);
+    assert_eq!(set.bitset[31], 3);
+    assert_eq!(set.len, 3);
+
+    let mut expected = synth();
+    expected.voices[14].pitch = 3;
+    expected.tail = 7;
+    assert_eq!(applied(set), expected);
+}
+
+#[test]
+fn opt_set_holds_arrays_longer_than_32() {
+    let mut set = SynthOptFieldSet::new();
+    set.voices(39).pitch().set(9);
+    set.voices(3).gate().set(true);
+    set.pair().field_0().set(4);
+    set.level().set(2);
+
+    let mut expected = synth();
+    expected.level = 2;
+    expected.pair.0 = 4;
+    expected.voices[3].gate = true;
+    expected.voices[39].pitch = 9;
+    assert_eq!(applied(set), expected);
+}
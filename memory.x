@@ -1,5 +1,20 @@
 MEMORY
 {
   FLASH : ORIGIN = 0x08000000, LENGTH = 2048K
+  /* DTCM: the stack and statics. DMA1 and DMA2 cannot reach it. */
   RAM : ORIGIN = 0x20000000, LENGTH = 128K
+  /* AXI SRAM, reachable by every DMA controller. */
+  AXISRAM : ORIGIN = 0x24000000, LENGTH = 512K
 }
+
+SECTIONS
+{
+  /* Buffers for DMA, see `dma::axi_singleton`. Not initialized at startup:
+     they are written when they are handed out. */
+  .axisram (NOLOAD) : ALIGN(32)
+  {
+    *(.axisram .axisram.*);
+    . = ALIGN(32);
+  } > AXISRAM
+}
+INSERT AFTER .bss;
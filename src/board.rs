@@ -4,6 +4,7 @@ use core::time::Duration;
 
 use crate::bounded::Bounded;
 use crate::bounded::Norm;
+use crate::dma::DmaBuffer;
 
 const NUMBER_OF_VOICES: usize = 8;
 
@@ -42,10 +43,68 @@ pub trait Display {
     fn flush(&mut self);
 }
 
+/// One sector of external memory, placed where DMA can move it.
+pub type SectorBuffer = DmaBuffer<[u8; 4096]>;
+
 pub trait ExtMemory {
     type Error;
-    async fn write(&mut self, sector_id: u8, data: &[u8; 4096]) -> Result<(), Self::Error>;
-    async fn read(&mut self, sector_id: u8, data: &mut [u8; 4096]) -> Result<(), Self::Error>;
+    async fn write(&mut self, sector_id: u8, data: &SectorBuffer) -> Result<(), Self::Error>;
+    async fn read(&mut self, sector_id: u8, data: &mut SectorBuffer) -> Result<(), Self::Error>;
+}
+
+/// Errors of an [`ExtFlash`], telling worn-out flash apart from other faults.
+pub trait FlashError {
+    /// An erase or program did not read back as expected.
+    fn is_verify_failure(&self) -> bool;
+}
+
+/// Byte-addressed access to the flash behind an [`ExtMemory`], for records
+/// smaller than a sector.
+///
+/// Like NOR flash, programming can only clear bits: a range has to be erased
+/// before it is programmed again.
+pub trait ExtFlash: ExtMemory<Error: FlashError> {
+    /// Size of the memory in bytes.
+    fn capacity(&self) -> u32;
+    async fn read_at(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+    /// Program `data` at `addr` without erasing first.
+    async fn program_at(&mut self, addr: u32, data: &[u8]) -> Result<(), Self::Error>;
+    /// Erase the 4096-byte sector containing `addr`.
+    async fn erase_sector(&mut self, addr: u32) -> Result<(), Self::Error>;
+
+    /// Read the `len` bytes from `addr` in chunks of the caller's choosing.
+    fn stream(&mut self, addr: u32, len: u32) -> ExtStream<'_, Self> {
+        ExtStream {
+            memory: self,
+            addr,
+            end: addr.saturating_add(len),
+        }
+    }
+}
+
+/// Sequential reader over a range of an [`ExtFlash`].
+pub struct ExtStream<'a, M: ?Sized> {
+    memory: &'a mut M,
+    addr: u32,
+    end: u32,
+}
+
+impl<M: ExtFlash + ?Sized> ExtStream<'_, M> {
+    /// Read the next chunk into `buf`. Returns its length, which is 0 once
+    /// the range is exhausted.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, M::Error> {
+        let len = buf.len().min((self.end - self.addr) as usize);
+        if len > 0 {
+            self.memory.read_at(self.addr, &mut buf[..len]).await?;
+            self.addr += len as u32;
+        }
+        Ok(len)
+    }
+
+    /// Bytes left to read.
+    pub fn remaining(&self) -> u32 {
+        self.end - self.addr
+    }
 }
 
 pub trait Gpio {
@@ -0,0 +1,117 @@
+//! Buffers the DMA controllers can transfer to and from.
+//!
+//! On the STM32H7, DMA1 and DMA2 cannot reach the tightly coupled memories,
+//! and a transfer to or from ITCM or DTCM fails without any error from the
+//! peripheral. [`DmaBuffer`] marks a buffer as meant for DMA: its alignment
+//! to the 32-byte cache line is enforced by the type, and its placement is
+//! checked at runtime by the drivers that hand it to a DMA controller.
+//!
+//! `memory.x` puts the stack and all statics in DTCM, so buffers for DMA are
+//! allocated in AXI SRAM with [`axi_singleton`].
+
+use core::ops::{Deref, DerefMut, Range};
+
+/// Size of a data cache line of the Cortex-M7.
+pub const CACHE_LINE: usize = 32;
+
+const ITCM: Range<usize> = 0x0000_0000..0x0001_0000;
+const DTCM: Range<usize> = 0x2000_0000..0x2002_0000;
+
+/// Whether DMA can reach all of `buf`.
+pub fn is_reachable(buf: &[u8]) -> bool {
+    let start = buf.as_ptr() as usize;
+    let end = start + buf.len();
+    let overlaps = |region: &Range<usize>| start < region.end && region.start < end;
+    #[cfg(test)]
+    if tests::TCM.with(|tcm| tcm.borrow().iter().any(overlaps)) {
+        return false;
+    }
+    !overlaps(&ITCM) && !overlaps(&DTCM)
+}
+
+/// Panic unless DMA can reach all of `buf`. A misplaced buffer is a bug in
+/// the memory layout, not something a caller can recover from.
+#[track_caller]
+pub fn assert_reachable(buf: &[u8]) {
+    assert!(
+        is_reachable(buf),
+        "DMA buffer at {:#010x} is in tightly coupled memory",
+        buf.as_ptr() as usize
+    );
+}
+
+/// A cache-line aligned buffer for DMA transfers.
+#[repr(C, align(32))]
+pub struct DmaBuffer<T: ?Sized>(T);
+
+impl<T> DmaBuffer<T> {
+    pub const fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: ?Sized> Deref for DmaBuffer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: ?Sized> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Clone> Clone for DmaBuffer<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: Copy> Copy for DmaBuffer<T> {}
+
+/// A `&'static mut` to a value placed in AXI SRAM, the `.axisram` section of
+/// `memory.x`, where every DMA controller can reach it. Like
+/// `cortex_m::singleton!`, each call site hands out its value once and
+/// evaluates to `None` after that.
+macro_rules! axi_singleton {
+    (: $ty:ty = $init:expr) => {{
+        #[link_section = ".axisram"]
+        static mut VALUE: core::mem::MaybeUninit<$ty> = core::mem::MaybeUninit::uninit();
+        static TAKEN: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+        if TAKEN.swap(true, core::sync::atomic::Ordering::AcqRel) {
+            None
+        } else {
+            // SAFETY: `TAKEN` lets only the first caller through, so this is
+            // the only reference to `VALUE` there will ever be.
+            Some(unsafe { (*core::ptr::addr_of_mut!(VALUE)).write($init) })
+        }
+    }};
+}
+pub(crate) use axi_singleton;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    use super::*;
+
+    thread_local! {
+        pub(super) static TCM: RefCell<Vec<Range<usize>>> = RefCell::default();
+    }
+
+    /// Treat `buf` as if it were in tightly coupled memory, which the host
+    /// does not have, for the rest of the test.
+    pub(crate) fn place_in_tcm(buf: &[u8]) {
+        let start = buf.as_ptr() as usize;
+        TCM.with(|tcm| tcm.borrow_mut().push(start..start + buf.len()));
+    }
+}
@@ -1,45 +1,293 @@
-use crate::board::ExtMemory;
-use crate::w25qxx::{PAGE_SIZE, SECTOR_SIZE, W25Q80};
+use crate::board::{ExtFlash, ExtMemory, FlashError, SectorBuffer};
+use crate::dma::{self, DmaBuffer};
+use crate::w25qxx::{Error, PAGE_SIZE, SECTOR_SIZE, W25Qxx};
+use embedded_hal_async::delay::DelayNs;
 use embedded_hal_async::spi::{ErrorType, SpiDevice};
 
-pub struct Driver<T> {
-    device: W25Q80<T>,
+pub mod atomic;
+mod cache;
+mod journal;
+pub mod partition;
+pub mod remap;
+mod wear_leveling;
+
+pub use cache::Cache;
+pub use wear_leveling::WearLeveling;
+
+/// One page of external memory, placed where DMA can move it.
+pub type PageBuffer = DmaBuffer<[u8; PAGE_SIZE]>;
+
+pub struct Driver<T, D> {
+    device: W25Qxx<T, D>,
+    /// Buffers DMA cannot reach, such as the page buffers the layers above
+    /// keep on the stack, are staged through here.
+    bounce: &'static mut PageBuffer,
+    bounced: u32,
 }
 
-type NewError<T> = crate::w25qxx::Error<<T as ErrorType>::Error>;
+type NewError<T> = Error<<T as ErrorType>::Error>;
 
-impl<T: SpiDevice> Driver<T> {
-    pub async fn new(device: T) -> Result<Self, NewError<T>> {
+impl<T: SpiDevice, D: DelayNs> Driver<T, D> {
+    /// Programs and erases are read back, so worn sectors fail with
+    /// [`Error::VerifyFailed`] instead of silently holding the wrong data.
+    ///
+    /// `bounce` has to be reachable by DMA, see [`dma::axi_singleton`].
+    pub async fn new(
+        device: T,
+        delay: D,
+        bounce: &'static mut PageBuffer,
+    ) -> Result<Self, NewError<T>> {
+        dma::assert_reachable(&bounce[..]);
+        let mut device = W25Qxx::new(device, delay).await?;
+        device.set_verify(true);
         Ok(Self {
-            device: W25Q80::new(device).await?,
+            device,
+            bounce,
+            bounced: 0,
         })
     }
-}
 
-impl<T: SpiDevice> ExtMemory for Driver<T> {
-    type Error = T::Error;
+    /// Number of pages staged through the bounce buffer. Each costs a copy,
+    /// so buffers on hot paths should be reachable by DMA.
+    pub fn bounced(&self) -> u32 {
+        self.bounced
+    }
 
-    async fn write(&mut self, sector_id: u8, data: &[u8; SECTOR_SIZE]) -> Result<(), T::Error> {
-        let sector_address = sector_id as u32 * SECTOR_SIZE as u32;
-        let delay = 100;
-        self.device.write_enable().await?;
-        self.device.erase_sector(sector_address, delay).await?;
-        for page in 0..(SECTOR_SIZE / PAGE_SIZE) {
-            self.device.write_enable().await?;
-            self.device
-                .write(
-                    sector_address + page as u32 * PAGE_SIZE as u32,
-                    &data[page * PAGE_SIZE..(page + 1) * PAGE_SIZE],
-                    delay,
-                )
-                .await?;
+    /// Read one page per transaction, so that on a shared bus other devices
+    /// get their turn during long reads.
+    async fn read_pages(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), NewError<T>> {
+        let mut current_addr = addr;
+        for chunk in buf.chunks_mut(PAGE_SIZE) {
+            if dma::is_reachable(chunk) {
+                self.device.read(current_addr, chunk).await?;
+            } else {
+                self.bounced = self.bounced.saturating_add(1);
+                let bounce = &mut self.bounce[..chunk.len()];
+                self.device.read(current_addr, bounce).await?;
+                chunk.copy_from_slice(bounce);
+            }
+            current_addr += chunk.len() as u32;
         }
         Ok(())
     }
 
-    async fn read(&mut self, sector_id: u8, data: &mut [u8; SECTOR_SIZE]) -> Result<(), T::Error> {
-        let sector_address = sector_id as u32 * SECTOR_SIZE as u32;
-        self.device.read(sector_address as u32, data).await?;
+    /// Program one page per transaction, staging what DMA cannot reach.
+    async fn program_pages(&mut self, addr: u32, data: &[u8]) -> Result<(), NewError<T>> {
+        let mut current_addr = addr;
+        let mut data = data;
+        while !data.is_empty() {
+            let page_left = PAGE_SIZE - current_addr as usize % PAGE_SIZE;
+            let (chunk, rest) = data.split_at(page_left.min(data.len()));
+            if dma::is_reachable(chunk) {
+                self.device.write(current_addr, chunk).await?;
+            } else {
+                self.bounced = self.bounced.saturating_add(1);
+                let bounce = &mut self.bounce[..chunk.len()];
+                bounce.copy_from_slice(chunk);
+                self.device.write(current_addr, bounce).await?;
+            }
+            current_addr += chunk.len() as u32;
+            data = rest;
+        }
         Ok(())
     }
 }
+
+impl<E> FlashError for Error<E> {
+    fn is_verify_failure(&self) -> bool {
+        matches!(self, Error::VerifyFailed { .. })
+    }
+}
+
+impl<T: SpiDevice, D: DelayNs> ExtMemory for Driver<T, D> {
+    type Error = Error<T::Error>;
+
+    /// Not atomic: cut short, the sector is left half erased or half
+    /// programmed. [`atomic::AtomicSectors`] makes updates atomic.
+    async fn write(&mut self, sector_id: u8, data: &SectorBuffer) -> Result<(), Self::Error> {
+        let sector_address = sector_id as u32 * SECTOR_SIZE as u32;
+        self.device.erase_sector(sector_address).await?;
+        self.device.verify_erased(sector_address, SECTOR_SIZE).await?;
+        self.program_pages(sector_address, &data[..]).await
+    }
+
+    async fn read(&mut self, sector_id: u8, data: &mut SectorBuffer) -> Result<(), Self::Error> {
+        let sector_address = sector_id as u32 * SECTOR_SIZE as u32;
+        self.read_pages(sector_address, &mut data[..]).await
+    }
+}
+
+impl<T: SpiDevice, D: DelayNs> ExtFlash for Driver<T, D> {
+    fn capacity(&self) -> u32 {
+        self.device.info().capacity()
+    }
+
+    async fn read_at(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.read_pages(addr, buf).await
+    }
+
+    async fn program_at(&mut self, addr: u32, data: &[u8]) -> Result<(), Self::Error> {
+        self.program_pages(addr, data).await
+    }
+
+    async fn erase_sector(&mut self, addr: u32) -> Result<(), Self::Error> {
+        self.device.erase_sector(addr).await?;
+        let sector_address = addr - addr % SECTOR_SIZE as u32;
+        self.device.verify_erased(sector_address, SECTOR_SIZE).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use futures::executor::block_on;
+    use w25qxx::sim::{FlashSim, SimDelay};
+
+    use super::*;
+    use crate::dma::tests::place_in_tcm;
+
+    pub(crate) const W25Q80: u32 = 0xEF4014;
+
+    pub(crate) fn driver(sim: &FlashSim) -> Driver<FlashSim, SimDelay> {
+        let bounce = Box::leak(Box::new(DmaBuffer::new([0; PAGE_SIZE])));
+        block_on(Driver::new(sim.clone(), sim.delay(), bounce)).unwrap()
+    }
+
+    /// A sector of bytes counting up from `seed`.
+    pub(crate) fn pattern(seed: u8) -> SectorBuffer {
+        let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = seed.wrapping_add(i as u8);
+        }
+        buf
+    }
+
+    #[test]
+    fn sectors_round_trip() {
+        let sim = FlashSim::new(W25Q80);
+        let mut memory = driver(&sim);
+        block_on(async {
+            memory.write(3, &pattern(1)).await.unwrap();
+            // Rewriting erases first.
+            memory.write(3, &pattern(2)).await.unwrap();
+
+            let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+            memory.read(3, &mut buf).await.unwrap();
+            assert_eq!(buf[..], pattern(2)[..]);
+        });
+        assert_eq!(
+            sim.read(3 * SECTOR_SIZE as u32, SECTOR_SIZE),
+            pattern(2)[..]
+        );
+        assert_eq!(
+            sim.read(2 * SECTOR_SIZE as u32, SECTOR_SIZE),
+            [0xFF; SECTOR_SIZE]
+        );
+    }
+
+    #[test]
+    fn reads_a_page_per_transaction() {
+        let sim = FlashSim::new(W25Q80);
+        let mut memory = driver(&sim);
+        sim.clear_commands();
+        let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+        block_on(memory.read(0, &mut buf)).unwrap();
+        assert_eq!(sim.commands(), [0x03; SECTOR_SIZE / PAGE_SIZE]);
+    }
+
+    #[test]
+    fn only_unreachable_buffers_are_bounced() {
+        let sim = FlashSim::new(W25Q80);
+        let mut memory = driver(&sim);
+        block_on(async {
+            memory.write(1, &pattern(1)).await.unwrap();
+            let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+            memory.read(1, &mut buf).await.unwrap();
+            assert_eq!(buf[..], pattern(1)[..]);
+            assert_eq!(memory.bounced(), 0);
+
+            // One page at a time, both ways.
+            let data = pattern(2);
+            let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+            place_in_tcm(&data[..]);
+            place_in_tcm(&buf[..]);
+            memory.write(2, &data).await.unwrap();
+            memory.read(2, &mut buf).await.unwrap();
+            assert_eq!(buf[..], pattern(2)[..]);
+            assert_eq!(memory.bounced(), 2 * (SECTOR_SIZE / PAGE_SIZE) as u32);
+        });
+        assert_eq!(
+            sim.read(2 * SECTOR_SIZE as u32, SECTOR_SIZE),
+            pattern(2)[..]
+        );
+    }
+
+    #[test]
+    fn worn_sectors_fail_verification() {
+        let sim = FlashSim::new(W25Q80);
+        let mut memory = driver(&sim);
+
+        sim.stick_bit(0x1234, 3, false);
+        let result = block_on(memory.write(1, &DmaBuffer::new([0xFF; SECTOR_SIZE])));
+        assert!(matches!(
+            result,
+            Err(Error::VerifyFailed {
+                addr: 0x1234,
+                expected: 0xFF,
+                ..
+            })
+        ));
+
+        sim.stick_bit(0x2345, 3, true);
+        let result = block_on(memory.write(2, &DmaBuffer::new([0x00; SECTOR_SIZE])));
+        assert!(matches!(
+            result,
+            Err(Error::VerifyFailed {
+                addr: 0x2345,
+                expected: 0x00,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn byte_access() {
+        let sim = FlashSim::new(W25Q80);
+        let mut memory = driver(&sim);
+        assert_eq!(memory.capacity(), 1 << 20);
+        block_on(async {
+            memory.program_at(0x10F0, &[0xA5; 0x20]).await.unwrap();
+            let mut buf = [0; 0x22];
+            memory.read_at(0x10EF, &mut buf).await.unwrap();
+            assert_eq!(buf[0], 0xFF);
+            assert_eq!(buf[1..0x21], [0xA5; 0x20]);
+            assert_eq!(buf[0x21], 0xFF);
+
+            let mut stream = memory.stream(0x10F0, 0x20);
+            let mut chunk = [0; 0x18];
+            assert_eq!(stream.read(&mut chunk).await.unwrap(), 0x18);
+            assert_eq!(stream.read(&mut chunk).await.unwrap(), 0x08);
+            assert_eq!(stream.read(&mut chunk).await.unwrap(), 0);
+
+            memory.erase_sector(0x1800).await.unwrap();
+            memory.read_at(0x10EF, &mut buf).await.unwrap();
+            assert_eq!(buf, [0xFF; 0x22]);
+        });
+    }
+
+    #[test]
+    fn power_loss_mid_write() {
+        let sim = FlashSim::new(W25Q80);
+        let mut memory = driver(&sim);
+        block_on(memory.write(5, &pattern(7))).unwrap();
+
+        // A rewrite is not atomic: cut short, the sector is half erased.
+        sim.arm_power_loss(SECTOR_SIZE / 2);
+        assert!(block_on(memory.write(5, &pattern(8))).is_err());
+        sim.power_cycle();
+        let mut memory = driver(&sim);
+        let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+        block_on(memory.read(5, &mut buf)).unwrap();
+        assert_eq!(buf[..SECTOR_SIZE / 2], [0xFF; SECTOR_SIZE / 2]);
+        assert_eq!(buf[SECTOR_SIZE / 2..], pattern(7)[SECTOR_SIZE / 2..]);
+    }
+}
@@ -0,0 +1,412 @@
+//! Sectors updated atomically through a shadow copy.
+//!
+//! Each sector has two copies in the [`ExtFlash`] below. An update erases and
+//! programs the copy that is not current, then appends a commit record with
+//! its sequence number and the CRC of its contents to a [`Journal`]. Until the
+//! record is written the other copy stays current, so a power loss leaves
+//! either the old or the new contents. Mounting picks the newest committed
+//! copy whose contents still match their CRC, and falls back to the other one.
+//!
+//! Layout from `base`: the two journal banks, then the two copies of each
+//! sector.
+
+use crate::board::{ExtFlash, ExtMemory, SectorBuffer};
+use crate::w25qxx::{crc32, Crc32, PAGE_SIZE, SECTOR_SIZE};
+
+use super::journal::{Journal, Record};
+
+/// A copy of a sector holds the contents with CRC `value`.
+const COMMIT: u8 = 0x02;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Memory(E),
+    /// `sector_id` is not one of the sectors of the store.
+    OutOfRange {
+        sector_id: u8,
+    },
+    /// The journal and the copies do not fit in the memory from `base`.
+    TooSmall,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(value: E) -> Self {
+        Self::Memory(value)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Commit {
+    sequence: u32,
+    crc: u32,
+}
+
+/// Whether `a` was committed after `b`. Sequence numbers are compared modulo
+/// 2^32.
+fn newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// The committed copies of each sector, as rebuilt from the journal.
+struct Commits<const SECTORS: usize>([[Option<Commit>; 2]; SECTORS]);
+
+impl<const SECTORS: usize> Commits<SECTORS> {
+    fn apply(&mut self, record: Record) {
+        let sector = record.sector as usize;
+        let copy = record.location as usize;
+        if record.kind == COMMIT && sector < SECTORS && copy < 2 {
+            self.0[sector][copy] = Some(Commit {
+                sequence: record.sequence,
+                crc: record.value,
+            });
+        }
+    }
+
+    /// One record per committed copy, enough to rebuild the table.
+    fn snapshot(&self) -> impl Iterator<Item = Record> + '_ {
+        self.0.iter().enumerate().flat_map(|(sector, copies)| {
+            copies.iter().enumerate().filter_map(move |(copy, commit)| {
+                let commit = (*commit)?;
+                Some(Record {
+                    kind: COMMIT,
+                    sector: sector as u8,
+                    location: copy as u16,
+                    sequence: commit.sequence,
+                    value: commit.crc,
+                })
+            })
+        })
+    }
+}
+
+/// `SECTORS` atomically updated sectors over an [`ExtFlash`].
+pub struct AtomicSectors<F, const SECTORS: usize> {
+    flash: F,
+    base: u32,
+    journal: Journal,
+    commits: Commits<SECTORS>,
+    /// The copy each sector is read from, `None` until it is written.
+    current: [Option<usize>; SECTORS],
+}
+
+impl<F: ExtFlash, const SECTORS: usize> AtomicSectors<F, SECTORS> {
+    const JOURNAL_SECTORS: usize = Journal::sectors_for(2 * SECTORS);
+
+    /// Sectors taken from the memory below, journal included.
+    pub const FOOTPRINT: usize = 2 * Self::JOURNAL_SECTORS + 2 * SECTORS;
+
+    /// Open the store at `base`, picking the newest intact copy of each
+    /// sector. The store is formatted if no journal is found.
+    pub async fn mount(mut flash: F, base: u32) -> Result<Self, Error<F::Error>> {
+        assert!(SECTORS <= 1 << 8);
+        let len = Self::FOOTPRINT * SECTOR_SIZE;
+        if !(base as usize).is_multiple_of(SECTOR_SIZE)
+            || base as usize + len > flash.capacity() as usize
+        {
+            return Err(Error::TooSmall);
+        }
+
+        let mut commits = Commits([[None; 2]; SECTORS]);
+        let journal = Journal::mount(&mut flash, base, Self::JOURNAL_SECTORS, |record| {
+            commits.apply(record)
+        })
+        .await?;
+        let mut new = Self {
+            flash,
+            base,
+            journal,
+            commits,
+            current: [None; SECTORS],
+        };
+
+        for sector in 0..SECTORS {
+            let [first, second] = new.commits.0[sector];
+            let order = match (first, second) {
+                (Some(a), Some(b)) if newer(b.sequence, a.sequence) => [1, 0],
+                _ => [0, 1],
+            };
+            for copy in order {
+                let Some(commit) = new.commits.0[sector][copy] else {
+                    continue;
+                };
+                if new.crc(sector, copy).await? == commit.crc {
+                    new.current[sector] = Some(copy);
+                    break;
+                }
+                // Corrupt: the next write goes here.
+                new.commits.0[sector][copy] = None;
+            }
+        }
+        Ok(new)
+    }
+
+    fn address(&self, sector: usize, copy: usize) -> u32 {
+        self.base + ((2 * Self::JOURNAL_SECTORS + 2 * sector + copy) * SECTOR_SIZE) as u32
+    }
+
+    /// CRC of the contents of a copy.
+    async fn crc(&mut self, sector: usize, copy: usize) -> Result<u32, Error<F::Error>> {
+        let address = self.address(sector, copy);
+        let mut crc = Crc32::new();
+        let mut page = [0; PAGE_SIZE];
+        for offset in (0..SECTOR_SIZE).step_by(PAGE_SIZE) {
+            self.flash
+                .read_at(address + offset as u32, &mut page)
+                .await?;
+            crc.update(&page);
+        }
+        Ok(crc.finish())
+    }
+
+    fn check(&self, sector_id: u8) -> Result<usize, Error<F::Error>> {
+        let sector = sector_id as usize;
+        if sector >= SECTORS {
+            return Err(Error::OutOfRange { sector_id });
+        }
+        Ok(sector)
+    }
+
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+}
+
+impl<F: ExtFlash, const SECTORS: usize> ExtMemory for AtomicSectors<F, SECTORS> {
+    type Error = Error<F::Error>;
+
+    /// Replace the contents of a sector. Until this returns, a power loss
+    /// leaves either the old or the new contents.
+    async fn write(&mut self, sector_id: u8, data: &SectorBuffer) -> Result<(), Self::Error> {
+        let sector = self.check(sector_id)?;
+        let copy = self.current[sector].map_or(0, |current| 1 - current);
+        let sequence = self.commits.0[sector]
+            .iter()
+            .flatten()
+            .map(|commit| commit.sequence)
+            .reduce(|a, b| if newer(b, a) { b } else { a })
+            .map_or(0, |newest| newest.wrapping_add(1));
+
+        // The copy is about to be overwritten, so it must not be snapshotted
+        // as committed.
+        self.commits.0[sector][copy] = None;
+        let address = self.address(sector, copy);
+        self.flash.erase_sector(address).await?;
+        self.flash.program_at(address, &data[..]).await?;
+
+        let record = Record {
+            kind: COMMIT,
+            sector: sector_id,
+            location: copy as u16,
+            sequence,
+            value: crc32(&data[..]),
+        };
+        self.journal
+            .append(&mut self.flash, record, self.commits.snapshot())
+            .await?;
+        self.commits.apply(record);
+        self.current[sector] = Some(copy);
+        Ok(())
+    }
+
+    /// Read the current contents of a sector, all `0xFF` if it was never
+    /// written.
+    async fn read(&mut self, sector_id: u8, data: &mut SectorBuffer) -> Result<(), Self::Error> {
+        let sector = self.check(sector_id)?;
+        match self.current[sector] {
+            Some(copy) => {
+                let address = self.address(sector, copy);
+                self.flash.read_at(address, &mut data[..]).await?;
+            }
+            None => data.fill(0xFF),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use w25qxx::sim::{FlashSim, SimDelay};
+
+    use super::*;
+    use crate::dma::DmaBuffer;
+    use crate::ext_memory::remap::Remapped;
+    use crate::ext_memory::tests::{driver, pattern, W25Q80};
+    use crate::ext_memory::{Driver, WearLeveling};
+
+    type Store = AtomicSectors<Driver<FlashSim, SimDelay>, 3>;
+
+    fn mount(sim: &FlashSim) -> Store {
+        block_on(Store::mount(driver(sim), 0)).unwrap()
+    }
+
+    fn read<M: ExtMemory<Error: core::fmt::Debug>>(store: &mut M, sector_id: u8) -> SectorBuffer {
+        let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+        block_on(store.read(sector_id, &mut buf)).unwrap();
+        buf
+    }
+
+    /// Appends left in the active bank after formatting.
+    const APPENDS: usize = Store::JOURNAL_SECTORS * SECTOR_SIZE / 16 - 1;
+
+    #[test]
+    fn sectors_survive_remount() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        assert_eq!(read(&mut store, 2)[..], [0xFF; SECTOR_SIZE]);
+        for sector_id in 0..3 {
+            block_on(store.write(sector_id, &pattern(sector_id))).unwrap();
+        }
+        block_on(store.write(1, &pattern(9))).unwrap();
+        block_on(store.write(1, &pattern(8))).unwrap();
+
+        let mut store = mount(&sim);
+        assert_eq!(read(&mut store, 0)[..], pattern(0)[..]);
+        assert_eq!(read(&mut store, 1)[..], pattern(8)[..]);
+        assert_eq!(read(&mut store, 2)[..], pattern(2)[..]);
+        assert!(matches!(
+            block_on(store.write(3, &pattern(0))),
+            Err(Error::OutOfRange { sector_id: 3 })
+        ));
+    }
+
+    #[test]
+    fn corrupt_copies_fall_back() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        block_on(store.write(1, &pattern(1))).unwrap();
+        block_on(store.write(1, &pattern(2))).unwrap();
+        let current = store.address(1, store.current[1].unwrap());
+        sim.flip_bit(current + 100, 5);
+
+        let mut store = mount(&sim);
+        assert_eq!(read(&mut store, 1)[..], pattern(1)[..]);
+        // The corrupt copy is the one rewritten next.
+        block_on(store.write(1, &pattern(3))).unwrap();
+        let mut store = mount(&sim);
+        assert_eq!(read(&mut store, 1)[..], pattern(3)[..]);
+        assert_eq!(store.address(1, store.current[1].unwrap()), current);
+    }
+
+    #[test]
+    fn compaction_keeps_the_commits() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        // Enough to compact twice, and a multiple of three.
+        let rounds = (2 * APPENDS).next_multiple_of(3);
+        for round in 0..rounds {
+            let sector_id = (round % 3) as u8;
+            block_on(store.write(sector_id, &pattern(round as u8))).unwrap();
+        }
+
+        let mut store = mount(&sim);
+        for sector_id in 0..3 {
+            let round = rounds - 3 + sector_id as usize;
+            assert_eq!(read(&mut store, sector_id)[..], pattern(round as u8)[..]);
+        }
+    }
+
+    /// Cut the power at every step of `update`, which must leave sector 1
+    /// holding `pattern(1)` or `pattern(2)`, with the others untouched.
+    fn cut_power_during(prepare: impl Fn(&mut Store), update: impl Fn(&mut Store)) {
+        let base = FlashSim::new(W25Q80);
+        let mut store = mount(&base);
+        for sector_id in 0..3 {
+            block_on(store.write(sector_id, &pattern(sector_id))).unwrap();
+        }
+        prepare(&mut store);
+        let image = base.contents();
+
+        let mut completed = false;
+        for budget in (0..).step_by(61) {
+            let sim = FlashSim::new(W25Q80);
+            sim.load(0, &image);
+            let mut store = mount(&sim);
+            sim.arm_power_loss(budget);
+            update(&mut store);
+            completed = sim.is_powered();
+            sim.power_cycle();
+
+            let mut store = mount(&sim);
+            let sector = read(&mut store, 1);
+            assert!(
+                sector[..] == pattern(1)[..] || sector[..] == pattern(2)[..],
+                "torn after {budget} bytes"
+            );
+            for sector_id in [0, 2] {
+                assert_eq!(read(&mut store, sector_id)[..], pattern(sector_id)[..]);
+            }
+            // The store carries on from wherever it was cut.
+            block_on(store.write(1, &pattern(3))).unwrap();
+            assert_eq!(read(&mut mount(&sim), 1)[..], pattern(3)[..]);
+
+            if completed {
+                break;
+            }
+        }
+        assert!(completed);
+    }
+
+    #[test]
+    fn power_loss_during_write() {
+        cut_power_during(
+            |_| {},
+            |store| {
+                let _ = block_on(store.write(1, &pattern(2)));
+            },
+        );
+    }
+
+    #[test]
+    fn power_loss_during_second_write() {
+        // Both copies hold committed contents, one of them is overwritten.
+        cut_power_during(
+            |store| {
+                block_on(store.write(1, &pattern(2))).unwrap();
+                block_on(store.write(1, &pattern(1))).unwrap();
+            },
+            |store| {
+                let _ = block_on(store.write(1, &pattern(2)));
+            },
+        );
+    }
+
+    #[test]
+    fn power_loss_during_compaction() {
+        cut_power_during(
+            |store| {
+                // Fill the active bank, so that the next append compacts.
+                for _ in 3..APPENDS {
+                    block_on(store.write(0, &pattern(0))).unwrap();
+                }
+            },
+            |store| {
+                let _ = block_on(store.write(1, &pattern(2)));
+            },
+        );
+    }
+
+    #[test]
+    fn over_wear_leveling_and_remapping() {
+        type Stack =
+            AtomicSectors<WearLeveling<Remapped<Driver<FlashSim, SimDelay>, 20, 2>, 10, 14>, 3>;
+        let mount = |sim: &FlashSim| {
+            let driver = driver(sim);
+            block_on(async {
+                let flash = Remapped::mount(driver, 0).await.unwrap();
+                let flash = WearLeveling::mount(flash, 0).await.unwrap();
+                Stack::mount(flash, 0).await.unwrap()
+            })
+        };
+
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        for round in 0..21 {
+            block_on(store.write(round % 3, &pattern(round))).unwrap();
+        }
+        let mut store = mount(&sim);
+        for sector_id in 0..3 {
+            assert_eq!(read(&mut store, sector_id)[..], pattern(18 + sector_id)[..]);
+        }
+    }
+}
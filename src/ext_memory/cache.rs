@@ -0,0 +1,298 @@
+//! Write-back RAM cache of whole sectors in front of an [`ExtMemory`].
+//!
+//! Dirty sectors reach the memory when they are evicted or on
+//! [`Cache::flush`], so anything written since the last flush is lost on
+//! power loss or if the cache is dropped.
+
+use crate::board::{ExtMemory, SectorBuffer};
+use crate::dma::DmaBuffer;
+use crate::w25qxx::SECTOR_SIZE;
+
+struct Line {
+    sector_id: Option<u8>,
+    dirty: bool,
+    /// Value of the cache clock when the line was last accessed.
+    last_used: u32,
+    data: SectorBuffer,
+}
+
+impl Line {
+    const EMPTY: Self = Self {
+        sector_id: None,
+        dirty: false,
+        last_used: 0,
+        data: DmaBuffer::new([0; SECTOR_SIZE]),
+    };
+}
+
+/// Caches up to `N` sectors of `M`, evicting the least recently used one.
+pub struct Cache<M, const N: usize> {
+    memory: M,
+    lines: [Line; N],
+    clock: u32,
+    hits: u32,
+    misses: u32,
+}
+
+impl<M: ExtMemory, const N: usize> Cache<M, N> {
+    pub fn new(memory: M) -> Self {
+        assert!(N > 0, "the cache needs at least one line");
+        Self {
+            memory,
+            lines: [Line::EMPTY; N],
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The line holding `sector_id`, if it is cached.
+    fn find(&mut self, sector_id: u8) -> Option<usize> {
+        self.clock = self.clock.wrapping_add(1);
+        let Some(index) = self
+            .lines
+            .iter()
+            .position(|line| line.sector_id == Some(sector_id))
+        else {
+            self.misses = self.misses.saturating_add(1);
+            return None;
+        };
+        self.hits = self.hits.saturating_add(1);
+        self.lines[index].last_used = self.clock;
+        Some(index)
+    }
+
+    /// Empty a line for a new sector, writing back the one it held.
+    async fn evict(&mut self) -> Result<usize, M::Error> {
+        let clock = self.clock;
+        // Empty lines first, then the one unused for the longest.
+        let (index, _) = self
+            .lines
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, line)| match line.sector_id {
+                None => u32::MAX,
+                Some(_) => clock.wrapping_sub(line.last_used),
+            })
+            .unwrap();
+        self.write_back(index).await?;
+        let line = &mut self.lines[index];
+        line.sector_id = None;
+        line.last_used = clock;
+        Ok(index)
+    }
+
+    /// The line holding `sector_id`, loading it from the memory on a miss.
+    async fn line(&mut self, sector_id: u8) -> Result<usize, M::Error> {
+        if let Some(index) = self.find(sector_id) {
+            return Ok(index);
+        }
+        let index = self.evict().await?;
+        let line = &mut self.lines[index];
+        // The line stays empty if the read fails halfway.
+        self.memory.read(sector_id, &mut line.data).await?;
+        line.sector_id = Some(sector_id);
+        Ok(index)
+    }
+
+    async fn write_back(&mut self, index: usize) -> Result<(), M::Error> {
+        let line = &mut self.lines[index];
+        if let (Some(sector_id), true) = (line.sector_id, line.dirty) {
+            self.memory.write(sector_id, &line.data).await?;
+            line.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// The cached contents of a sector, loading it on a miss. Unlike
+    /// [`read`](ExtMemory::read), this hands out the cache line itself
+    /// instead of copying it.
+    pub async fn get(&mut self, sector_id: u8) -> Result<&[u8; SECTOR_SIZE], M::Error> {
+        let index = self.line(sector_id).await?;
+        Ok(&self.lines[index].data)
+    }
+
+    /// Write every dirty sector back to the memory.
+    pub async fn flush(&mut self) -> Result<(), M::Error> {
+        for index in 0..N {
+            self.write_back(index).await?;
+        }
+        Ok(())
+    }
+
+    /// Number of accesses served from the cache.
+    pub fn hits(&self) -> u32 {
+        self.hits
+    }
+
+    /// Number of accesses that had to load the sector from the memory.
+    pub fn misses(&self) -> u32 {
+        self.misses
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.hits = 0;
+        self.misses = 0;
+    }
+
+    /// The memory behind the cache. Writing to it directly bypasses the cache,
+    /// so flush and [`invalidate`](Self::invalidate) around such writes.
+    pub fn memory(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    /// Drop all cached sectors, including dirty ones.
+    pub fn invalidate(&mut self) {
+        for line in &mut self.lines {
+            line.sector_id = None;
+            line.dirty = false;
+        }
+    }
+}
+
+impl<M: ExtMemory, const N: usize> ExtMemory for Cache<M, N> {
+    type Error = M::Error;
+
+    /// A miss takes a line without reading the sector, as all of it is
+    /// replaced.
+    async fn write(&mut self, sector_id: u8, data: &SectorBuffer) -> Result<(), Self::Error> {
+        let line = match self.find(sector_id) {
+            Some(index) => &mut self.lines[index],
+            None => {
+                let line = &mut self.lines[self.evict().await?];
+                line.sector_id = Some(sector_id);
+                line.data = *data;
+                line.dirty = true;
+                return Ok(());
+            }
+        };
+        if *line.data != **data {
+            line.data = *data;
+            line.dirty = true;
+        }
+        Ok(())
+    }
+
+    async fn read(&mut self, sector_id: u8, data: &mut SectorBuffer) -> Result<(), Self::Error> {
+        *data = self.lines[self.line(sector_id).await?].data;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use w25qxx::sim::{FlashSim, SimDelay};
+    use w25qxx::PAGE_SIZE;
+
+    use super::*;
+    use crate::board::ExtFlash;
+    use crate::ext_memory::tests::{driver, pattern, W25Q80};
+    use crate::ext_memory::Driver;
+
+    type Store = Cache<Driver<FlashSim, SimDelay>, 2>;
+
+    fn read(store: &mut Store, sector_id: u8) -> SectorBuffer {
+        let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+        block_on(store.read(sector_id, &mut buf)).unwrap();
+        buf
+    }
+
+    fn sector(sim: &FlashSim, sector_id: u8) -> Vec<u8> {
+        sim.read(sector_id as u32 * SECTOR_SIZE as u32, SECTOR_SIZE)
+    }
+
+    #[test]
+    fn least_recently_used_sector_is_evicted() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = Store::new(driver(&sim));
+
+        read(&mut store, 0);
+        read(&mut store, 1);
+        read(&mut store, 0);
+        assert_eq!((store.hits(), store.misses()), (1, 2));
+
+        // Sector 1 was used last longest ago.
+        read(&mut store, 2);
+        sim.clear_commands();
+        read(&mut store, 0);
+        read(&mut store, 2);
+        assert!(sim.commands().is_empty());
+        read(&mut store, 1);
+        assert_eq!(sim.commands(), [0x03; SECTOR_SIZE / PAGE_SIZE]);
+        assert_eq!((store.hits(), store.misses()), (3, 4));
+
+        store.reset_stats();
+        assert_eq!((store.hits(), store.misses()), (0, 0));
+    }
+
+    #[test]
+    fn dirty_sectors_are_written_back() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = Store::new(driver(&sim));
+
+        block_on(store.write(0, &pattern(1))).unwrap();
+        block_on(store.write(1, &pattern(2))).unwrap();
+        assert_eq!(sector(&sim, 0), [0xFF; SECTOR_SIZE]);
+        assert_eq!(read(&mut store, 0)[..], pattern(1)[..]);
+
+        // Evicting sector 1 writes it back.
+        read(&mut store, 2);
+        assert_eq!(sector(&sim, 1), pattern(2)[..]);
+        assert_eq!(sector(&sim, 0), [0xFF; SECTOR_SIZE]);
+
+        block_on(store.flush()).unwrap();
+        assert_eq!(sector(&sim, 0), pattern(1)[..]);
+
+        // Nothing is left dirty.
+        sim.clear_commands();
+        block_on(store.flush()).unwrap();
+        assert!(sim.commands().is_empty());
+    }
+
+    #[test]
+    fn identical_data_leaves_the_line_clean() {
+        let sim = FlashSim::new(W25Q80);
+        sim.load(0, &pattern(3)[..]);
+        let mut store = Store::new(driver(&sim));
+
+        read(&mut store, 0);
+        block_on(store.write(0, &pattern(3))).unwrap();
+        sim.clear_commands();
+        block_on(store.flush()).unwrap();
+        assert!(sim.commands().is_empty());
+    }
+
+    #[test]
+    fn invalidate_drops_dirty_sectors() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = Store::new(driver(&sim));
+
+        block_on(store.write(0, &pattern(4))).unwrap();
+        store.invalidate();
+        block_on(store.flush()).unwrap();
+        assert_eq!(sector(&sim, 0), [0xFF; SECTOR_SIZE]);
+
+        // The sector is loaded again from the memory.
+        let misses = store.misses();
+        assert_eq!(read(&mut store, 0)[..], [0xFF; SECTOR_SIZE]);
+        assert_eq!(store.misses(), misses + 1);
+    }
+
+    #[test]
+    fn write_miss_does_not_read_the_sector() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = Store::new(driver(&sim));
+
+        sim.clear_commands();
+        block_on(store.write(0, &pattern(5))).unwrap();
+        assert!(sim.commands().is_empty());
+
+        // Even with the memory gone, the write lands in the cache.
+        sim.arm_power_loss(0);
+        assert!(block_on(store.memory().erase_sector(3 * SECTOR_SIZE as u32)).is_err());
+        block_on(store.write(1, &pattern(6))).unwrap();
+        assert_eq!(*block_on(store.get(1)).unwrap(), *pattern(6));
+        assert!(block_on(store.get(2)).is_err());
+    }
+}
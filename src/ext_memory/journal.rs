@@ -0,0 +1,244 @@
+//! Append-only journal of fixed-size records, kept in two banks.
+//!
+//! Records are appended to the active bank. When it fills up, the owner's
+//! state is written as a snapshot to the other bank, and the header making
+//! that bank active is written last, so losing power at any point leaves
+//! either the old or the new bank intact. Torn records fail their CRC and are
+//! skipped on replay.
+
+use crate::board::ExtFlash;
+use crate::w25qxx::{crc32, PAGE_SIZE, SECTOR_SIZE};
+
+pub const RECORD_SIZE: usize = 16;
+const RECORDS_PER_PAGE: usize = PAGE_SIZE / RECORD_SIZE;
+
+/// Slot 0 of a bank: its generation, written once the bank is complete.
+const HEADER: u8 = 0x01;
+
+/// One journal entry. The meaning of the fields besides `kind` is up to the
+/// owner of the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub kind: u8,
+    pub sector: u8,
+    pub location: u16,
+    pub sequence: u32,
+    pub value: u32,
+}
+
+impl Record {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut buf = [0; RECORD_SIZE];
+        buf[0] = self.kind;
+        buf[1] = self.sector;
+        buf[2..4].copy_from_slice(&self.location.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.value.to_le_bytes());
+        let crc = crc32(&buf[..12]);
+        buf[12..].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// `None` for a blank or corrupt slot.
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let word = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        if word(12) != crc32(&buf[..12]) {
+            return None;
+        }
+        Some(Self {
+            kind: buf[0],
+            sector: buf[1],
+            location: u16::from_le_bytes([buf[2], buf[3]]),
+            sequence: word(4),
+            value: word(8),
+        })
+    }
+
+    fn header(generation: u32) -> Self {
+        Self {
+            kind: HEADER,
+            sector: 0,
+            location: 0,
+            sequence: generation,
+            value: 0,
+        }
+    }
+}
+
+fn is_blank(slot: &[u8]) -> bool {
+    slot.iter().all(|&b| b == 0xFF)
+}
+
+/// A journal of two banks of `sectors` sectors each, from `base`.
+pub struct Journal {
+    base: u32,
+    sectors: usize,
+    bank: u32,
+    generation: u32,
+    /// Next free record slot of the active bank.
+    cursor: usize,
+}
+
+impl Journal {
+    /// Sectors per bank for snapshots of up to `records` records: room for
+    /// the snapshot and its header plus a sector of appends.
+    pub const fn sectors_for(records: usize) -> usize {
+        ((records + 1) * RECORD_SIZE).div_ceil(SECTOR_SIZE) + 1
+    }
+
+    /// Open the journal at `base`, passing the records of the active bank to
+    /// `apply` in order. A journal is formatted if none is found.
+    pub async fn mount<F: ExtFlash>(
+        flash: &mut F,
+        base: u32,
+        sectors: usize,
+        mut apply: impl FnMut(Record),
+    ) -> Result<Self, F::Error> {
+        let mut new = Self {
+            base,
+            sectors,
+            bank: 0,
+            generation: 0,
+            cursor: 1,
+        };
+
+        let mut newest = None;
+        for bank in 0..2 {
+            let mut slot = [0; RECORD_SIZE];
+            flash.read_at(new.slot_address(bank, 0), &mut slot).await?;
+            let Some(header) = Record::decode(&slot).filter(|r| r.kind == HEADER) else {
+                continue;
+            };
+            let newer = match newest {
+                // Generations are compared modulo 2^32.
+                Some((_, generation)) => (header.sequence.wrapping_sub(generation) as i32) > 0,
+                None => true,
+            };
+            if newer {
+                newest = Some((bank, header.sequence));
+            }
+        }
+
+        match newest {
+            Some((bank, generation)) => {
+                new.bank = bank;
+                new.generation = generation;
+                new.replay(flash, &mut apply).await?;
+            }
+            None => {
+                new.erase_bank(flash, 0).await?;
+                new.write_record(flash, 0, 0, Record::header(0)).await?;
+            }
+        }
+        Ok(new)
+    }
+
+    fn records(&self) -> usize {
+        self.sectors * SECTOR_SIZE / RECORD_SIZE
+    }
+
+    fn slot_address(&self, bank: u32, slot: usize) -> u32 {
+        self.base + ((bank as usize * self.sectors * SECTOR_SIZE) + slot * RECORD_SIZE) as u32
+    }
+
+    async fn erase_bank<F: ExtFlash>(&self, flash: &mut F, bank: u32) -> Result<(), F::Error> {
+        for sector in 0..self.sectors {
+            let address = self.slot_address(bank, sector * SECTOR_SIZE / RECORD_SIZE);
+            flash.erase_sector(address).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_record<F: ExtFlash>(
+        &self,
+        flash: &mut F,
+        bank: u32,
+        slot: usize,
+        record: Record,
+    ) -> Result<(), F::Error> {
+        flash
+            .program_at(self.slot_address(bank, slot), &record.encode())
+            .await
+    }
+
+    /// Pass the records of the active bank to `apply`.
+    async fn replay<F: ExtFlash>(
+        &mut self,
+        flash: &mut F,
+        apply: &mut impl FnMut(Record),
+    ) -> Result<(), F::Error> {
+        let mut page = [0; PAGE_SIZE];
+        let mut end = 1;
+        for first in (0..self.records()).step_by(RECORDS_PER_PAGE) {
+            flash
+                .read_at(self.slot_address(self.bank, first), &mut page)
+                .await?;
+            for (i, slot) in page.chunks(RECORD_SIZE).enumerate() {
+                let index = first + i;
+                if index == 0 || is_blank(slot) {
+                    continue;
+                }
+                // Torn records are skipped, but appends carry on after them.
+                end = index + 1;
+                if let Some(record) = Record::decode(slot) {
+                    apply(record);
+                }
+            }
+        }
+        self.cursor = end;
+        Ok(())
+    }
+
+    /// Write `snapshot` to the other bank and switch to it.
+    async fn compact<F: ExtFlash>(
+        &mut self,
+        flash: &mut F,
+        snapshot: impl Iterator<Item = Record>,
+    ) -> Result<(), F::Error> {
+        let bank = 1 - self.bank;
+        self.erase_bank(flash, bank).await?;
+
+        let mut page = [0xFF; PAGE_SIZE];
+        // Slot 0 is reserved for the header.
+        let mut slot = 1;
+        let mut snapshot = snapshot.peekable();
+        while let Some(record) = snapshot.next() {
+            assert!(slot < self.records(), "snapshot larger than a bank");
+            let offset = slot % RECORDS_PER_PAGE * RECORD_SIZE;
+            page[offset..offset + RECORD_SIZE].copy_from_slice(&record.encode());
+            slot += 1;
+            if slot.is_multiple_of(RECORDS_PER_PAGE) || snapshot.peek().is_none() {
+                let first = (slot - 1) / RECORDS_PER_PAGE * RECORDS_PER_PAGE;
+                flash
+                    .program_at(self.slot_address(bank, first), &page)
+                    .await?;
+                page = [0xFF; PAGE_SIZE];
+            }
+        }
+
+        let generation = self.generation.wrapping_add(1);
+        self.write_record(flash, bank, 0, Record::header(generation))
+            .await?;
+        self.bank = bank;
+        self.generation = generation;
+        self.cursor = slot;
+        Ok(())
+    }
+
+    /// Append `record`, first compacting the journal to `snapshot` if the
+    /// active bank is full. The snapshot must not include `record`.
+    pub async fn append<F: ExtFlash>(
+        &mut self,
+        flash: &mut F,
+        record: Record,
+        snapshot: impl Iterator<Item = Record>,
+    ) -> Result<(), F::Error> {
+        if self.cursor == self.records() {
+            self.compact(flash, snapshot).await?;
+        }
+        self.write_record(flash, self.bank, self.cursor, record)
+            .await?;
+        self.cursor += 1;
+        Ok(())
+    }
+}
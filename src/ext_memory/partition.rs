@@ -0,0 +1,435 @@
+//! Partition table assigning ranges of sectors of an [`ExtMemory`] to regions.
+//!
+//! The table lives in sector [`TABLE_SECTOR`], which no partition may use.
+//! When the memory holds no table, the default [`Layout`] is written to it.
+//! Every layout is checked for overlapping and out-of-range partitions:
+//! built-in layouts when they are declared in a `const`, stored ones at mount.
+
+use crate::board::{ExtMemory, SectorBuffer};
+use crate::w25qxx::{crc32, SECTOR_SIZE};
+
+/// Sector holding the partition table.
+pub const TABLE_SECTOR: u8 = 0;
+/// Partitions start after the table.
+const FIRST_PARTITION_SECTOR: u8 = TABLE_SECTOR + 1;
+/// Most partitions a table can hold.
+pub const MAX_PARTITIONS: usize = 16;
+
+/// "PTB1" in little-endian byte order.
+const MAGIC: u32 = 0x3142_5450;
+/// Magic and number of entries.
+const HEADER_SIZE: usize = 5;
+const ENTRY_SIZE: usize = 3;
+
+/// What a partition is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Region {
+    Presets = 1,
+    Calibration = 2,
+    FirmwareStaging = 3,
+    Sequences = 4,
+}
+
+impl Region {
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Self::Presets),
+            2 => Some(Self::Calibration),
+            3 => Some(Self::FirmwareStaging),
+            4 => Some(Self::Sequences),
+            _ => None,
+        }
+    }
+}
+
+/// `sectors` sectors from `first_sector`, assigned to `region`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionSpec {
+    pub region: Region,
+    pub first_sector: u8,
+    pub sectors: u8,
+}
+
+/// Filler for the unused entries of a table.
+const UNUSED: PartitionSpec = PartitionSpec {
+    region: Region::Presets,
+    first_sector: 0,
+    sectors: 0,
+};
+
+impl PartitionSpec {
+    const fn end(&self) -> usize {
+        self.first_sector as usize + self.sectors as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutError {
+    TooManyPartitions,
+    /// A partition has no sectors.
+    Empty(Region),
+    /// A partition includes the table sector or lies past the end of the memory.
+    OutOfRange(Region),
+    Overlap(Region, Region),
+    /// Two partitions are assigned to the same region.
+    Duplicate(Region),
+}
+
+impl LayoutError {
+    const fn message(&self) -> &'static str {
+        match self {
+            Self::TooManyPartitions => "too many partitions",
+            Self::Empty(_) => "empty partition",
+            Self::OutOfRange(_) => "partition out of range",
+            Self::Overlap(..) => "overlapping partitions",
+            Self::Duplicate(_) => "region assigned twice",
+        }
+    }
+}
+
+/// Check `partitions` against a memory of `sector_count` sectors.
+const fn check(partitions: &[PartitionSpec], sector_count: usize) -> Result<(), LayoutError> {
+    if partitions.len() > MAX_PARTITIONS {
+        return Err(LayoutError::TooManyPartitions);
+    }
+    let mut i = 0;
+    while i < partitions.len() {
+        let a = &partitions[i];
+        if a.sectors == 0 {
+            return Err(LayoutError::Empty(a.region));
+        }
+        if a.first_sector < FIRST_PARTITION_SECTOR || a.end() > sector_count {
+            return Err(LayoutError::OutOfRange(a.region));
+        }
+        let mut j = 0;
+        while j < i {
+            let b = &partitions[j];
+            if a.region as u8 == b.region as u8 {
+                return Err(LayoutError::Duplicate(a.region));
+            }
+            if (a.first_sector as usize) < b.end() && (b.first_sector as usize) < a.end() {
+                return Err(LayoutError::Overlap(b.region, a.region));
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+/// A validated set of partitions over a memory of a given size.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    partitions: &'static [PartitionSpec],
+    sector_count: usize,
+}
+
+impl Layout {
+    /// Panics if the partitions do not fit the memory, which fails the build
+    /// when the layout is declared in a `const`.
+    pub const fn new(partitions: &'static [PartitionSpec], sector_count: usize) -> Self {
+        assert!(sector_count <= 1 << 8, "sector ids are 8 bits");
+        if let Err(error) = check(partitions, sector_count) {
+            panic!("{}", error.message());
+        }
+        Self {
+            partitions,
+            sector_count,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Memory(E),
+    /// The table sector holds something other than a partition table.
+    Corrupt,
+    /// The stored table does not fit the memory.
+    InvalidLayout(LayoutError),
+    /// No partition is assigned to the region.
+    NotFound(Region),
+    /// `sector_id` is past the end of the partition.
+    OutOfRange {
+        sector_id: u8,
+    },
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(value: E) -> Self {
+        Self::Memory(value)
+    }
+}
+
+fn encode(partitions: &[PartitionSpec], buf: &mut [u8; SECTOR_SIZE]) {
+    buf.fill(0xFF);
+    buf[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    buf[4] = partitions.len() as u8;
+    for (partition, entry) in partitions
+        .iter()
+        .zip(buf[HEADER_SIZE..].chunks_mut(ENTRY_SIZE))
+    {
+        entry.copy_from_slice(&[
+            partition.region as u8,
+            partition.first_sector,
+            partition.sectors,
+        ]);
+    }
+    let len = HEADER_SIZE + partitions.len() * ENTRY_SIZE;
+    let crc = crc32(&buf[..len]);
+    buf[len..len + 4].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// The stored partitions and their number, or `None` if `buf` holds no table.
+fn decode(buf: &[u8; SECTOR_SIZE]) -> Option<([PartitionSpec; MAX_PARTITIONS], usize)> {
+    let count = buf[4] as usize;
+    if buf[..4] != MAGIC.to_le_bytes() || count > MAX_PARTITIONS {
+        return None;
+    }
+    let len = HEADER_SIZE + count * ENTRY_SIZE;
+    if buf[len..len + 4] != crc32(&buf[..len]).to_le_bytes() {
+        return None;
+    }
+    let mut partitions = [UNUSED; MAX_PARTITIONS];
+    for (partition, entry) in partitions
+        .iter_mut()
+        .zip(buf[HEADER_SIZE..len].chunks(ENTRY_SIZE))
+    {
+        *partition = PartitionSpec {
+            region: Region::from_tag(entry[0])?,
+            first_sector: entry[1],
+            sectors: entry[2],
+        };
+    }
+    Some((partitions, count))
+}
+
+/// The partitions of a memory, handing out access to one at a time.
+pub struct PartitionTable<M> {
+    memory: M,
+    partitions: [PartitionSpec; MAX_PARTITIONS],
+    count: usize,
+}
+
+impl<M: ExtMemory> PartitionTable<M> {
+    /// Read the table from `memory`, writing `default` to it if it has none.
+    /// The table sector is read into `buf`, so that the caller decides where
+    /// it lives: the stack is out of reach of DMA.
+    pub async fn mount(
+        mut memory: M,
+        default: &Layout,
+        buf: &mut SectorBuffer,
+    ) -> Result<Self, Error<M::Error>> {
+        memory.read(TABLE_SECTOR, buf).await?;
+
+        let (partitions, count) = match decode(buf) {
+            Some(table) => table,
+            None if buf.iter().all(|&b| b == 0xFF) => {
+                encode(default.partitions, buf);
+                memory.write(TABLE_SECTOR, buf).await?;
+                let mut partitions = [UNUSED; MAX_PARTITIONS];
+                partitions[..default.partitions.len()].copy_from_slice(default.partitions);
+                (partitions, default.partitions.len())
+            }
+            None => return Err(Error::Corrupt),
+        };
+        check(&partitions[..count], default.sector_count).map_err(Error::InvalidLayout)?;
+
+        Ok(Self {
+            memory,
+            partitions,
+            count,
+        })
+    }
+
+    pub fn partitions(&self) -> &[PartitionSpec] {
+        &self.partitions[..self.count]
+    }
+
+    pub fn get(&self, region: Region) -> Option<PartitionSpec> {
+        self.partitions()
+            .iter()
+            .find(|p| p.region == region)
+            .copied()
+    }
+
+    /// Access to the sectors of the partition assigned to `region`.
+    pub fn partition(&mut self, region: Region) -> Result<Partition<'_, M>, Error<M::Error>> {
+        let spec = self.get(region).ok_or(Error::NotFound(region))?;
+        Ok(Partition {
+            memory: &mut self.memory,
+            spec,
+        })
+    }
+
+    pub fn memory(&mut self) -> &mut M {
+        &mut self.memory
+    }
+}
+
+/// One partition, addressed by sector ids relative to its first sector.
+pub struct Partition<'a, M> {
+    memory: &'a mut M,
+    spec: PartitionSpec,
+}
+
+impl<M: ExtMemory> Partition<'_, M> {
+    pub fn spec(&self) -> PartitionSpec {
+        self.spec
+    }
+
+    fn sector(&self, sector_id: u8) -> Result<u8, Error<M::Error>> {
+        if sector_id >= self.spec.sectors {
+            return Err(Error::OutOfRange { sector_id });
+        }
+        Ok(self.spec.first_sector + sector_id)
+    }
+}
+
+impl<M: ExtMemory> ExtMemory for Partition<'_, M> {
+    type Error = Error<M::Error>;
+
+    async fn write(&mut self, sector_id: u8, data: &SectorBuffer) -> Result<(), Self::Error> {
+        let sector = self.sector(sector_id)?;
+        self.memory.write(sector, data).await?;
+        Ok(())
+    }
+
+    async fn read(&mut self, sector_id: u8, data: &mut SectorBuffer) -> Result<(), Self::Error> {
+        let sector = self.sector(sector_id)?;
+        self.memory.read(sector, data).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use w25qxx::sim::{FlashSim, SimDelay, SimError};
+
+    use super::*;
+    use crate::dma::DmaBuffer;
+    use crate::ext_memory::tests::{driver, pattern, W25Q80};
+    use crate::ext_memory::Driver;
+
+    const LAYOUT: Layout = Layout::new(
+        &[
+            PartitionSpec {
+                region: Region::Presets,
+                first_sector: 1,
+                sectors: 4,
+            },
+            PartitionSpec {
+                region: Region::Sequences,
+                first_sector: 5,
+                sectors: 2,
+            },
+        ],
+        8,
+    );
+
+    type Table = PartitionTable<Driver<FlashSim, SimDelay>>;
+
+    fn mount(sim: &FlashSim) -> Result<Table, Error<w25qxx::Error<SimError>>> {
+        let driver = driver(sim);
+        let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+        block_on(Table::mount(driver, &LAYOUT, &mut buf))
+    }
+
+    #[test]
+    fn default_layout_is_written_once() {
+        let sim = FlashSim::new(W25Q80);
+        let mut table = mount(&sim).unwrap();
+        assert_eq!(table.partitions(), LAYOUT.partitions);
+        let mut presets = table.partition(Region::Presets).unwrap();
+        block_on(presets.write(3, &pattern(3))).unwrap();
+        assert!(matches!(
+            block_on(presets.write(4, &pattern(4))),
+            Err(Error::OutOfRange { sector_id: 4 })
+        ));
+        assert!(matches!(
+            table.partition(Region::Calibration),
+            Err(Error::NotFound(Region::Calibration))
+        ));
+
+        let mut table = mount(&sim).unwrap();
+        assert_eq!(table.get(Region::Sequences), Some(LAYOUT.partitions[1]));
+        let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+        let mut presets = table.partition(Region::Presets).unwrap();
+        block_on(presets.read(3, &mut buf)).unwrap();
+        assert_eq!(buf[..], pattern(3)[..]);
+        assert_eq!(
+            sim.read(4 * SECTOR_SIZE as u32, SECTOR_SIZE),
+            pattern(3)[..]
+        );
+    }
+
+    #[test]
+    fn foreign_table_sector_is_left_alone() {
+        let sim = FlashSim::new(W25Q80);
+        sim.load(0, &[0x00; 16]);
+        assert!(matches!(mount(&sim), Err(Error::Corrupt)));
+        assert_eq!(sim.read(0, 16), [0x00; 16]);
+    }
+
+    /// Store a table of `partitions` with a valid CRC, as if written by a
+    /// firmware with another layout.
+    fn store(sim: &FlashSim, partitions: &[PartitionSpec]) {
+        let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+        encode(partitions, &mut buf);
+        sim.load(0, &buf[..]);
+    }
+
+    const fn spec(region: Region, first_sector: u8, sectors: u8) -> PartitionSpec {
+        PartitionSpec {
+            region,
+            first_sector,
+            sectors,
+        }
+    }
+
+    #[test]
+    fn invalid_stored_tables_are_refused() {
+        let cases = [
+            (
+                [spec(Region::Presets, 1, 4), spec(Region::Sequences, 4, 2)],
+                LayoutError::Overlap(Region::Presets, Region::Sequences),
+            ),
+            (
+                [spec(Region::Presets, 1, 4), spec(Region::Sequences, 6, 3)],
+                LayoutError::OutOfRange(Region::Sequences),
+            ),
+            (
+                [spec(Region::Presets, 0, 4), spec(Region::Sequences, 5, 2)],
+                LayoutError::OutOfRange(Region::Presets),
+            ),
+            (
+                [spec(Region::Presets, 1, 2), spec(Region::Presets, 3, 2)],
+                LayoutError::Duplicate(Region::Presets),
+            ),
+            (
+                [spec(Region::Presets, 1, 4), spec(Region::Calibration, 5, 0)],
+                LayoutError::Empty(Region::Calibration),
+            ),
+        ];
+        for (partitions, expected) in cases {
+            let sim = FlashSim::new(W25Q80);
+            store(&sim, &partitions);
+            let table = sim.read(0, SECTOR_SIZE);
+            match mount(&sim) {
+                Err(Error::InvalidLayout(error)) => assert_eq!(error, expected),
+                _ => panic!("{partitions:?} mounted"),
+            }
+            // The table is not replaced by the default.
+            assert_eq!(sim.read(0, SECTOR_SIZE), table);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "overlapping partitions")]
+    fn invalid_layouts_are_refused() {
+        static OVERLAPPING: [PartitionSpec; 2] =
+            [spec(Region::Presets, 1, 4), spec(Region::Sequences, 3, 2)];
+        Layout::new(&OVERLAPPING, 8);
+    }
+}
@@ -0,0 +1,609 @@
+//! Bad-sector detection and remapping.
+//!
+//! The memory below reads back every erase and program, like
+//! [`Driver`](super::Driver) does, and fails with an error for which
+//! [`FlashError::is_verify_failure`] holds. A sector that fails is retired:
+//! its contents are copied to the next unused spare, and the move is appended
+//! to a table in a reserved sector so that it survives a reboot.
+//!
+//! The table is never erased. Each spare has a fixed slot in it, written once
+//! the spare is in use, so a power loss while a sector is being moved leaves
+//! it where it was. A spare that fails in turn is marked dead in its slot,
+//! with a marker that a torn write leaves blank rather than valid.
+//!
+//! Layout from `base`: the table sector, `SECTORS` data sectors, then
+//! `SPARES` spare sectors.
+
+use core::ops::Range;
+
+use crate::board::{ExtFlash, ExtMemory, FlashError, SectorBuffer};
+use crate::w25qxx::{PAGE_SIZE, SECTOR_SIZE};
+
+/// Logical sector id and its complement.
+const SLOT_SIZE: usize = 2;
+/// Slot of a spare that failed before it could be used. Only its second byte
+/// is programmed, so a torn write leaves the slot blank. It would be the slot
+/// of logical sector 255, which is why there are at most 255 sectors.
+const DEAD: [u8; SLOT_SIZE] = [0xFF, 0x00];
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Memory(E),
+    /// `sector_id` is not one of the logical sectors of the store.
+    OutOfRange {
+        sector_id: u8,
+    },
+    /// `addr` lies past the logical sectors of the store, further than a
+    /// sector id can tell.
+    AddressOutOfRange {
+        addr: u32,
+    },
+    /// The table, data and spare sectors do not fit in the memory from `base`.
+    TooSmall,
+    /// A sector failed and every spare is already in use.
+    NoSpares,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(value: E) -> Self {
+        Self::Memory(value)
+    }
+}
+
+impl<E: FlashError> FlashError for Error<E> {
+    fn is_verify_failure(&self) -> bool {
+        matches!(self, Self::Memory(error) if error.is_verify_failure())
+    }
+}
+
+/// Counters for diagnostics. Failures are counted since mount, the rest
+/// comes from the persistent table.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Health {
+    /// Erases that did not leave the sector blank.
+    pub erase_failures: u32,
+    /// Programs that did not read back as written.
+    pub program_failures: u32,
+    /// Sectors retired, including spares that failed in turn.
+    pub retired: usize,
+    /// Spares still available.
+    pub spares_left: usize,
+}
+
+/// `SECTORS` logical sectors backed by data sectors and `SPARES` spares.
+pub struct Remapped<F, const SECTORS: usize, const SPARES: usize> {
+    flash: F,
+    base: u32,
+    /// Physical sector, counted from the first data sector, of each logical one.
+    map: [usize; SECTORS],
+    /// Spares taken, including dead ones and those whose table slot was torn.
+    spares_used: usize,
+    erase_failures: u32,
+    program_failures: u32,
+}
+
+impl<F: ExtFlash, const SECTORS: usize, const SPARES: usize> Remapped<F, SECTORS, SPARES> {
+    /// Sectors taken from the memory below, table included.
+    pub const FOOTPRINT: usize = 1 + SECTORS + SPARES;
+
+    /// Open the store at `base`, applying the remappings recorded in its table.
+    pub async fn mount(mut flash: F, base: u32) -> Result<Self, Error<F::Error>> {
+        assert!(SECTORS < 1 << 8, "logical sector 255 is taken by DEAD");
+        assert!(
+            SPARES * SLOT_SIZE <= SECTOR_SIZE,
+            "the table does not fit in a sector"
+        );
+        let len = Self::FOOTPRINT * SECTOR_SIZE;
+        if !(base as usize).is_multiple_of(SECTOR_SIZE)
+            || base as usize + len > flash.capacity() as usize
+        {
+            return Err(Error::TooSmall);
+        }
+
+        let mut map = core::array::from_fn(|sector| sector);
+        let mut spares_used = 0;
+        let mut page = [0; PAGE_SIZE];
+        for first in (0..SPARES).step_by(PAGE_SIZE / SLOT_SIZE) {
+            flash
+                .read_at(base + (first * SLOT_SIZE) as u32, &mut page)
+                .await?;
+            for (i, slot) in page.chunks(SLOT_SIZE).enumerate() {
+                let spare = first + i;
+                if spare == SPARES {
+                    break;
+                }
+                // A slot whose write failed or was cut short may be blank,
+                // with later spares in use after it.
+                if slot == [0xFF; SLOT_SIZE] {
+                    continue;
+                }
+                spares_used = spare + 1;
+                let logical = slot[0] as usize;
+                if slot[1] == !slot[0] && logical < SECTORS {
+                    map[logical] = SECTORS + spare;
+                }
+            }
+        }
+        Ok(Self {
+            flash,
+            base,
+            map,
+            spares_used,
+            erase_failures: 0,
+            program_failures: 0,
+        })
+    }
+
+    fn address(&self, physical: usize) -> u32 {
+        self.base + ((1 + physical) * SECTOR_SIZE) as u32
+    }
+
+    /// The logical sector holding byte `addr`.
+    fn logical(&self, addr: u32) -> Result<usize, Error<F::Error>> {
+        let logical = addr as usize / SECTOR_SIZE;
+        if logical >= SECTORS {
+            return Err(match u8::try_from(logical) {
+                Ok(sector_id) => Error::OutOfRange { sector_id },
+                Err(_) => Error::AddressOutOfRange { addr },
+            });
+        }
+        Ok(logical)
+    }
+
+    /// Erase `to` and copy the contents of `from` to it, except for the bytes
+    /// in `skip`, which are left blank.
+    async fn copy(
+        &mut self,
+        from: usize,
+        to: usize,
+        skip: Range<usize>,
+    ) -> Result<(), Error<F::Error>> {
+        if let Err(error) = self.flash.erase_sector(self.address(to)).await {
+            if error.is_verify_failure() {
+                self.erase_failures += 1;
+            }
+            return Err(error.into());
+        }
+
+        let mut page = [0; PAGE_SIZE];
+        for offset in (0..SECTOR_SIZE).step_by(PAGE_SIZE) {
+            let page_range = offset..offset + PAGE_SIZE;
+            if skip.start <= page_range.start && page_range.end <= skip.end {
+                continue;
+            }
+            let from_address = self.address(from) + offset as u32;
+            self.flash.read_at(from_address, &mut page).await?;
+            for (i, byte) in page.iter_mut().enumerate() {
+                if skip.contains(&(offset + i)) {
+                    *byte = 0xFF;
+                }
+            }
+            if page.iter().all(|&b| b == 0xFF) {
+                continue;
+            }
+            let to_address = self.address(to) + offset as u32;
+            if let Err(error) = self.flash.program_at(to_address, &page).await {
+                if error.is_verify_failure() {
+                    self.program_failures += 1;
+                }
+                return Err(error.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Move `logical` to the next good spare and record it in the table. The
+    /// bytes in `skip` are about to be rewritten, so they are not copied.
+    async fn retire(&mut self, logical: usize, skip: Range<usize>) -> Result<(), Error<F::Error>> {
+        loop {
+            if self.spares_used == SPARES {
+                return Err(Error::NoSpares);
+            }
+            let spare = self.spares_used;
+            let result = self
+                .copy(self.map[logical], SECTORS + spare, skip.clone())
+                .await;
+            let slot = match result {
+                Ok(()) => [logical as u8, !(logical as u8)],
+                Err(ref error) if error.is_verify_failure() => DEAD,
+                Err(error) => return Err(error),
+            };
+            // Taken even if the slot write fails, since the slot may be torn.
+            self.spares_used += 1;
+            self.flash
+                .program_at(self.base + (spare * SLOT_SIZE) as u32, &slot)
+                .await?;
+            if slot != DEAD {
+                self.map[logical] = SECTORS + spare;
+                return Ok(());
+            }
+        }
+    }
+
+    /// The spare a logical sector was moved to, if any.
+    pub fn spare_of(&self, sector_id: u8) -> Option<usize> {
+        let physical = *self.map.get(sector_id as usize)?;
+        physical.checked_sub(SECTORS)
+    }
+
+    pub fn health(&self) -> Health {
+        Health {
+            erase_failures: self.erase_failures,
+            program_failures: self.program_failures,
+            retired: self.spares_used,
+            spares_left: SPARES - self.spares_used,
+        }
+    }
+
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+}
+
+impl<F: ExtFlash, const SECTORS: usize, const SPARES: usize> ExtMemory
+    for Remapped<F, SECTORS, SPARES>
+{
+    type Error = Error<F::Error>;
+
+    async fn write(&mut self, sector_id: u8, data: &SectorBuffer) -> Result<(), Self::Error> {
+        let address = sector_id as u32 * SECTOR_SIZE as u32;
+        self.erase_sector(address).await?;
+        self.program_at(address, &data[..]).await
+    }
+
+    async fn read(&mut self, sector_id: u8, data: &mut SectorBuffer) -> Result<(), Self::Error> {
+        let address = sector_id as u32 * SECTOR_SIZE as u32;
+        self.read_at(address, &mut data[..]).await
+    }
+}
+
+impl<F: ExtFlash, const SECTORS: usize, const SPARES: usize> ExtFlash
+    for Remapped<F, SECTORS, SPARES>
+{
+    fn capacity(&self) -> u32 {
+        (SECTORS * SECTOR_SIZE) as u32
+    }
+
+    async fn read_at(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let mut addr = addr;
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let logical = self.logical(addr)?;
+            let offset = addr % SECTOR_SIZE as u32;
+            let len = buf.len().min(SECTOR_SIZE - offset as usize);
+            let (chunk, rest) = buf.split_at_mut(len);
+            let address = self.address(self.map[logical]) + offset;
+            self.flash.read_at(address, chunk).await?;
+            addr += len as u32;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    /// A sector that fails to program is moved to a spare with what it held
+    /// so far, and the data programmed there instead.
+    async fn program_at(&mut self, addr: u32, data: &[u8]) -> Result<(), Self::Error> {
+        let mut addr = addr;
+        let mut data = data;
+        while !data.is_empty() {
+            let logical = self.logical(addr)?;
+            let offset = addr as usize % SECTOR_SIZE;
+            let (chunk, rest) = data.split_at(data.len().min(SECTOR_SIZE - offset));
+            loop {
+                let address = self.address(self.map[logical]) + offset as u32;
+                match self.flash.program_at(address, chunk).await {
+                    Err(error) if error.is_verify_failure() => {
+                        self.program_failures += 1;
+                        self.retire(logical, offset..offset + chunk.len()).await?;
+                    }
+                    result => break result?,
+                }
+            }
+            addr += chunk.len() as u32;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// A sector that fails to erase is replaced by an erased spare.
+    async fn erase_sector(&mut self, addr: u32) -> Result<(), Self::Error> {
+        let logical = self.logical(addr)?;
+        match self
+            .flash
+            .erase_sector(self.address(self.map[logical]))
+            .await
+        {
+            Err(error) if error.is_verify_failure() => {
+                self.erase_failures += 1;
+                self.retire(logical, 0..SECTOR_SIZE).await
+            }
+            result => Ok(result?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use w25qxx::sim::{FlashSim, SimDelay};
+
+    use super::*;
+    use crate::dma::DmaBuffer;
+    use crate::ext_memory::tests::{driver, pattern, W25Q80};
+    use crate::ext_memory::Driver;
+
+    type Store = Remapped<Driver<FlashSim, SimDelay>, 4, 2>;
+
+    fn mount(sim: &FlashSim) -> Store {
+        block_on(Store::mount(driver(sim), 0)).unwrap()
+    }
+
+    fn read(store: &mut Store, sector_id: u8) -> SectorBuffer {
+        let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+        block_on(store.read(sector_id, &mut buf)).unwrap();
+        buf
+    }
+
+    /// Flash address of byte `offset` of data sector `physical`, spares
+    /// following the data sectors.
+    fn physical_address(physical: usize, offset: usize) -> u32 {
+        ((1 + physical) * SECTOR_SIZE + offset) as u32
+    }
+
+    #[test]
+    fn healthy_sectors_stay_in_place() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        for sector_id in 0..4 {
+            block_on(store.write(sector_id, &pattern(sector_id))).unwrap();
+        }
+        assert_eq!(
+            sim.read(physical_address(2, 0), SECTOR_SIZE),
+            pattern(2)[..]
+        );
+        assert_eq!(
+            store.health(),
+            Health {
+                spares_left: 2,
+                ..Health::default()
+            }
+        );
+        assert_eq!(read(&mut mount(&sim), 3)[..], pattern(3)[..]);
+    }
+
+    #[test]
+    fn failed_program_retires_the_sector() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        block_on(store.write(1, &pattern(1))).unwrap();
+        // A bit that no longer programs.
+        sim.stick_bit(physical_address(2, 0x123), 0, true);
+        let mut data = pattern(2);
+        data[0x123] = 0x00;
+        block_on(store.write(2, &data)).unwrap();
+
+        assert_eq!(store.spare_of(2), Some(0));
+        assert_eq!(read(&mut store, 2)[..], data[..]);
+        assert_eq!(
+            store.health(),
+            Health {
+                erase_failures: 0,
+                program_failures: 1,
+                retired: 1,
+                spares_left: 1,
+            }
+        );
+
+        // The move is in the table, and the other sectors never moved.
+        let mut store = mount(&sim);
+        assert_eq!(store.spare_of(2), Some(0));
+        assert_eq!(store.spare_of(1), None);
+        assert_eq!(read(&mut store, 2)[..], data[..]);
+        assert_eq!(read(&mut store, 1)[..], pattern(1)[..]);
+        assert_eq!(store.health().retired, 1);
+    }
+
+    #[test]
+    fn failed_erase_retires_the_sector() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        block_on(store.write(3, &pattern(3))).unwrap();
+        // A bit that no longer erases.
+        sim.stick_bit(physical_address(3, 0x800), 7, false);
+        block_on(store.erase_sector(3 * SECTOR_SIZE as u32)).unwrap();
+
+        assert_eq!(store.spare_of(3), Some(0));
+        assert_eq!(read(&mut store, 3)[..], [0xFF; SECTOR_SIZE]);
+        assert_eq!(store.health().erase_failures, 1);
+    }
+
+    #[test]
+    fn partial_programs_move_with_the_sector() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        block_on(async {
+            store.program_at(0x0010, b"header").await.unwrap();
+            sim.stick_bit(physical_address(0, 0x0900), 1, true);
+            store.program_at(0x0900, &[0x00; 4]).await.unwrap();
+
+            let mut buf = [0; 6];
+            store.read_at(0x0010, &mut buf).await.unwrap();
+            assert_eq!(&buf, b"header");
+            let mut buf = [0xFF; 4];
+            store.read_at(0x0900, &mut buf).await.unwrap();
+            assert_eq!(buf, [0x00; 4]);
+        });
+        assert_eq!(store.spare_of(0), Some(0));
+    }
+
+    #[test]
+    fn failing_spares_are_skipped() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        sim.stick_bit(physical_address(1, 0x10), 3, false);
+        // The first spare fails to erase too.
+        sim.stick_bit(physical_address(4, 0x20), 3, false);
+        block_on(store.write(1, &pattern(1))).unwrap();
+
+        assert_eq!(store.spare_of(1), Some(1));
+        assert_eq!(read(&mut store, 1)[..], pattern(1)[..]);
+        assert_eq!(
+            store.health(),
+            Health {
+                erase_failures: 2,
+                program_failures: 0,
+                retired: 2,
+                spares_left: 0,
+            }
+        );
+        let store = mount(&sim);
+        assert_eq!(store.spare_of(1), Some(1));
+        assert_eq!(store.health().spares_left, 0);
+    }
+
+    #[test]
+    fn far_addresses_are_reported_whole() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        assert!(matches!(
+            block_on(store.program_at(4 * SECTOR_SIZE as u32, &[0])),
+            Err(Error::OutOfRange { sector_id: 4 })
+        ));
+        // Sector 257 would pass for sector 1 if truncated to a u8.
+        let addr = 257 * SECTOR_SIZE as u32;
+        assert!(matches!(
+            block_on(store.program_at(addr, &[0])),
+            Err(Error::AddressOutOfRange { addr: a }) if a == addr
+        ));
+    }
+
+    #[test]
+    fn running_out_of_spares() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        for physical in [0, 4, 5] {
+            sim.stick_bit(physical_address(physical, 0), 0, false);
+        }
+        assert!(matches!(
+            block_on(store.write(0, &pattern(0))),
+            Err(Error::NoSpares)
+        ));
+        // Sectors that still work carry on.
+        block_on(store.write(1, &pattern(1))).unwrap();
+    }
+
+    #[test]
+    fn wear_leveling_on_top() {
+        use crate::ext_memory::WearLeveling;
+
+        let sim = FlashSim::new(W25Q80);
+        let remapped = block_on(Remapped::<_, 8, 2>::mount(driver(&sim), 0)).unwrap();
+        let mut store = block_on(WearLeveling::<_, 2, 4>::mount(remapped, 0)).unwrap();
+        // Data sector 1 of the wear leveling, after its four journal sectors.
+        sim.stick_bit(physical_address(5, 0x42), 2, false);
+        for round in 0..8 {
+            block_on(store.write(round % 2, &pattern(round))).unwrap();
+        }
+
+        let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+        block_on(store.read(0, &mut buf)).unwrap();
+        assert_eq!(buf[..], pattern(6)[..]);
+        block_on(store.read(1, &mut buf)).unwrap();
+        assert_eq!(buf[..], pattern(7)[..]);
+        assert_eq!(store.flash().spare_of(5), Some(0));
+    }
+
+    #[test]
+    fn power_loss_while_retiring() {
+        let base = FlashSim::new(W25Q80);
+        let mut store = mount(&base);
+        block_on(store.program_at(0x2000, b"kept")).unwrap();
+        base.stick_bit(physical_address(2, 0x400), 0, true);
+        let image = base.contents();
+
+        // Cut anywhere in the move, the sector is either still in place
+        // or completely moved.
+        let mut completed = false;
+        for budget in (0..).step_by(97) {
+            let sim = FlashSim::new(W25Q80);
+            sim.load(0, &image);
+            sim.stick_bit(physical_address(2, 0x400), 0, true);
+            let mut store = mount(&sim);
+            sim.arm_power_loss(budget);
+            let _ = block_on(store.program_at(0x2400, &[0x00]));
+            completed = sim.is_powered();
+            sim.power_cycle();
+
+            let mut store = mount(&sim);
+            let mut buf = [0; 4];
+            block_on(store.read_at(0x2000, &mut buf)).unwrap();
+            assert_eq!(&buf, b"kept", "lost after {budget} bytes");
+            if completed {
+                assert_eq!(store.spare_of(2), Some(0));
+                break;
+            }
+        }
+        assert!(completed);
+    }
+
+    #[test]
+    fn torn_dead_markers_stay_blank() {
+        let base = FlashSim::new(W25Q80);
+        block_on(mount(&base).write(0, &pattern(0))).unwrap();
+        let image = base.contents();
+        let worn = |sim: &FlashSim| {
+            // Sector 0 and the first spare no longer erase.
+            sim.stick_bit(physical_address(0, 0x10), 4, false);
+            sim.stick_bit(physical_address(4, 0x10), 4, false);
+        };
+
+        // Cut at every byte, through the dead marker of the first spare
+        // and the move to the second.
+        let mut completed = false;
+        for budget in 0.. {
+            let sim = FlashSim::new(W25Q80);
+            sim.load(0, &image);
+            worn(&sim);
+            let mut store = mount(&sim);
+            sim.arm_power_loss(budget);
+            let _ = block_on(store.erase_sector(0));
+            completed = sim.is_powered();
+            sim.power_cycle();
+
+            let store = mount(&sim);
+            assert_ne!(
+                store.spare_of(0),
+                Some(0),
+                "dead spare after {budget} bytes"
+            );
+            if completed {
+                assert_eq!(store.spare_of(0), Some(1));
+                break;
+            }
+        }
+        assert!(completed);
+    }
+
+    #[test]
+    fn moves_after_a_failed_slot_write_survive_remount() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        // The slot of the first spare no longer programs, and stays blank.
+        for bit in 0..16 {
+            sim.stick_bit((bit / 8) as u32, bit as u8 % 8, true);
+        }
+        sim.stick_bit(physical_address(2, 0x10), 4, false);
+        assert!(block_on(store.write(2, &pattern(2))).is_err());
+
+        // The next move goes to the second spare.
+        sim.stick_bit(physical_address(1, 0x10), 4, false);
+        block_on(store.write(1, &pattern(1))).unwrap();
+        assert_eq!(store.spare_of(1), Some(1));
+
+        let mut store = mount(&sim);
+        assert_eq!(store.spare_of(1), Some(1));
+        assert_eq!(read(&mut store, 1)[..], pattern(1)[..]);
+        assert_eq!(store.spare_of(2), None);
+        assert_eq!(store.health().spares_left, 0);
+    }
+}
@@ -0,0 +1,503 @@
+//! Wear leveling over the sectors of an [`ExtFlash`].
+//!
+//! Every write or erase of a logical sector goes to the least-erased free
+//! physical sector, and the logical to physical mapping is recorded in a
+//! [`Journal`]. Data is always programmed before the record that points to
+//! it, so a sector write interrupted by a power loss leaves the old contents
+//! mapped. Programming a mapped sector with [`ExtFlash::program_at`] happens in
+//! place.
+//!
+//! Layout from `base`: the two journal banks, then `PHYSICAL` data sectors.
+
+use crate::board::{ExtFlash, ExtMemory, FlashError, SectorBuffer};
+use crate::w25qxx::SECTOR_SIZE;
+
+use super::journal::{Journal, Record};
+
+/// A logical sector now lives in a physical sector.
+const MAPPING: u8 = 0x02;
+/// Erase count of a physical sector that holds no logical sector.
+const FREE: u8 = 0x03;
+
+const UNMAPPED: u16 = u16::MAX;
+
+#[derive(Clone, Copy, Default)]
+struct Physical {
+    erase_count: u32,
+    owner: Option<u8>,
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Memory(E),
+    /// `sector_id` is not one of the logical sectors of the store.
+    OutOfRange {
+        sector_id: u8,
+    },
+    /// `addr` lies past the logical sectors of the store, further than a
+    /// sector id can tell.
+    AddressOutOfRange {
+        addr: u32,
+    },
+    /// The journal and data sectors do not fit in the memory from `base`.
+    TooSmall,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(value: E) -> Self {
+        Self::Memory(value)
+    }
+}
+
+impl<E: FlashError> FlashError for Error<E> {
+    fn is_verify_failure(&self) -> bool {
+        matches!(self, Self::Memory(error) if error.is_verify_failure())
+    }
+}
+
+/// The mapping and erase counters, as rebuilt from the journal.
+struct Tables<const LOGICAL: usize, const PHYSICAL: usize> {
+    map: [u16; LOGICAL],
+    sectors: [Physical; PHYSICAL],
+}
+
+impl<const LOGICAL: usize, const PHYSICAL: usize> Tables<LOGICAL, PHYSICAL> {
+    fn apply(&mut self, record: Record) {
+        let physical = record.location as usize;
+        let logical = record.sector as usize;
+        if physical >= PHYSICAL {
+            return;
+        }
+        match record.kind {
+            MAPPING if logical < LOGICAL => {
+                let old = self.map[logical];
+                if old != UNMAPPED {
+                    self.sectors[old as usize].owner = None;
+                }
+                self.map[logical] = record.location;
+                let sector = &mut self.sectors[physical];
+                sector.owner = Some(record.sector);
+                sector.erase_count = sector.erase_count.max(record.value);
+            }
+            FREE => {
+                let sector = &mut self.sectors[physical];
+                sector.erase_count = sector.erase_count.max(record.value);
+            }
+            _ => {}
+        }
+    }
+
+    /// One record per physical sector, enough to rebuild the tables.
+    fn snapshot(&self) -> impl Iterator<Item = Record> + '_ {
+        self.sectors
+            .iter()
+            .enumerate()
+            .map(|(physical, sector)| Record {
+                kind: if sector.owner.is_some() {
+                    MAPPING
+                } else {
+                    FREE
+                },
+                sector: sector.owner.unwrap_or(0),
+                location: physical as u16,
+                sequence: 0,
+                value: sector.erase_count,
+            })
+    }
+}
+
+/// `LOGICAL` wear-leveled sectors spread over `PHYSICAL` physical ones.
+///
+/// The `PHYSICAL - LOGICAL` spare sectors are what the writes rotate through.
+pub struct WearLeveling<F, const LOGICAL: usize, const PHYSICAL: usize> {
+    flash: F,
+    base: u32,
+    journal: Journal,
+    tables: Tables<LOGICAL, PHYSICAL>,
+}
+
+impl<F: ExtFlash, const LOGICAL: usize, const PHYSICAL: usize> WearLeveling<F, LOGICAL, PHYSICAL> {
+    const JOURNAL_SECTORS: usize = Journal::sectors_for(PHYSICAL);
+
+    /// Sectors taken from the memory below, journal included.
+    pub const FOOTPRINT: usize = 2 * Self::JOURNAL_SECTORS + PHYSICAL;
+
+    /// Open the store at `base`, formatting it if no journal is found.
+    pub async fn mount(mut flash: F, base: u32) -> Result<Self, Error<F::Error>> {
+        assert!(
+            LOGICAL < PHYSICAL,
+            "wear leveling needs at least one spare sector"
+        );
+        assert!(LOGICAL <= 1 << 8 && PHYSICAL < UNMAPPED as usize);
+
+        let len = Self::FOOTPRINT * SECTOR_SIZE;
+        if !(base as usize).is_multiple_of(SECTOR_SIZE)
+            || base as usize + len > flash.capacity() as usize
+        {
+            return Err(Error::TooSmall);
+        }
+
+        let mut tables = Tables {
+            map: [UNMAPPED; LOGICAL],
+            sectors: [Physical::default(); PHYSICAL],
+        };
+        let journal = Journal::mount(&mut flash, base, Self::JOURNAL_SECTORS, |record| {
+            tables.apply(record)
+        })
+        .await?;
+        Ok(Self {
+            flash,
+            base,
+            journal,
+            tables,
+        })
+    }
+
+    fn data_address(&self, physical: usize) -> u32 {
+        self.base + ((2 * Self::JOURNAL_SECTORS + physical) * SECTOR_SIZE) as u32
+    }
+
+    /// The logical sector holding byte `addr`.
+    fn logical(&self, addr: u32) -> Result<usize, Error<F::Error>> {
+        let logical = addr as usize / SECTOR_SIZE;
+        if logical >= LOGICAL {
+            return Err(match u8::try_from(logical) {
+                Ok(sector_id) => Error::OutOfRange { sector_id },
+                Err(_) => Error::AddressOutOfRange { addr },
+            });
+        }
+        Ok(logical)
+    }
+
+    /// Erase the least-erased free physical sector and return it.
+    async fn allocate(&mut self) -> Result<usize, Error<F::Error>> {
+        // There is always a spare: LOGICAL < PHYSICAL.
+        let (target, _) = self
+            .tables
+            .sectors
+            .iter()
+            .enumerate()
+            .filter(|(_, sector)| sector.owner.is_none())
+            .min_by_key(|(_, sector)| sector.erase_count)
+            .unwrap();
+        self.flash.erase_sector(self.data_address(target)).await?;
+        self.tables.sectors[target].erase_count += 1;
+        Ok(target)
+    }
+
+    /// Point `logical` at `physical`, freeing the sector it used before.
+    async fn map(&mut self, logical: usize, physical: usize) -> Result<(), Error<F::Error>> {
+        let record = Record {
+            kind: MAPPING,
+            // Fits, as mount checks that LOGICAL <= 256.
+            sector: logical as u8,
+            location: physical as u16,
+            sequence: 0,
+            value: self.tables.sectors[physical].erase_count,
+        };
+        self.journal
+            .append(&mut self.flash, record, self.tables.snapshot())
+            .await?;
+        self.tables.apply(record);
+        Ok(())
+    }
+
+    /// Number of times physical sector `physical` has been erased by the store.
+    pub fn erase_count(&self, physical: usize) -> u32 {
+        self.tables.sectors[physical].erase_count
+    }
+
+    /// The physical sector holding `sector_id`, if it was ever written.
+    pub fn physical(&self, sector_id: u8) -> Option<usize> {
+        match *self.tables.map.get(sector_id as usize)? {
+            UNMAPPED => None,
+            physical => Some(physical as usize),
+        }
+    }
+
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+}
+
+impl<F: ExtFlash, const LOGICAL: usize, const PHYSICAL: usize> ExtMemory
+    for WearLeveling<F, LOGICAL, PHYSICAL>
+{
+    type Error = Error<F::Error>;
+
+    async fn write(&mut self, sector_id: u8, data: &SectorBuffer) -> Result<(), Self::Error> {
+        let logical = self.logical(sector_id as u32 * SECTOR_SIZE as u32)?;
+        let physical = self.allocate().await?;
+        self.flash
+            .program_at(self.data_address(physical), &data[..])
+            .await?;
+        self.map(logical, physical).await
+    }
+
+    async fn read(&mut self, sector_id: u8, data: &mut SectorBuffer) -> Result<(), Self::Error> {
+        let address = sector_id as u32 * SECTOR_SIZE as u32;
+        self.read_at(address, &mut data[..]).await
+    }
+}
+
+impl<F: ExtFlash, const LOGICAL: usize, const PHYSICAL: usize> ExtFlash
+    for WearLeveling<F, LOGICAL, PHYSICAL>
+{
+    fn capacity(&self) -> u32 {
+        (LOGICAL * SECTOR_SIZE) as u32
+    }
+
+    async fn read_at(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let mut addr = addr;
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let logical = self.logical(addr)?;
+            let offset = addr % SECTOR_SIZE as u32;
+            let len = buf.len().min(SECTOR_SIZE - offset as usize);
+            let (chunk, rest) = buf.split_at_mut(len);
+            match self.tables.map[logical] {
+                UNMAPPED => chunk.fill(0xFF),
+                physical => {
+                    let address = self.data_address(physical as usize) + offset;
+                    self.flash.read_at(address, chunk).await?;
+                }
+            }
+            addr += len as u32;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    async fn program_at(&mut self, addr: u32, data: &[u8]) -> Result<(), Self::Error> {
+        let mut addr = addr;
+        let mut data = data;
+        while !data.is_empty() {
+            let logical = self.logical(addr)?;
+            let offset = addr % SECTOR_SIZE as u32;
+            let (chunk, rest) = data.split_at(data.len().min(SECTOR_SIZE - offset as usize));
+            if self.tables.map[logical] == UNMAPPED {
+                // Never written: give it a blank sector to program.
+                let physical = self.allocate().await?;
+                self.map(logical, physical).await?;
+            }
+            let physical = self.tables.map[logical] as usize;
+            self.flash
+                .program_at(self.data_address(physical) + offset, chunk)
+                .await?;
+            addr += chunk.len() as u32;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// Moves the logical sector to a freshly erased physical sector, so that
+    /// erases rotate through the spares too.
+    async fn erase_sector(&mut self, addr: u32) -> Result<(), Self::Error> {
+        let logical = self.logical(addr)?;
+        let physical = self.allocate().await?;
+        self.map(logical, physical).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use w25qxx::sim::{FlashSim, SimDelay};
+
+    use super::*;
+    use crate::dma::DmaBuffer;
+    use crate::ext_memory::tests::{driver, pattern, W25Q80};
+    use crate::ext_memory::Driver;
+
+    type Store = WearLeveling<Driver<FlashSim, SimDelay>, 4, 6>;
+
+    fn mount(sim: &FlashSim) -> Store {
+        block_on(Store::mount(driver(sim), 0)).unwrap()
+    }
+
+    fn read(store: &mut Store, sector_id: u8) -> SectorBuffer {
+        let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+        block_on(store.read(sector_id, &mut buf)).unwrap();
+        buf
+    }
+
+    /// Appends left in the active bank after formatting.
+    const APPENDS: usize = 2 * SECTOR_SIZE / 16 - 1;
+
+    #[test]
+    fn sectors_survive_remount() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        assert_eq!(read(&mut store, 2)[..], [0xFF; SECTOR_SIZE]);
+        for sector_id in 0..4 {
+            block_on(store.write(sector_id, &pattern(sector_id))).unwrap();
+        }
+        block_on(store.write(1, &pattern(9))).unwrap();
+
+        let mut store = mount(&sim);
+        assert_eq!(read(&mut store, 0)[..], pattern(0)[..]);
+        assert_eq!(read(&mut store, 1)[..], pattern(9)[..]);
+        assert_eq!(read(&mut store, 3)[..], pattern(3)[..]);
+        assert!(matches!(
+            block_on(store.write(4, &pattern(0))),
+            Err(Error::OutOfRange { sector_id: 4 })
+        ));
+    }
+
+    #[test]
+    fn far_addresses_are_reported_whole() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        let mut buf = [0; 16];
+        assert!(matches!(
+            block_on(store.read_at(5 * SECTOR_SIZE as u32, &mut buf)),
+            Err(Error::OutOfRange { sector_id: 5 })
+        ));
+        // Sector 260 would pass for sector 4 if truncated to a u8.
+        let addr = 260 * SECTOR_SIZE as u32;
+        assert!(matches!(
+            block_on(store.read_at(addr, &mut buf)),
+            Err(Error::AddressOutOfRange { addr: a }) if a == addr
+        ));
+    }
+
+    #[test]
+    fn writes_rotate_through_spares() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        block_on(store.write(0, &pattern(0))).unwrap();
+        for round in 0..60 {
+            block_on(store.write(1, &pattern(round))).unwrap();
+        }
+        // Sector 0 pins one physical sector, the other five share the writes.
+        let counts: Vec<_> = (0..6).map(|physical| store.erase_count(physical)).collect();
+        let pinned = store.physical(0).unwrap();
+        assert_eq!(counts[pinned], 1);
+        for (physical, &count) in counts.iter().enumerate() {
+            if physical != pinned {
+                assert!((12..=13).contains(&count), "{counts:?}");
+            }
+        }
+
+        let store = mount(&sim);
+        assert_eq!(
+            (0..6)
+                .map(|physical| store.erase_count(physical))
+                .collect::<Vec<_>>(),
+            counts
+        );
+    }
+
+    #[test]
+    fn byte_access_and_erase() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        assert_eq!(store.capacity(), 4 * SECTOR_SIZE as u32);
+        block_on(async {
+            // Straddles sectors 1 and 2, neither written before.
+            store.program_at(0x1FF8, &[0x5A; 16]).await.unwrap();
+            let mut buf = [0; 18];
+            store.read_at(0x1FF7, &mut buf).await.unwrap();
+            assert_eq!(buf[0], 0xFF);
+            assert_eq!(buf[1..17], [0x5A; 16]);
+            assert_eq!(buf[17], 0xFF);
+
+            let before = store.physical(1).unwrap();
+            store.erase_sector(0x1800).await.unwrap();
+            assert_ne!(store.physical(1), Some(before));
+            store.read_at(0x1FF7, &mut buf).await.unwrap();
+            assert_eq!(buf[..9], [0xFF; 9]);
+            assert_eq!(buf[9..17], [0x5A; 8]);
+        });
+    }
+
+    #[test]
+    fn compaction_keeps_the_tables() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        // Enough to compact three times, and a multiple of four.
+        let rounds = (3 * APPENDS).next_multiple_of(4);
+        for round in 0..rounds {
+            let sector_id = (round % 4) as u8;
+            block_on(store.write(sector_id, &pattern(round as u8))).unwrap();
+        }
+        let counts: Vec<_> = (0..6).map(|physical| store.erase_count(physical)).collect();
+
+        let mut store = mount(&sim);
+        for sector_id in 0..4 {
+            let round = rounds - 4 + sector_id as usize;
+            assert_eq!(read(&mut store, sector_id)[..], pattern(round as u8)[..]);
+        }
+        for (physical, &count) in counts.iter().enumerate() {
+            // Counts are journaled when a sector is mapped, so a free
+            // sector may have lost its last erase.
+            assert!(count - store.erase_count(physical) <= 1, "{counts:?}");
+        }
+    }
+
+    /// Cut the power at every step of `update`, which must leave sector 1
+    /// holding `pattern(1)` or `pattern(2)`, with the others untouched.
+    fn cut_power_during(prepare: impl Fn(&mut Store), update: impl Fn(&mut Store)) {
+        let base = FlashSim::new(W25Q80);
+        let mut store = mount(&base);
+        for sector_id in 0..4 {
+            block_on(store.write(sector_id, &pattern(sector_id))).unwrap();
+        }
+        prepare(&mut store);
+        let image = base.contents();
+
+        let mut completed = false;
+        for budget in (0..).step_by(61) {
+            let sim = FlashSim::new(W25Q80);
+            sim.load(0, &image);
+            let mut store = mount(&sim);
+            sim.arm_power_loss(budget);
+            update(&mut store);
+            completed = sim.is_powered();
+            sim.power_cycle();
+
+            let mut store = mount(&sim);
+            let sector = read(&mut store, 1);
+            assert!(
+                sector[..] == pattern(1)[..] || sector[..] == pattern(2)[..],
+                "torn after {budget} bytes"
+            );
+            for sector_id in [0, 2, 3] {
+                assert_eq!(read(&mut store, sector_id)[..], pattern(sector_id)[..]);
+            }
+            // The store carries on from wherever it was cut.
+            block_on(store.write(1, &pattern(3))).unwrap();
+            assert_eq!(read(&mut mount(&sim), 1)[..], pattern(3)[..]);
+
+            if completed {
+                break;
+            }
+        }
+        assert!(completed);
+    }
+
+    #[test]
+    fn power_loss_during_write() {
+        cut_power_during(
+            |_| {},
+            |store| {
+                let _ = block_on(store.write(1, &pattern(2)));
+            },
+        );
+    }
+
+    #[test]
+    fn power_loss_during_compaction() {
+        cut_power_during(
+            |store| {
+                // Fill the active bank, so that the next append compacts.
+                for _ in 4..APPENDS {
+                    block_on(store.write(0, &pattern(0))).unwrap();
+                }
+            },
+            |store| {
+                let _ = block_on(store.write(1, &pattern(2)));
+            },
+        );
+    }
+}
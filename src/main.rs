@@ -1,5 +1,5 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(const_fn_floating_point_arithmetic)]
 #![feature(ptr_metadata)]
 #![feature(type_alias_impl_trait)]
@@ -12,9 +12,12 @@ mod adc;
 mod board;
 mod bounded;
 mod display;
+mod dma;
 mod ext_memory;
+mod page_manager;
 mod parameter_controllers;
 mod params;
+mod shared_bus;
 mod user_inputs;
 
 use embassy_stm32::init;
@@ -23,14 +26,44 @@ use embassy_time::Delay;
 use embassy_time::Duration;
 use embassy_time::Timer;
 use w25qxx;
+use w25qxx::{PAGE_SIZE, SECTOR_SIZE};
 
 use embassy_executor::Spawner;
 use embassy_stm32::gpio::{Level, Output, Speed};
-use embedded_hal_bus::spi::ExclusiveDevice;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 
-use crate::board::ExtMemory as _;
+use crate::board::{ExtMemory as _, SectorBuffer};
+use crate::dma::DmaBuffer;
+use crate::ext_memory::PageBuffer;
+use crate::ext_memory::partition::{Layout, PartitionSpec, PartitionTable, Region};
+use crate::shared_bus::{Priority, SharedBus, SharedSpiDevice};
+#[cfg(not(test))]
 use {defmt_rtt as _, panic_probe as _};
 
+/// Sectors kept in external memory, each updated atomically.
+const STORED_SECTORS: usize = 112;
+/// Wear-leveled sectors: two copies of each stored sector and 4 journal
+/// sectors.
+const LOGICAL_SECTORS: usize = 2 * STORED_SECTORS + 4;
+/// Physical sectors the logical sectors are wear-leveled over.
+const PHYSICAL_SECTORS: usize = 240;
+/// Sectors taken by wear leveling: the physical sectors and 4 journal sectors.
+const LEVELED_SECTORS: usize = PHYSICAL_SECTORS + 4;
+/// Spares for the sectors that wear out.
+const SPARE_SECTORS: usize = 8;
+
+/// Partitions written to the stored sectors on first boot.
+const LAYOUT: Layout = Layout::new(
+    &[
+        PartitionSpec { region: Region::Calibration, first_sector: 1, sectors: 2 },
+        PartitionSpec { region: Region::Presets, first_sector: 3, sectors: 32 },
+        PartitionSpec { region: Region::Sequences, first_sector: 35, sectors: 32 },
+        PartitionSpec { region: Region::FirmwareStaging, first_sector: 67, sectors: 45 },
+    ],
+    STORED_SECTORS,
+);
+
+#[cfg(not(test))]
 #[embassy_executor::main]
 async fn main(mut _spawner: Spawner) {
     let p = init(Default::default());
@@ -45,23 +78,47 @@ async fn main(mut _spawner: Spawner) {
         spi::Config::default(),
     );
 
-    let memory_device =
-        ExclusiveDevice::new(spi_bus, Output::new(p.PA8, Level::Low, Speed::High), Delay);
+    // Shared with the display and DAC chips.
+    let spi_bus = SharedBus::<NoopRawMutex, _>::new(spi_bus);
+    let memory_device = SharedSpiDevice::new(
+        &spi_bus,
+        Output::new(p.PA8, Level::High, Speed::High),
+        Priority::Low,
+    );
+
+    // DMA cannot reach the stack or the statics, which are in DTCM.
+    let bounce = dma::axi_singleton!(: PageBuffer = DmaBuffer::new([0; PAGE_SIZE])).unwrap();
+    let buffer = dma::axi_singleton!(: SectorBuffer = DmaBuffer::new([0; SECTOR_SIZE])).unwrap();
 
-    let mut memory = ext_memory::Driver::new(memory_device)
+    let flash = ext_memory::Driver::new(memory_device, Delay, bounce)
         .await
         .expect("driver creation failed");
+    let flash = ext_memory::remap::Remapped::<_, LEVELED_SECTORS, SPARE_SECTORS>::mount(flash, 0)
+        .await
+        .expect("bad-sector table mount failed");
+    let flash = ext_memory::WearLeveling::<_, LOGICAL_SECTORS, PHYSICAL_SECTORS>::mount(flash, 0)
+        .await
+        .expect("wear leveling mount failed");
+    let memory = ext_memory::atomic::AtomicSectors::<_, STORED_SECTORS>::mount(flash, 0)
+        .await
+        .expect("mount failed");
+    let mut partitions = PartitionTable::mount(memory, &LAYOUT, buffer)
+        .await
+        .expect("partition table mount failed");
+    let mut presets = partitions
+        .partition(Region::Presets)
+        .expect("no presets partition");
+    let preset_sectors = presets.spec().sectors;
 
-    let mut buffer = [0 as u8; 4096];
     let mut counter: u8 = 0;
 
     loop {
-        memory.write(counter, &buffer).await.expect("write failed");
-        memory
-            .read(counter, &mut buffer)
+        presets.write(counter, buffer).await.expect("write failed");
+        presets
+            .read(counter, buffer)
             .await
             .expect("read failed");
-        counter += 1;
+        counter = (counter + 1) % preset_sectors;
 
         Timer::after(Duration::from_millis(20)).await;
     }
@@ -0,0 +1,711 @@
+//! Log-structured key-value store over the sectors of an [`ExtMemory`].
+//!
+//! Records are only ever appended to the newest ("head") sector. Each sector
+//! starts with a header holding its sequence number, and each record carries
+//! a CRC. When no sector is left for a new head, the oldest sector is
+//! collected: its live records are copied to the head and it is blanked.
+//!
+//! Every `set` and `remove` rewrites the head sector, so the store only
+//! survives power loss over sector writes that are atomic, as they are with
+//! [`AtomicSectors`](crate::ext_memory::atomic::AtomicSectors): a record is
+//! durable once `set` or `remove` returns, and a collection only blanks a
+//! sector once its live records have been written elsewhere.
+
+use crate::board::{ExtMemory, SectorBuffer};
+use crate::dma::DmaBuffer;
+use crate::w25qxx::{crc32, SECTOR_SIZE};
+
+/// "KVS1" in little-endian byte order.
+const MAGIC: u32 = 0x3153_564B;
+/// Magic, sequence number and CRC.
+const HEADER_SIZE: usize = 12;
+/// Key length, flags and value length.
+const RECORD_HEADER_SIZE: usize = 4;
+const CRC_SIZE: usize = 4;
+
+/// Longest key name.
+pub const MAX_NAME_LEN: usize = 32;
+/// Largest value that fits in a sector.
+pub const MAX_VALUE_LEN: usize =
+    SECTOR_SIZE - HEADER_SIZE - RECORD_HEADER_SIZE - (MAX_NAME_LEN + 1) - CRC_SIZE;
+
+/// Record flag: the key has been removed.
+const TOMBSTONE: u8 = 1 << 0;
+
+const ID_TAG: u8 = 0;
+const NAME_TAG: u8 = 1;
+
+/// A key, either a small integer or a short string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key<'a> {
+    Id(u16),
+    Name(&'a str),
+}
+
+impl From<u16> for Key<'static> {
+    fn from(id: u16) -> Self {
+        Key::Id(id)
+    }
+}
+
+impl<'a> From<&'a str> for Key<'a> {
+    fn from(name: &'a str) -> Self {
+        Key::Name(name)
+    }
+}
+
+/// A key as stored: a tag byte followed by the id or the name.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct EncodedKey {
+    len: u8,
+    bytes: [u8; MAX_NAME_LEN + 1],
+}
+
+impl EncodedKey {
+    fn new(key: Key<'_>) -> Option<Self> {
+        let mut bytes = [0; MAX_NAME_LEN + 1];
+        let len = match key {
+            Key::Id(id) => {
+                bytes[0] = ID_TAG;
+                bytes[1..3].copy_from_slice(&id.to_le_bytes());
+                3
+            }
+            Key::Name(name) => {
+                if name.len() > MAX_NAME_LEN {
+                    return None;
+                }
+                bytes[0] = NAME_TAG;
+                bytes[1..1 + name.len()].copy_from_slice(name.as_bytes());
+                1 + name.len()
+            }
+        };
+        Some(Self {
+            len: len as u8,
+            bytes,
+        })
+    }
+
+    fn from_bytes(key: &[u8]) -> Self {
+        let mut bytes = [0; MAX_NAME_LEN + 1];
+        bytes[..key.len()].copy_from_slice(key);
+        Self {
+            len: key.len() as u8,
+            bytes,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+/// A record decoded in place from a sector buffer.
+struct Record<'a> {
+    key: &'a [u8],
+    flags: u8,
+    value: &'a [u8],
+    /// Length of the whole record, CRC included.
+    len: usize,
+}
+
+impl<'a> Record<'a> {
+    fn encoded_len(key_len: usize, value_len: usize) -> usize {
+        RECORD_HEADER_SIZE + key_len + value_len + CRC_SIZE
+    }
+
+    /// Decode the record at the start of `buf`. `None` at the end of the log
+    /// or on a corrupt record, after which nothing in the sector can be trusted.
+    fn decode(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < RECORD_HEADER_SIZE || buf[0] == 0xFF {
+            return None;
+        }
+        let key_len = buf[0] as usize;
+        let flags = buf[1];
+        let value_len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        let len = Self::encoded_len(key_len, value_len);
+        if key_len > MAX_NAME_LEN + 1 || len > buf.len() {
+            return None;
+        }
+        let body = &buf[..len - CRC_SIZE];
+        let crc = &buf[len - CRC_SIZE..len];
+        if crc32(body).to_le_bytes() != crc {
+            return None;
+        }
+        Some(Self {
+            key: &body[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + key_len],
+            flags,
+            value: &body[RECORD_HEADER_SIZE + key_len..],
+            len,
+        })
+    }
+
+    /// Encode a record into the start of `buf`, which must be large enough.
+    fn encode(buf: &mut [u8], key: &[u8], flags: u8, value: &[u8]) -> usize {
+        let len = Self::encoded_len(key.len(), value.len());
+        buf[0] = key.len() as u8;
+        buf[1] = flags;
+        buf[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        buf[RECORD_HEADER_SIZE..][..key.len()].copy_from_slice(key);
+        buf[RECORD_HEADER_SIZE + key.len()..][..value.len()].copy_from_slice(value);
+        let crc = crc32(&buf[..len - CRC_SIZE]);
+        buf[len - CRC_SIZE..len].copy_from_slice(&crc.to_le_bytes());
+        len
+    }
+}
+
+fn encode_header(buf: &mut [u8], sequence: u32) {
+    buf[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    buf[4..8].copy_from_slice(&sequence.to_le_bytes());
+    let crc = crc32(&buf[..8]);
+    buf[8..HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Sequence number of a sector, or `None` if it is not part of the store.
+fn decode_header(buf: &[u8]) -> Option<u32> {
+    let word = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+    (word(0) == MAGIC && word(8) == crc32(&buf[..8])).then(|| word(4))
+}
+
+/// Whether `a` is a later sequence number than `b`. Sequence numbers are
+/// compared modulo 2^32.
+fn newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// Where the newest record of a key lives.
+#[derive(Clone, Copy)]
+struct Entry {
+    key: EncodedKey,
+    sector: usize,
+    offset: usize,
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Memory(E),
+    /// The key name is longer than [`MAX_NAME_LEN`].
+    KeyTooLong,
+    /// The value is longer than [`MAX_VALUE_LEN`].
+    ValueTooLarge,
+    /// No room is left for the record, or the key index is full.
+    Full,
+    /// The value is `len` bytes long and does not fit in the buffer.
+    BufferTooSmall {
+        len: usize,
+    },
+    /// A record indexed at mount no longer reads back from `sector_id`.
+    Corrupt {
+        sector_id: u8,
+    },
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(value: E) -> Self {
+        Self::Memory(value)
+    }
+}
+
+/// A key-value store over `SECTORS` sectors from `first_sector`, holding up
+/// to `KEYS` keys.
+pub struct PageManager<M, const SECTORS: usize, const KEYS: usize> {
+    memory: M,
+    first_sector: u8,
+    /// Sequence number of each sector in use.
+    sequences: [Option<u32>; SECTORS],
+    head: usize,
+    head_len: usize,
+    head_buf: SectorBuffer,
+    scratch: SectorBuffer,
+    next_sequence: u32,
+    index: [Option<Entry>; KEYS],
+}
+
+impl<M: ExtMemory, const SECTORS: usize, const KEYS: usize> PageManager<M, SECTORS, KEYS> {
+    /// Open the store, rebuilding the key index from the log.
+    pub async fn mount(memory: M, first_sector: u8) -> Result<Self, Error<M::Error>> {
+        assert!(
+            SECTORS >= 3,
+            "the store needs a head, a spare and a sector to collect"
+        );
+        assert!(first_sector as usize + SECTORS <= 1 << 8);
+
+        let mut new = Self {
+            memory,
+            first_sector,
+            sequences: [None; SECTORS],
+            head: 0,
+            head_len: HEADER_SIZE,
+            head_buf: DmaBuffer::new([0xFF; SECTOR_SIZE]),
+            scratch: DmaBuffer::new([0xFF; SECTOR_SIZE]),
+            next_sequence: 0,
+            index: [None; KEYS],
+        };
+
+        for sector in 0..SECTORS {
+            new.memory
+                .read(new.sector_id(sector), &mut new.scratch)
+                .await?;
+            new.sequences[sector] = decode_header(&new.scratch[..]);
+        }
+
+        // Replay the sectors from oldest to newest.
+        let mut last = None;
+        while let Some(sector) = new.next_sector_after(last) {
+            new.memory
+                .read(new.sector_id(sector), &mut new.scratch)
+                .await?;
+            let mut offset = HEADER_SIZE;
+            while let Some(record) = Record::decode(&new.scratch[offset..]) {
+                let key = EncodedKey::from_bytes(record.key);
+                let tombstone = record.flags & TOMBSTONE != 0;
+                let len = record.len;
+                if tombstone {
+                    new.unindex(&key);
+                } else {
+                    // Keys beyond the index capacity cannot have been written.
+                    let _ = new.reindex(key, sector, offset);
+                }
+                offset += len;
+            }
+            new.head = sector;
+            new.head_len = offset;
+            new.head_buf = new.scratch;
+            last = new.sequences[sector];
+        }
+
+        match last {
+            Some(sequence) => new.next_sequence = sequence.wrapping_add(1),
+            None => new.open_head(0),
+        }
+        Ok(new)
+    }
+
+    fn sector_id(&self, sector: usize) -> u8 {
+        self.first_sector + sector as u8
+    }
+
+    /// The used sector with the lowest sequence number above `after`.
+    fn next_sector_after(&self, after: Option<u32>) -> Option<usize> {
+        (0..SECTORS)
+            .filter_map(|sector| Some((sector, self.sequences[sector]?)))
+            .filter(|&(_, sequence)| after.is_none_or(|after| newer(sequence, after)))
+            .min_by(|&(_, a), &(_, b)| (a.wrapping_sub(b) as i32).cmp(&0))
+            .map(|(sector, _)| sector)
+    }
+
+    fn free_sectors(&self) -> usize {
+        self.sequences.iter().filter(|s| s.is_none()).count()
+    }
+
+    fn lookup(&self, key: &EncodedKey) -> Option<usize> {
+        self.index
+            .iter()
+            .position(|entry| entry.is_some_and(|entry| entry.key == *key))
+    }
+
+    fn reindex(&mut self, key: EncodedKey, sector: usize, offset: usize) -> Result<(), ()> {
+        let slot = match self.lookup(&key) {
+            Some(slot) => slot,
+            None => self.index.iter().position(Option::is_none).ok_or(())?,
+        };
+        self.index[slot] = Some(Entry {
+            key,
+            sector,
+            offset,
+        });
+        Ok(())
+    }
+
+    fn unindex(&mut self, key: &EncodedKey) {
+        if let Some(slot) = self.lookup(key) {
+            self.index[slot] = None;
+        }
+    }
+
+    /// Start a new head in the free `sector`. Nothing is written until the
+    /// first record lands in it.
+    fn open_head(&mut self, sector: usize) {
+        self.head = sector;
+        self.head_len = HEADER_SIZE;
+        self.head_buf.fill(0xFF);
+        encode_header(&mut self.head_buf[..], self.next_sequence);
+        self.sequences[sector] = Some(self.next_sequence);
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+    }
+
+    async fn flush_head(&mut self) -> Result<(), Error<M::Error>> {
+        self.memory
+            .write(self.sector_id(self.head), &self.head_buf)
+            .await?;
+        Ok(())
+    }
+
+    /// Move the head to a free sector, keeping one free for collection.
+    async fn advance(&mut self) -> Result<(), Error<M::Error>> {
+        // Collecting a sector with no garbage only rotates it to the head, so
+        // give up once every sector has been collected once.
+        let mut attempts = SECTORS;
+        while self.free_sectors() < 2 {
+            if attempts == 0 {
+                return Err(Error::Full);
+            }
+            attempts -= 1;
+            self.collect().await?;
+        }
+        self.open_free_head()
+    }
+
+    fn open_free_head(&mut self) -> Result<(), Error<M::Error>> {
+        let sector = self
+            .sequences
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Error::Full)?;
+        self.open_head(sector);
+        Ok(())
+    }
+
+    /// Copy the live records of the oldest sector to the head and blank it.
+    async fn collect(&mut self) -> Result<(), Error<M::Error>> {
+        let victim = match self.next_sector_after(None) {
+            Some(sector) if sector != self.head => sector,
+            _ => return Err(Error::Full),
+        };
+        self.memory
+            .read(self.sector_id(victim), &mut self.scratch)
+            .await?;
+
+        let mut offset = HEADER_SIZE;
+        while let Some(record) = Record::decode(&self.scratch[offset..]) {
+            let key = EncodedKey::from_bytes(record.key);
+            let len = record.len;
+            let live = self
+                .lookup(&key)
+                .and_then(|slot| self.index[slot])
+                .is_some_and(|entry| entry.sector == victim && entry.offset == offset);
+            if live {
+                if self.head_len + len > SECTOR_SIZE {
+                    // The spare kept by `advance` takes the overflow.
+                    self.flush_head().await?;
+                    self.open_free_head()?;
+                }
+                self.head_buf[self.head_len..][..len]
+                    .copy_from_slice(&self.scratch[offset..offset + len]);
+                let _ = self.reindex(key, self.head, self.head_len);
+                self.head_len += len;
+            }
+            offset += len;
+        }
+        self.flush_head().await?;
+
+        self.scratch.fill(0xFF);
+        self.memory
+            .write(self.sector_id(victim), &self.scratch)
+            .await?;
+        self.sequences[victim] = None;
+        Ok(())
+    }
+
+    /// Append a record to the head, moving on to a new head if it is full.
+    async fn append(
+        &mut self,
+        key: &EncodedKey,
+        flags: u8,
+        value: &[u8],
+    ) -> Result<usize, Error<M::Error>> {
+        let len = Record::encoded_len(key.len as usize, value.len());
+        if self.head_len + len > SECTOR_SIZE {
+            self.advance().await?;
+        }
+        let offset = self.head_len;
+        Record::encode(&mut self.head_buf[offset..], key.as_bytes(), flags, value);
+        self.head_len += len;
+        self.flush_head().await?;
+        Ok(offset)
+    }
+
+    /// Copy the value of `key` into `buf`. Returns its length, or `None` if
+    /// the key is not in the store.
+    pub async fn get<'k>(
+        &mut self,
+        key: impl Into<Key<'k>>,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, Error<M::Error>> {
+        let key = EncodedKey::new(key.into()).ok_or(Error::KeyTooLong)?;
+        let Some(entry) = self.lookup(&key).and_then(|slot| self.index[slot]) else {
+            return Ok(None);
+        };
+
+        let sector = if entry.sector == self.head {
+            &self.head_buf
+        } else {
+            self.memory
+                .read(self.sector_id(entry.sector), &mut self.scratch)
+                .await?;
+            &self.scratch
+        };
+        // Indexed records were valid at mount, but the flash may have rotted
+        // since.
+        let record = Record::decode(&sector[entry.offset..]).ok_or(Error::Corrupt {
+            sector_id: self.first_sector + entry.sector as u8,
+        })?;
+        let len = record.value.len();
+        if buf.len() < len {
+            return Err(Error::BufferTooSmall { len });
+        }
+        buf[..len].copy_from_slice(record.value);
+        Ok(Some(len))
+    }
+
+    /// Whether `key` is in the store.
+    pub fn contains<'k>(&self, key: impl Into<Key<'k>>) -> bool {
+        EncodedKey::new(key.into()).is_some_and(|key| self.lookup(&key).is_some())
+    }
+
+    /// Store `value` under `key`, replacing any previous value.
+    pub async fn set<'k>(
+        &mut self,
+        key: impl Into<Key<'k>>,
+        value: &[u8],
+    ) -> Result<(), Error<M::Error>> {
+        let key = EncodedKey::new(key.into()).ok_or(Error::KeyTooLong)?;
+        if value.len() > MAX_VALUE_LEN {
+            return Err(Error::ValueTooLarge);
+        }
+        if self.lookup(&key).is_none() && !self.index.iter().any(Option::is_none) {
+            return Err(Error::Full);
+        }
+
+        let offset = self.append(&key, 0, value).await?;
+        let _ = self.reindex(key, self.head, offset);
+        Ok(())
+    }
+
+    /// Remove `key` from the store. Returns whether it was there.
+    pub async fn remove<'k>(&mut self, key: impl Into<Key<'k>>) -> Result<bool, Error<M::Error>> {
+        let key = EncodedKey::new(key.into()).ok_or(Error::KeyTooLong)?;
+        if self.lookup(&key).is_none() {
+            return Ok(false);
+        }
+
+        self.append(&key, TOMBSTONE, &[]).await?;
+        self.unindex(&key);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use w25qxx::sim::{FlashSim, SimDelay};
+
+    use super::*;
+    use crate::ext_memory::atomic::AtomicSectors;
+    use crate::ext_memory::tests::{driver, W25Q80};
+    use crate::ext_memory::Driver;
+
+    type Memory = AtomicSectors<Driver<FlashSim, SimDelay>, 6>;
+    type Store = PageManager<Memory, 4, 8>;
+
+    fn mount(sim: &FlashSim) -> Store {
+        let driver = driver(sim);
+        block_on(async {
+            let memory = Memory::mount(driver, 0).await.unwrap();
+            Store::mount(memory, 2).await.unwrap()
+        })
+    }
+
+    fn get<'k>(store: &mut Store, key: impl Into<Key<'k>>) -> Option<Vec<u8>> {
+        let mut buf = [0; MAX_VALUE_LEN];
+        let len = block_on(store.get(key, &mut buf)).unwrap()?;
+        Some(buf[..len].to_vec())
+    }
+
+    #[test]
+    fn records_survive_remount() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        block_on(async {
+            store.set(7, b"seven").await.unwrap();
+            store.set("name", b"value").await.unwrap();
+            store.set("name", b"newer").await.unwrap();
+            store.set("gone", b"soon").await.unwrap();
+            assert!(store.remove("gone").await.unwrap());
+            assert!(!store.remove("gone").await.unwrap());
+        });
+
+        let mut store = mount(&sim);
+        assert_eq!(get(&mut store, 7).as_deref(), Some(&b"seven"[..]));
+        assert_eq!(get(&mut store, "name").as_deref(), Some(&b"newer"[..]));
+        assert_eq!(get(&mut store, "gone"), None);
+        assert!(!store.contains(8));
+    }
+
+    #[test]
+    fn bad_arguments_are_refused() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        let long = "k".repeat(MAX_NAME_LEN + 1);
+        block_on(async {
+            assert!(matches!(
+                store.set(long.as_str(), b"").await,
+                Err(Error::KeyTooLong)
+            ));
+            assert!(matches!(
+                store.set(1, &[0; MAX_VALUE_LEN + 1]).await,
+                Err(Error::ValueTooLarge)
+            ));
+            store.set(1, &[0; 16]).await.unwrap();
+            assert!(matches!(
+                store.get(1, &mut [0; 15]).await,
+                Err(Error::BufferTooSmall { len: 16 })
+            ));
+            for id in 2..9 {
+                store.set(id, b"").await.unwrap();
+            }
+            assert!(matches!(store.set(9, b"").await, Err(Error::Full)));
+        });
+    }
+
+    #[test]
+    fn collection_reclaims_overwritten_records() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        block_on(async {
+            store.set("kept", b"forever").await.unwrap();
+            // Many times the size of the store.
+            for round in 0..40u8 {
+                store.set(1, &[round; 1000]).await.unwrap();
+                store.set(2, &[!round; 1000]).await.unwrap();
+            }
+        });
+
+        let mut store = mount(&sim);
+        assert_eq!(get(&mut store, "kept").as_deref(), Some(&b"forever"[..]));
+        assert_eq!(get(&mut store, 1), Some(vec![39; 1000]));
+        assert_eq!(get(&mut store, 2), Some(vec![!39; 1000]));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let sim = FlashSim::new(W25Q80);
+        let mut store = mount(&sim);
+        // Restart the log just short of the wrap.
+        store.sequences[0] = None;
+        store.next_sequence = u32::MAX - 1;
+        store.open_head(0);
+        block_on(async {
+            store.set("kept", b"forever").await.unwrap();
+            for round in 0..12u8 {
+                store.set(1, &[round; 1000]).await.unwrap();
+            }
+        });
+        // Past zero, or it would still be at least u32::MAX - 1.
+        assert!(store.next_sequence < u32::MAX - 1);
+
+        let mut store = mount(&sim);
+        assert_eq!(get(&mut store, "kept").as_deref(), Some(&b"forever"[..]));
+        assert_eq!(get(&mut store, 1), Some(vec![11; 1000]));
+    }
+
+    /// Cut the power at every step of `set(1, new)` after `prepare`, and
+    /// check that key 1 holds its old or new value and the others are
+    /// untouched.
+    fn cut_power_during(prepare: impl Fn(&mut Store), new: &[u8]) {
+        const KEYS: [Key<'static>; 3] = [Key::Id(1), Key::Id(2), Key::Name("kept")];
+        let base = FlashSim::new(W25Q80);
+        let mut store = mount(&base);
+        prepare(&mut store);
+        let old = KEYS.map(|key| get(&mut store, key));
+        let image = base.contents();
+
+        let mut completed = false;
+        for budget in (0..).step_by(61) {
+            let sim = FlashSim::new(W25Q80);
+            sim.load(0, &image);
+            let mut store = mount(&sim);
+            sim.arm_power_loss(budget);
+            let _ = block_on(store.set(1, new));
+            completed = sim.is_powered();
+            sim.power_cycle();
+
+            let mut store = mount(&sim);
+            let value = get(&mut store, 1);
+            assert!(
+                value == old[0] || value.as_deref() == Some(new),
+                "torn after {budget} bytes"
+            );
+            for (key, old) in KEYS.iter().zip(&old).skip(1) {
+                assert_eq!(
+                    get(&mut store, *key),
+                    *old,
+                    "lost {key:?} after {budget} bytes"
+                );
+            }
+            // The store carries on from wherever it was cut.
+            block_on(store.set(1, b"after")).unwrap();
+            assert_eq!(get(&mut mount(&sim), 1).as_deref(), Some(&b"after"[..]));
+
+            if completed {
+                break;
+            }
+        }
+        assert!(completed);
+    }
+
+    #[test]
+    fn power_loss_during_set() {
+        cut_power_during(
+            |store| {
+                block_on(async {
+                    store.set(1, b"old").await.unwrap();
+                    store.set(2, b"other").await.unwrap();
+                    store.set("kept", b"forever").await.unwrap();
+                })
+            },
+            b"new",
+        );
+    }
+
+    #[test]
+    fn power_loss_during_collection() {
+        cut_power_during(
+            |store| {
+                block_on(async {
+                    store.set("kept", b"forever").await.unwrap();
+                    store.set(2, &[2; 1000]).await.unwrap();
+                    // Fill up until the next value makes room by collecting
+                    // the oldest sector, which holds live records.
+                    let len = Record::encoded_len(3, 1000);
+                    let mut round = 0;
+                    while store.free_sectors() >= 2 || store.head_len + len <= SECTOR_SIZE {
+                        store.set(1, &[round; 1000]).await.unwrap();
+                        round += 1;
+                    }
+                })
+            },
+            &[0xAA; 1000],
+        );
+    }
+
+    #[test]
+    fn rotten_records_are_reported() {
+        type Store = PageManager<Driver<FlashSim, SimDelay>, 4, 8>;
+        let sim = FlashSim::new(W25Q80);
+        let mut store = block_on(Store::mount(driver(&sim), 2)).unwrap();
+        block_on(async {
+            store.set("old", b"value").await.unwrap();
+            // Fill the first sector, so that "old" is no longer in the head.
+            store.set(1, &[0; 3000]).await.unwrap();
+            store.set(1, &[1; 3000]).await.unwrap();
+        });
+        assert_ne!(store.head, 0);
+
+        sim.flip_bit(2 * SECTOR_SIZE as u32 + HEADER_SIZE as u32 + 6, 0);
+        let mut buf = [0; 16];
+        assert!(matches!(
+            block_on(store.get("old", &mut buf)),
+            Err(Error::Corrupt { sector_id: 2 })
+        ));
+    }
+}
@@ -0,0 +1,602 @@
+//! SPI bus shared between several devices, granted by priority.
+//!
+//! A device holds the bus for one transaction at a time. When the bus is
+//! released, it goes to the waiting device with the highest [`Priority`], so
+//! an audio-critical DAC update only waits for the transaction in progress,
+//! not for every flash transaction queued before it. Drivers that split long
+//! transfers into short transactions, like [`ext_memory::Driver`], give way
+//! between the pieces.
+//!
+//! Every operation of a transaction, [`Operation::DelayNs`] included, runs
+//! holding the bus with chip select asserted, as [`SpiDevice`] requires. A
+//! driver that polls should wait between separate transactions, like
+//! [`w25qxx`] does, so the bus is free in between.
+//!
+//! [`ext_memory::Driver`]: crate::ext_memory::Driver
+
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::ops::{Deref, DerefMut};
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::mutex::{Mutex, MutexGuard};
+use embassy_sync::waitqueue::MultiWakerRegistration;
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::spi::{self, ErrorKind, ErrorType, Operation, SpiBus, SpiDevice};
+
+const PRIORITIES: usize = 3;
+/// Devices that can wait at the same priority. Past this they keep waking
+/// each other until the bus is granted, so it is sized for every device on
+/// the board.
+const WAITERS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Bulk transfers that can wait, like flash reads and display updates.
+    Low = 0,
+    Normal = 1,
+    /// Transfers with a deadline, like DAC updates from the audio path.
+    High = 2,
+}
+
+struct Arbiter {
+    busy: bool,
+    /// Number of devices waiting at each priority.
+    waiting: [usize; PRIORITIES],
+    wakers: [MultiWakerRegistration<WAITERS>; PRIORITIES],
+}
+
+impl Arbiter {
+    const fn new() -> Self {
+        Self {
+            busy: false,
+            waiting: [0; PRIORITIES],
+            wakers: [const { MultiWakerRegistration::new() }; PRIORITIES],
+        }
+    }
+
+    fn wake_all(&mut self) {
+        self.wakers
+            .iter_mut()
+            .for_each(MultiWakerRegistration::wake);
+    }
+
+    fn waiting_above(&self, priority: Priority) -> bool {
+        self.waiting[priority as usize + 1..].iter().any(|&n| n > 0)
+    }
+}
+
+/// Counts a device as waiting until it is granted the bus or gives up.
+struct Waiting<'a, M: RawMutex> {
+    arbiter: &'a BlockingMutex<M, RefCell<Arbiter>>,
+    priority: Priority,
+}
+
+impl<M: RawMutex> Drop for Waiting<'_, M> {
+    fn drop(&mut self) {
+        self.arbiter.lock(|arbiter| {
+            let mut arbiter = arbiter.borrow_mut();
+            arbiter.waiting[self.priority as usize] -= 1;
+            // A lower priority device may have been held back by this one.
+            arbiter.wake_all();
+        });
+    }
+}
+
+/// The bus, owned by one device until dropped.
+pub struct Grant<'a, M: RawMutex, BUS> {
+    bus: MutexGuard<'a, M, BUS>,
+    arbiter: &'a BlockingMutex<M, RefCell<Arbiter>>,
+}
+
+impl<M: RawMutex, BUS> Deref for Grant<'_, M, BUS> {
+    type Target = BUS;
+
+    fn deref(&self) -> &BUS {
+        &self.bus
+    }
+}
+
+impl<M: RawMutex, BUS> DerefMut for Grant<'_, M, BUS> {
+    fn deref_mut(&mut self) -> &mut BUS {
+        &mut self.bus
+    }
+}
+
+impl<M: RawMutex, BUS> Drop for Grant<'_, M, BUS> {
+    fn drop(&mut self) {
+        self.arbiter.lock(|arbiter| {
+            let mut arbiter = arbiter.borrow_mut();
+            arbiter.busy = false;
+            arbiter.wake_all();
+        });
+    }
+}
+
+pub struct SharedBus<M: RawMutex, BUS> {
+    bus: Mutex<M, BUS>,
+    arbiter: BlockingMutex<M, RefCell<Arbiter>>,
+}
+
+impl<M: RawMutex, BUS> SharedBus<M, BUS> {
+    pub fn new(bus: BUS) -> Self {
+        Self {
+            bus: Mutex::new(bus),
+            arbiter: BlockingMutex::new(RefCell::new(Arbiter::new())),
+        }
+    }
+
+    /// Wait until the bus is free and no device of a higher priority is
+    /// waiting for it.
+    pub async fn acquire(&self, priority: Priority) -> Grant<'_, M, BUS> {
+        self.arbiter.lock(|arbiter| {
+            arbiter.borrow_mut().waiting[priority as usize] += 1;
+        });
+        let waiting = Waiting {
+            arbiter: &self.arbiter,
+            priority,
+        };
+        poll_fn(|cx| {
+            self.arbiter.lock(|arbiter| {
+                let mut arbiter = arbiter.borrow_mut();
+                if arbiter.busy || arbiter.waiting_above(priority) {
+                    arbiter.wakers[priority as usize].register(cx.waker());
+                    Poll::Pending
+                } else {
+                    arbiter.busy = true;
+                    Poll::Ready(())
+                }
+            })
+        })
+        .await;
+        drop(waiting);
+
+        // Only the holder of the arbiter's grant locks the bus, so this is
+        // never contended.
+        let bus = self.bus.lock().await;
+        Grant {
+            bus,
+            arbiter: &self.arbiter,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError<BUS, CS> {
+    Spi(BUS),
+    Cs(CS),
+}
+
+impl<BUS: spi::Error, CS: core::fmt::Debug> spi::Error for DeviceError<BUS, CS> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Spi(error) => error.kind(),
+            Self::Cs(_) => ErrorKind::ChipSelectFault,
+        }
+    }
+}
+
+/// One device on a [`SharedBus`], selected by its own chip select pin.
+pub struct SharedSpiDevice<'a, M: RawMutex, BUS, CS> {
+    bus: &'a SharedBus<M, BUS>,
+    cs: CS,
+    priority: Priority,
+}
+
+impl<'a, M: RawMutex, BUS, CS> SharedSpiDevice<'a, M, BUS, CS> {
+    pub fn new(bus: &'a SharedBus<M, BUS>, cs: CS, priority: Priority) -> Self {
+        Self { bus, cs, priority }
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+}
+
+impl<M: RawMutex, BUS: ErrorType, CS: OutputPin> ErrorType for SharedSpiDevice<'_, M, BUS, CS> {
+    type Error = DeviceError<BUS::Error, CS::Error>;
+}
+
+impl<M: RawMutex, BUS: SpiBus, CS: OutputPin> SpiDevice for SharedSpiDevice<'_, M, BUS, CS> {
+    async fn transaction(
+        &mut self,
+        operations: &mut [Operation<'_, u8>],
+    ) -> Result<(), Self::Error> {
+        let mut grant = self.bus.acquire(self.priority).await;
+        let bus = &mut *grant;
+
+        self.cs.set_low().map_err(DeviceError::Cs)?;
+        let result = async {
+            for operation in operations {
+                match operation {
+                    Operation::Read(buf) => bus.read(buf).await?,
+                    Operation::Write(buf) => bus.write(buf).await?,
+                    Operation::Transfer(read, write) => bus.transfer(read, write).await?,
+                    Operation::TransferInPlace(buf) => bus.transfer_in_place(buf).await?,
+                    Operation::DelayNs(ns) => {
+                        bus.flush().await?;
+                        Timer::after(Duration::from_nanos(*ns as u64)).await;
+                    }
+                }
+            }
+            bus.flush().await
+        }
+        .await;
+        // Deselect even if the transfer failed, so the bus stays usable.
+        let deselect = self.cs.set_high();
+
+        result.map_err(DeviceError::Spi)?;
+        deselect.map_err(DeviceError::Cs)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::task::Context;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_time::Instant;
+    use futures::executor::{block_on, LocalPool};
+    use futures::task::LocalSpawnExt;
+    use w25qxx::sim::{FlashSim, SimError};
+    use w25qxx::{PAGE_SIZE, SECTOR_SIZE};
+
+    use crate::board::ExtMemory;
+    use crate::dma::DmaBuffer;
+    use crate::ext_memory::tests::{pattern, W25Q80};
+    use crate::ext_memory::Driver;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Event {
+        Select(u8),
+        Write(u8),
+        Deselect(u8),
+    }
+
+    type Log = Rc<RefCell<Vec<Event>>>;
+
+    /// Gives the other tasks a turn, like a DMA transfer would.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    struct MockBus(Log);
+
+    impl ErrorType for MockBus {
+        type Error = Infallible;
+    }
+
+    impl SpiBus for MockBus {
+        async fn read(&mut self, _words: &mut [u8]) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        async fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+            for &word in words {
+                self.0.borrow_mut().push(Event::Write(word));
+            }
+            YieldNow(false).await;
+            Ok(())
+        }
+
+        async fn transfer(&mut self, _read: &mut [u8], write: &[u8]) -> Result<(), Infallible> {
+            self.write(write).await
+        }
+
+        async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+            self.write(words).await
+        }
+
+        async fn flush(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    struct MockCs(u8, Log);
+
+    impl embedded_hal::digital::ErrorType for MockCs {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for MockCs {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.1.borrow_mut().push(Event::Select(self.0));
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.1.borrow_mut().push(Event::Deselect(self.0));
+            Ok(())
+        }
+    }
+
+    type Bus = SharedBus<NoopRawMutex, MockBus>;
+
+    fn bus(log: &Log) -> &'static Bus {
+        Box::leak(Box::new(SharedBus::new(MockBus(log.clone()))))
+    }
+
+    fn device(
+        bus: &'static Bus,
+        log: &Log,
+        id: u8,
+        priority: Priority,
+    ) -> SharedSpiDevice<'static, NoopRawMutex, MockBus, MockCs> {
+        SharedSpiDevice::new(bus, MockCs(id, log.clone()), priority)
+    }
+
+    fn writes(log: &Log) -> Vec<u8> {
+        log.borrow()
+            .iter()
+            .filter_map(|event| match event {
+                Event::Write(word) => Some(*word),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn released_bus_goes_to_highest_priority() {
+        let log = Log::default();
+        let bus = bus(&log);
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+
+        let grant = pool.run_until(bus.acquire(Priority::High));
+        for (id, priority) in [
+            (1, Priority::Low),
+            (2, Priority::Normal),
+            (3, Priority::High),
+            (4, Priority::Low),
+        ] {
+            let mut device = device(bus, &log, id, priority);
+            spawner
+                .spawn_local(async move { device.write(&[id]).await.unwrap() })
+                .unwrap();
+        }
+        pool.run_until_stalled();
+        assert!(log.borrow().is_empty());
+
+        drop(grant);
+        pool.run_until_stalled();
+        // Waiters of the same priority are served in turn.
+        assert_eq!(writes(&log), [3, 2, 1, 4]);
+    }
+
+    #[test]
+    fn every_waiter_of_a_priority_is_woken() {
+        let log = Log::default();
+        let bus = bus(&log);
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+
+        let grant = pool.run_until(bus.acquire(Priority::High));
+        for id in 1..=WAITERS as u8 {
+            let mut device = device(bus, &log, id, Priority::Normal);
+            spawner
+                .spawn_local(async move { device.write(&[id]).await.unwrap() })
+                .unwrap();
+        }
+        pool.run_until_stalled();
+
+        drop(grant);
+        pool.run_until_stalled();
+        let mut served = writes(&log);
+        served.sort_unstable();
+        assert_eq!(served, (1..=WAITERS as u8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn high_priority_preempts_between_transactions() {
+        let log = Log::default();
+        let bus = bus(&log);
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+
+        // A long read split into short transactions, as the flash driver does.
+        let mut flash = device(bus, &log, 1, Priority::Low);
+        spawner
+            .spawn_local(async move {
+                for page in 0..4 {
+                    flash.write(&[0x10 + page]).await.unwrap();
+                }
+            })
+            .unwrap();
+        let mut dac = device(bus, &log, 2, Priority::High);
+        spawner
+            .spawn_local(async move { dac.write(&[0x20]).await.unwrap() })
+            .unwrap();
+        pool.run_until_stalled();
+
+        // The DAC only waits for the transaction in progress.
+        assert_eq!(writes(&log), [0x10, 0x20, 0x11, 0x12, 0x13]);
+        assert_eq!(
+            log.borrow()[..6],
+            [
+                Event::Select(1),
+                Event::Write(0x10),
+                Event::Deselect(1),
+                Event::Select(2),
+                Event::Write(0x20),
+                Event::Deselect(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn delays_keep_chip_select_low() {
+        let log = Log::default();
+        let bus = bus(&log);
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+        let start = Instant::now();
+
+        let mut flash = device(bus, &log, 1, Priority::Low);
+        spawner
+            .spawn_local(async move {
+                let mut ops = [
+                    Operation::DelayNs(2_000_000),
+                    Operation::Write(&[0x05]),
+                    Operation::DelayNs(2_000_000),
+                ];
+                flash.transaction(&mut ops).await.unwrap();
+            })
+            .unwrap();
+        let display_done = Rc::new(RefCell::new(None));
+        let mut display = device(bus, &log, 2, Priority::Low);
+        let done = display_done.clone();
+        spawner
+            .spawn_local(async move {
+                display.write(&[0x2C]).await.unwrap();
+                *done.borrow_mut() = Some(Instant::now());
+            })
+            .unwrap();
+        pool.run();
+
+        assert_eq!(
+            *log.borrow(),
+            [
+                Event::Select(1),
+                Event::Write(0x05),
+                Event::Deselect(1),
+                Event::Select(2),
+                Event::Write(0x2C),
+                Event::Deselect(2),
+            ]
+        );
+        // The display waited out both delays of the flash transaction.
+        let done = display_done.borrow().unwrap();
+        assert!(done - start >= Duration::from_millis(4));
+    }
+
+    /// Bytes clocked out to a [`FlashSim`] since its chip select went low.
+    struct Window {
+        sim: FlashSim,
+        clocked: Vec<u8>,
+        executed: bool,
+    }
+
+    /// Drives a [`FlashSim`] as a bus, handing it each chip select window
+    /// as one transaction.
+    struct SimBus(Rc<RefCell<Window>>);
+
+    impl ErrorType for SimBus {
+        type Error = SimError;
+    }
+
+    impl SimBus {
+        /// Clock `operation` out after the bytes written so far in the
+        /// window, as the flash only answers once it has its command.
+        fn answer(&mut self, operation: Operation<'_, u8>) -> Result<(), SimError> {
+            let mut window = self.0.borrow_mut();
+            // The flash driver reads at most once, after the command.
+            assert!(!window.executed, "second read in one transaction");
+            window.executed = true;
+            let command = window.clocked.clone();
+            let mut ops = [Operation::Write(&command), operation];
+            embedded_hal::spi::SpiDevice::transaction(&mut window.sim, &mut ops)
+        }
+    }
+
+    impl SpiBus for SimBus {
+        async fn read(&mut self, words: &mut [u8]) -> Result<(), SimError> {
+            self.answer(Operation::Read(words))
+        }
+
+        async fn write(&mut self, words: &[u8]) -> Result<(), SimError> {
+            let mut window = self.0.borrow_mut();
+            assert!(!window.executed, "write after read in one transaction");
+            window.clocked.extend_from_slice(words);
+            Ok(())
+        }
+
+        async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), SimError> {
+            self.answer(Operation::Transfer(read, write))
+        }
+
+        async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), SimError> {
+            self.answer(Operation::TransferInPlace(words))
+        }
+
+        async fn flush(&mut self) -> Result<(), SimError> {
+            Ok(())
+        }
+    }
+
+    struct SimCs(Rc<RefCell<Window>>);
+
+    impl embedded_hal::digital::ErrorType for SimCs {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for SimCs {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            let mut window = self.0.borrow_mut();
+            window.clocked.clear();
+            window.executed = false;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            let mut window = self.0.borrow_mut();
+            if !window.executed && !window.clocked.is_empty() {
+                let command = core::mem::take(&mut window.clocked);
+                let mut ops = [Operation::Write(&command)];
+                embedded_hal::spi::SpiDevice::transaction(&mut window.sim, &mut ops).unwrap();
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flash_driver_runs_over_the_shared_bus() {
+        let sim = FlashSim::new(W25Q80);
+        let window = Rc::new(RefCell::new(Window {
+            sim: sim.clone(),
+            clocked: Vec::new(),
+            executed: false,
+        }));
+        let bus: &'static SharedBus<NoopRawMutex, SimBus> =
+            Box::leak(Box::new(SharedBus::new(SimBus(window.clone()))));
+        let device = SharedSpiDevice::new(bus, SimCs(window), Priority::Low);
+        let bounce = Box::leak(Box::new(DmaBuffer::new([0; PAGE_SIZE])));
+
+        block_on(async {
+            let mut flash = Driver::new(device, sim.delay(), bounce).await.unwrap();
+            flash.write(3, &pattern(7)).await.unwrap();
+            let mut buf = DmaBuffer::new([0; SECTOR_SIZE]);
+            flash.read(3, &mut buf).await.unwrap();
+            assert_eq!(buf[..], pattern(7)[..]);
+        });
+        let at = 3 * SECTOR_SIZE as u32;
+        assert_eq!(sim.read(at, SECTOR_SIZE), pattern(7)[..]);
+    }
+}
@@ -0,0 +1,266 @@
+//! Blocking driver over `embedded_hal::spi::SpiDevice`, for code that runs
+//! without an executor.
+//!
+//! The blocking [`W25Qxx`] runs the async driver on the [`BlockingSpi`] and
+//! [`BlockingDelay`] adapters. Every operation of the adapters completes
+//! before it returns, so each driver future is ready the first time it is
+//! polled, and both variants share all command encoding, status handling and
+//! polling logic.
+//!
+//! Without the `time` feature nothing in the driver needs an `embassy_time`
+//! driver either, so it runs on a bare `DelayNs` and `SpiDevice`.
+
+use core::future::Future;
+use core::pin::pin;
+use core::ptr;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+#[cfg(feature = "time")]
+use embassy_time::{Duration, Instant};
+use embedded_hal::delay::DelayNs;
+use embedded_hal::spi::{ErrorType, Operation, SpiDevice};
+use embedded_hal_async::delay as async_delay;
+use embedded_hal_async::spi as async_spi;
+
+use crate::sfdp::Sfdp;
+use crate::{
+    AddressMode, BlockProtection, Error, FlashInfo, Identification, Persistence, ReadMode,
+    SecurityRegister, Status, Status2, Status3,
+};
+
+/// Runs a blocking `SpiDevice` behind the async `SpiDevice` interface.
+pub struct BlockingSpi<T>(pub T);
+
+impl<T: ErrorType> ErrorType for BlockingSpi<T> {
+    type Error = T::Error;
+}
+
+impl<T: SpiDevice> async_spi::SpiDevice for BlockingSpi<T> {
+    async fn transaction(
+        &mut self,
+        operations: &mut [Operation<'_, u8>],
+    ) -> Result<(), Self::Error> {
+        self.0.transaction(operations)
+    }
+}
+
+/// Runs a blocking `DelayNs` behind the async `DelayNs` interface.
+pub struct BlockingDelay<D>(pub D);
+
+impl<D: DelayNs> async_delay::DelayNs for BlockingDelay<D> {
+    async fn delay_ns(&mut self, ns: u32) {
+        self.0.delay_ns(ns)
+    }
+}
+
+fn noop_raw_waker() -> RawWaker {
+    const VTABLE: RawWakerVTable =
+        RawWakerVTable::new(|_| noop_raw_waker(), |_| {}, |_| {}, |_| {});
+    RawWaker::new(ptr::null(), &VTABLE)
+}
+
+/// Poll `future` to completion on the current thread.
+fn block_on<F: Future>(future: F) -> F::Output {
+    // SAFETY: the vtable functions ignore the data pointer.
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+/// Blocking counterpart of [`crate::W25Qxx`].
+pub struct W25Qxx<T, D> {
+    inner: crate::W25Qxx<BlockingSpi<T>, BlockingDelay<D>>,
+}
+
+/// Define blocking methods that run the async method of the same name.
+macro_rules! blocking {
+    ($(
+        $(#[$attr:meta])*
+        fn $name:ident(&mut self $(, $arg:ident: $ty:ty)*) -> $ret:ty;
+    )*) => {
+        $(
+            $(#[$attr])*
+            pub fn $name(&mut self $(, $arg: $ty)*) -> $ret {
+                block_on(self.inner.$name($($arg),*))
+            }
+        )*
+    };
+}
+
+impl<T: SpiDevice, D: DelayNs> W25Qxx<T, D> {
+    /// Probe the device on `spi` and bind the driver to its geometry.
+    ///
+    /// See [`crate::W25Qxx::new`].
+    pub fn new(spi: T, delay: D) -> Result<Self, Error<T::Error>> {
+        let inner = block_on(crate::W25Qxx::new(BlockingSpi(spi), BlockingDelay(delay)))?;
+        Ok(Self { inner })
+    }
+
+    /// The async driver this one runs.
+    pub fn as_async(&mut self) -> &mut crate::W25Qxx<BlockingSpi<T>, BlockingDelay<D>> {
+        &mut self.inner
+    }
+
+    pub fn info(&self) -> &FlashInfo {
+        self.inner.info()
+    }
+
+    pub fn address_mode(&self) -> AddressMode {
+        self.inner.address_mode()
+    }
+
+    pub fn read_mode(&self) -> ReadMode {
+        self.inner.read_mode()
+    }
+
+    pub fn set_read_mode(&mut self, read_mode: ReadMode) {
+        self.inner.set_read_mode(read_mode)
+    }
+
+    pub fn verify(&self) -> bool {
+        self.inner.verify()
+    }
+
+    pub fn set_verify(&mut self, verify: bool) {
+        self.inner.set_verify(verify)
+    }
+
+    pub fn in_flight(&self) -> Option<crate::Operation> {
+        self.inner.in_flight()
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.inner.is_suspended()
+    }
+
+    pub fn is_powered_down(&self) -> bool {
+        self.inner.is_powered_down()
+    }
+
+    #[cfg(feature = "time")]
+    pub fn set_auto_sleep(&mut self, idle: Option<Duration>) {
+        self.inner.set_auto_sleep(idle)
+    }
+
+    #[cfg(feature = "time")]
+    pub fn idle_deadline(&self) -> Option<Instant> {
+        self.inner.idle_deadline()
+    }
+
+    #[cfg(feature = "time")]
+    pub fn sleep_if_idle(&mut self) -> Result<bool, Error<T::Error>> {
+        block_on(self.inner.sleep_if_idle())
+    }
+
+    /// Start erasing the sector containing `addr` without waiting for it.
+    ///
+    /// Reads issued meanwhile suspend the erase and `wait_done` finishes it.
+    pub fn start_erase_sector(&mut self, addr: u32) -> Result<(), Error<T::Error>> {
+        block_on(self.inner.start_erase_sector(addr))?;
+        Ok(())
+    }
+
+    /// Start erasing the 32 KiB half block containing `addr` without waiting for it.
+    pub fn start_erase_half_block(&mut self, addr: u32) -> Result<(), Error<T::Error>> {
+        block_on(self.inner.start_erase_half_block(addr))?;
+        Ok(())
+    }
+
+    /// Start erasing the block containing `addr` without waiting for it.
+    pub fn start_erase_block(&mut self, addr: u32) -> Result<(), Error<T::Error>> {
+        block_on(self.inner.start_erase_block(addr))?;
+        Ok(())
+    }
+
+    /// Start erasing the whole array without waiting for it.
+    pub fn start_erase_all(&mut self) -> Result<(), Error<T::Error>> {
+        block_on(self.inner.start_erase_all())?;
+        Ok(())
+    }
+
+    blocking! {
+        fn enter_4byte_mode(&mut self) -> Result<(), T::Error>;
+        fn exit_4byte_mode(&mut self) -> Result<(), T::Error>;
+        fn read_status(&mut self) -> Result<Status, T::Error>;
+        fn read_status2(&mut self) -> Result<Status2, T::Error>;
+        fn read_status3(&mut self) -> Result<Status3, T::Error>;
+        fn write_status(
+            &mut self,
+            status: Status,
+            persistence: Persistence
+        ) -> Result<(), Error<T::Error>>;
+        fn write_status2(
+            &mut self,
+            status: Status2,
+            persistence: Persistence
+        ) -> Result<(), Error<T::Error>>;
+        fn write_status3(
+            &mut self,
+            status: Status3,
+            persistence: Persistence
+        ) -> Result<(), Error<T::Error>>;
+        fn read_block_protection(&mut self) -> Result<BlockProtection, T::Error>;
+        fn set_block_protection(
+            &mut self,
+            protection: BlockProtection,
+            persistence: Persistence
+        ) -> Result<(), Error<T::Error>>;
+        fn set_quad_enable(
+            &mut self,
+            enable: bool,
+            persistence: Persistence
+        ) -> Result<(), Error<T::Error>>;
+        fn read_jedec_id(&mut self) -> Result<Identification, T::Error>;
+        fn get_device_info(&mut self) -> Result<FlashInfo, Error<T::Error>>;
+        fn read_sfdp_bytes(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), T::Error>;
+        fn read_sfdp(&mut self) -> Result<Sfdp, Error<T::Error>>;
+        fn write_enable(&mut self) -> Result<(), T::Error>;
+        fn wait_done(&mut self) -> Result<(), Error<T::Error>>;
+        fn suspend(&mut self) -> Result<bool, T::Error>;
+        fn resume(&mut self) -> Result<(), T::Error>;
+        fn power_down(&mut self) -> Result<(), Error<T::Error>>;
+        fn release_power_down(&mut self) -> Result<(), T::Error>;
+        fn exit_continuous_read(&mut self) -> Result<(), T::Error>;
+        fn reset(&mut self) -> Result<(), T::Error>;
+        fn recover(&mut self) -> Result<(), Error<T::Error>>;
+        fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<T::Error>>;
+        fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error<T::Error>>;
+        fn verify_erased(&mut self, addr: u32, len: usize) -> Result<(), Error<T::Error>>;
+        fn crc32_range(&mut self, addr: u32, len: usize) -> Result<u32, Error<T::Error>>;
+        fn erase_sector(&mut self, addr: u32) -> Result<(), Error<T::Error>>;
+        fn erase_half_block(&mut self, addr: u32) -> Result<(), Error<T::Error>>;
+        fn erase_block(&mut self, addr: u32) -> Result<(), Error<T::Error>>;
+        fn erase_range(&mut self, addr: u32, len: usize) -> Result<(), Error<T::Error>>;
+        fn erase_all(&mut self) -> Result<(), Error<T::Error>>;
+        fn read_unique_id(&mut self) -> Result<u64, Error<T::Error>>;
+        fn is_security_register_locked(
+            &mut self,
+            register: SecurityRegister
+        ) -> Result<bool, Error<T::Error>>;
+        fn read_security_register(
+            &mut self,
+            register: SecurityRegister,
+            offset: u8,
+            buf: &mut [u8]
+        ) -> Result<(), Error<T::Error>>;
+        fn program_security_register(
+            &mut self,
+            register: SecurityRegister,
+            offset: u8,
+            data: &[u8]
+        ) -> Result<(), Error<T::Error>>;
+        fn erase_security_register(
+            &mut self,
+            register: SecurityRegister
+        ) -> Result<(), Error<T::Error>>;
+        fn lock_security_register(
+            &mut self,
+            register: SecurityRegister
+        ) -> Result<(), Error<T::Error>>;
+    }
+}
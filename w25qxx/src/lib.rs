@@ -1,10 +1,31 @@
 #![no_std]
 #![feature(effects)]
 
-use bitflags::bitflags;
+#[cfg(feature = "std")]
+extern crate std;
+
 use core::fmt;
+#[cfg(feature = "time")]
+use embassy_time::{Duration, Instant};
+use embedded_hal_async::delay::DelayNs;
 use embedded_hal_async::spi;
 
+pub mod blocking;
+mod crc;
+mod operation;
+mod security;
+pub mod sfdp;
+#[cfg(feature = "std")]
+pub mod sim;
+mod status;
+
+pub use crc::{crc32, Crc32};
+pub use operation::{Backoff, Operation, Pending, Timing};
+pub use security::{SecurityRegister, SECURITY_REGISTER_SIZE};
+pub use status::{BlockProtection, Persistence, Status, Status2, Status3};
+
+use sfdp::{BasicFlashParameters, ParameterHeader, Sfdp, SfdpError, SfdpHeader};
+
 pub struct HexSlice<T>(pub T)
 where
     T: AsRef<[u8]>;
@@ -22,6 +43,7 @@ impl<T: AsRef<[u8]>> fmt::Debug for HexSlice<T> {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Identification {
     bytes: [u8; 3],
     continuations: u8,
@@ -36,13 +58,10 @@ impl Identification {
         // 0xC2 is the company identifier for Cypress (Ramtron)
 
         // Find the end of the continuation bytes (0x7F)
-        let mut start_idx = 0;
-        for i in 0..(buf.len() - 2) {
-            if buf[i] != 0x7F {
-                start_idx = i;
-                break;
-            }
-        }
+        let start_idx = buf[..buf.len() - 2]
+            .iter()
+            .position(|&b| b != 0x7F)
+            .unwrap_or(0);
 
         Self {
             bytes: [buf[start_idx], buf[start_idx + 1], buf[start_idx + 2]],
@@ -67,6 +86,18 @@ impl Identification {
     pub fn continuation_count(&self) -> u8 {
         self.continuations
     }
+
+    /// The 24-bit JEDEC ID: manufacturer code, memory type and capacity code.
+    pub fn jedec_id(&self) -> u32 {
+        ((self.bytes[0] as u32) << 16) | ((self.bytes[1] as u32) << 8) | (self.bytes[2] as u32)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Identification {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "Identification({=[u8]:02x})", self.bytes)
+    }
 }
 
 impl fmt::Debug for Identification {
@@ -78,6 +109,7 @@ impl fmt::Debug for Identification {
 }
 
 #[repr(u8)]
+#[derive(Clone, Copy)]
 #[allow(unused)] // TODO support more features
 enum Opcode {
     /// Read the 8-bit legacy device ID.
@@ -94,32 +126,182 @@ enum Opcode {
     ReadStatus = 0x05,
     /// Write the 8-bit status register. Not all bits are writeable.
     WriteStatus = 0x01,
+    ReadStatus2 = 0x35,
+    WriteStatus2 = 0x31,
+    ReadStatus3 = 0x15,
+    WriteStatus3 = 0x11,
+    /// Let the next status register write skip the non-volatile bits.
+    VolatileWriteEnable = 0x50,
     Read = 0x03,
     PageProg = 0x02, // directly writes to EEPROMs too
     SectorErase = 0x20,
     BlockErase = 0xD8,
+    /// Erase a 32 KiB half block.
+    HalfBlockErase = 0x52,
     ChipErase = 0xC7,
     PowerDown = 0xB9,
+    /// Switch addressed commands to 4-byte addresses.
+    Enter4ByteMode = 0xB7,
+    /// Switch addressed commands back to 3-byte addresses.
+    Exit4ByteMode = 0xE9,
+    /// `Read` with a 4-byte address, regardless of the address mode.
+    Read4 = 0x13,
+    /// `PageProg` with a 4-byte address, regardless of the address mode.
+    PageProg4 = 0x12,
+    /// `SectorErase` with a 4-byte address, regardless of the address mode.
+    SectorErase4 = 0x21,
+    /// `BlockErase` with a 4-byte address, regardless of the address mode.
+    BlockErase4 = 0xDC,
+    /// `HalfBlockErase` with a 4-byte address, regardless of the address mode.
+    HalfBlockErase4 = 0x5C,
+    /// Pause the erase or program operation in progress.
+    Suspend = 0x75,
+    /// Continue a suspended erase or program operation.
+    Resume = 0x7A,
+    /// Arm the next `Reset`.
+    EnableReset = 0x66,
+    /// Reset the device, if directly preceded by `EnableReset`.
+    Reset = 0x99,
+    /// Leave continuous read mode.
+    ModeBitReset = 0xFF,
+    /// Read the 64-bit factory-programmed unique ID.
+    ReadUniqueId = 0x4B,
+    EraseSecurityRegister = 0x44,
+    ProgramSecurityRegister = 0x42,
+    /// Read a security register. Takes an address and a dummy byte.
+    ReadSecurityRegister = 0x48,
+    /// Read the SFDP space. Always takes a 3-byte address and a dummy byte.
+    ReadSfdp = 0x5A,
+    /// `Read` followed by a dummy byte, allowing the full clock rate.
+    FastRead = 0x0B,
+    FastRead4 = 0x0C,
+    /// Fast read with the data clocked out over two lines.
+    DualOutputRead = 0x3B,
+    DualOutputRead4 = 0x3C,
+    /// Fast read with the data clocked out over four lines. Needs the QE bit set.
+    QuadOutputRead = 0x6B,
+    QuadOutputRead4 = 0x6C,
+}
+
+impl Opcode {
+    /// The dedicated 4-byte address variant of this opcode, if there is one.
+    fn four_byte(self) -> Option<Opcode> {
+        match self {
+            Opcode::Read => Some(Opcode::Read4),
+            Opcode::FastRead => Some(Opcode::FastRead4),
+            Opcode::DualOutputRead => Some(Opcode::DualOutputRead4),
+            Opcode::QuadOutputRead => Some(Opcode::QuadOutputRead4),
+            Opcode::PageProg => Some(Opcode::PageProg4),
+            Opcode::SectorErase => Some(Opcode::SectorErase4),
+            Opcode::BlockErase => Some(Opcode::BlockErase4),
+            Opcode::HalfBlockErase => Some(Opcode::HalfBlockErase4),
+            _ => None,
+        }
+    }
 }
 
-bitflags! {
-    /// Status register bits.
-    pub struct Status: u8 {
-        /// Erase or write in progress.
-        const BUSY = 1 << 0;
-        /// Status of the **W**rite **E**nable **L**atch.
-        const WEL = 1 << 1;
-        /// The 3 protection region bits.
-        const PROT = 0b00011100;
-        /// **S**tatus **R**egister **W**rite **D**isable bit.
-        const SRWD = 1 << 7;
+/// Number of address bytes sent with addressed commands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressMode {
+    /// 3-byte addresses, reaching the first 16 MiB.
+    ThreeByte,
+    /// 4-byte addresses, needed by W25Q256 and larger.
+    FourByte,
+}
+
+impl AddressMode {
+    /// Size of the address space reachable in this mode.
+    pub const fn reach(self) -> u64 {
+        match self {
+            AddressMode::ThreeByte => 1 << 24,
+            AddressMode::FourByte => 1 << 32,
+        }
+    }
+}
+
+/// Command used by `W25Qxx::read`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadMode {
+    /// `03h`, limited to 50 MHz on most parts.
+    Normal,
+    /// `0Bh` followed by 8 dummy clocks, usable at the full clock rate.
+    Fast,
+}
+
+/// Number of data lines used for the data phase of a multi-lane read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lanes {
+    Dual,
+    Quad,
+}
+
+/// An SPI device whose bus can clock the data phase of a read over several lines.
+#[allow(async_fn_in_trait)]
+pub trait MultiLaneSpiDevice: spi::SpiDevice {
+    /// Write `cmd` on a single line, wait `dummy_cycles` clocks, then read `buf`
+    /// over `lanes` lines, all within one chip-select assertion.
+    async fn read_multi_lane(
+        &mut self,
+        cmd: &[u8],
+        dummy_cycles: u8,
+        lanes: Lanes,
+        buf: &mut [u8],
+    ) -> Result<(), Self::Error>;
+}
+
+/// Time for the device to enter the suspended state after `75h` (tSUS).
+const SUSPEND_LATENCY_NS: u32 = 20_000;
+
+/// Minimum time between a resume and the next suspend (tRS), so that the
+/// operation keeps making progress.
+const RESUME_TO_SUSPEND_NS: u32 = 20_000;
+
+/// Time for the device to come back after a software reset (tRST).
+const RESET_LATENCY_NS: u32 = 30_000;
+
+/// Time for the device to enter deep power-down after `B9h` (tDP).
+const POWER_DOWN_LATENCY_NS: u32 = 3_000;
+
+/// Time for the device to leave deep power-down after `ABh` (tRES1).
+const POWER_UP_LATENCY_NS: u32 = 3_000;
+
+/// Dummy clocks of the fast, dual output and quad output reads.
+const FAST_READ_DUMMY_CYCLES: u8 = 8;
+
+/// An opcode followed by its address.
+struct AddressedCommand {
+    buf: [u8; 5],
+    len: usize,
+}
+
+impl AddressedCommand {
+    fn new(opcode: Opcode, addr: u32, mode: AddressMode) -> Self {
+        let addr = addr.to_be_bytes();
+        match mode {
+            AddressMode::ThreeByte => Self {
+                buf: [opcode as u8, addr[1], addr[2], addr[3], 0],
+                len: 4,
+            },
+            AddressMode::FourByte => Self {
+                buf: [opcode as u8, addr[0], addr[1], addr[2], addr[3]],
+                len: 5,
+            },
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
     }
 }
 
 pub const PAGE_SIZE: usize = 0x100;
 pub const SECTOR_SIZE: usize = 0x1000;
+pub const HALF_BLOCK_SIZE: usize = SECTOR_SIZE * 8;
 pub const BLOCK_SIZE: usize = SECTOR_SIZE * 16;
 
+/// JEDEC manufacturer code of Winbond.
+const WINBOND_MFR_CODE: u8 = 0xEF;
+
 pub struct FlashInfo {
     pub id: u32,
     pub page_count: u32,
@@ -130,44 +312,198 @@ pub struct FlashInfo {
 
 impl fmt::Debug for FlashInfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_tuple("FlashInfo")
-            .field(&self.id)
-            .field(&format_args!("_KB:_"))
-            .field(&self.capacity_kb)
+        f.debug_struct("FlashInfo")
+            .field("id", &format_args!("{:06x}", self.id))
+            .field("page_count", &self.page_count)
+            .field("sector_count", &self.sector_count)
+            .field("block_count", &self.block_count)
+            .field("capacity_kb", &self.capacity_kb)
             .finish()
     }
 }
 
+#[cfg(feature = "defmt")]
+impl defmt::Format for FlashInfo {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(
+            f,
+            "FlashInfo {{ id: {=u32:06x}, page_count: {}, sector_count: {}, block_count: {}, capacity_kb: {} }}",
+            self.id,
+            self.page_count,
+            self.sector_count,
+            self.block_count,
+            self.capacity_kb,
+        )
+    }
+}
+
 impl FlashInfo {
+    /// Decode the geometry of a W25Qxx part from its JEDEC ID.
+    ///
+    /// Returns `None` if the ID does not belong to one of W25Q10 through W25Q512.
+    pub fn from_jedec_id(id: &Identification) -> Option<FlashInfo> {
+        if id.mfr_code() != WINBOND_MFR_CODE {
+            return None;
+        }
+
+        // 0x40: SPI mode parts, 0x60 / 0x70: QPI and DTR capable parts.
+        if !matches!(id.device_id()[0], 0x40 | 0x60 | 0x70) {
+            return None;
+        }
+
+        let block_count = match id.device_id()[1] {
+            0x20 => 1024, // W25Q512
+            0x19 => 512,  // W25Q256
+            0x18 => 256,  // W25Q128
+            0x17 => 128,  // W25Q64
+            0x16 => 64,   // W25Q32
+            0x15 => 32,   // W25Q16
+            0x14 => 16,   // W25Q80
+            0x13 => 8,    // W25Q40
+            0x12 => 4,    // W25Q20
+            0x11 => 2,    // W25Q10
+            _ => return None,
+        };
+
+        Some(Self::from_block_count(id.jedec_id(), block_count))
+    }
+
+    /// Derive the geometry of a non-Winbond part from its SFDP tables.
+    ///
+    /// Returns `None` unless the part has the page, sector and block layout
+    /// and the erase opcodes of the W25Q family.
+    pub fn from_sfdp(id: &Identification, basic: &BasicFlashParameters) -> Option<FlashInfo> {
+        if basic.page_size != PAGE_SIZE as u32 {
+            return None;
+        }
+        let sector_erase = basic.erase_type(SECTOR_SIZE as u32)?;
+        let block_erase = basic.erase_type(BLOCK_SIZE as u32)?;
+        if sector_erase.opcode != Opcode::SectorErase as u8
+            || block_erase.opcode != Opcode::BlockErase as u8
+        {
+            return None;
+        }
+
+        let capacity = basic.capacity();
+        if capacity == 0
+            || !capacity.is_multiple_of(BLOCK_SIZE as u64)
+            || capacity > u32::MAX as u64
+        {
+            return None;
+        }
+
+        Some(Self::from_block_count(
+            id.jedec_id(),
+            (capacity / BLOCK_SIZE as u64) as u32,
+        ))
+    }
+
+    const fn from_block_count(id: u32, block_count: u32) -> FlashInfo {
+        FlashInfo {
+            id,
+            sector_count: block_count * (BLOCK_SIZE / SECTOR_SIZE) as u32,
+            page_count: block_count * (BLOCK_SIZE / PAGE_SIZE) as u32,
+            block_count,
+            capacity_kb: (BLOCK_SIZE as u32 * block_count) / 1024,
+        }
+    }
+
+    /// Total capacity in bytes.
+    pub const fn capacity(&self) -> u32 {
+        self.block_count * BLOCK_SIZE as u32
+    }
+
+    /// Whether `len` bytes starting at `addr` lie within the device.
+    pub const fn contains(&self, addr: u32, len: usize) -> bool {
+        addr as u64 + len as u64 <= self.capacity() as u64
+    }
+
     pub const fn page_to_sector(page_address: &u32) -> u32 {
-        return (page_address * PAGE_SIZE as u32) / SECTOR_SIZE as u32;
+        (page_address * PAGE_SIZE as u32) / SECTOR_SIZE as u32
     }
 
     pub const fn page_to_block(page_address: &u32) -> u32 {
-        return (page_address * PAGE_SIZE as u32) / BLOCK_SIZE as u32;
+        (page_address * PAGE_SIZE as u32) / BLOCK_SIZE as u32
     }
 
     pub const fn sector_to_block(sector_address: &u32) -> u32 {
-        return (sector_address * SECTOR_SIZE as u32) / BLOCK_SIZE as u32;
+        (sector_address * SECTOR_SIZE as u32) / BLOCK_SIZE as u32
     }
 
     pub const fn sector_to_page(sector_address: &u32) -> u32 {
-        return (sector_address * SECTOR_SIZE as u32) / PAGE_SIZE as u32;
+        (sector_address * SECTOR_SIZE as u32) / PAGE_SIZE as u32
     }
 
     pub const fn block_to_page(block_adress: &u32) -> u32 {
-        return (block_adress * BLOCK_SIZE as u32) / PAGE_SIZE as u32;
+        (block_adress * BLOCK_SIZE as u32) / PAGE_SIZE as u32
     }
 }
 
-pub struct W25Q80<T> {
+/// Driver for a W25Qxx device on `spi`, waiting out busy and latency times
+/// on `delay`.
+///
+/// Waits never happen inside an SPI transaction, so a device sharing the
+/// bus is free to use it while the flash is busy.
+pub struct W25Qxx<T, D> {
     spi: T,
+    delay: D,
+    info: FlashInfo,
+    address_mode: AddressMode,
+    read_mode: ReadMode,
+    verify: bool,
+    in_flight: Option<Operation>,
+    suspended: bool,
+    resumed: bool,
+    powered_down: bool,
+    #[cfg(feature = "time")]
+    auto_sleep: Option<Duration>,
+    #[cfg(feature = "time")]
+    last_active: Instant,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Error<E> {
     Spi(E),
-    UnexpectedStatus,
+    /// The device was busy or had its write enable latch set when it should
+    /// have been idle.
+    UnexpectedStatus {
+        status: Status,
+    },
+    /// The JEDEC ID read from the device does not match any supported part,
+    /// or no longer matches the part the driver was bound to.
+    UnknownDevice {
+        jedec_id: u32,
+    },
+    /// The access of `len` bytes at `addr` does not fit within the device.
+    OutOfRange {
+        addr: u32,
+        len: usize,
+    },
+    /// The SFDP space is missing or malformed.
+    Sfdp(SfdpError),
+    /// `operation` was still running after `timeout_ns`.
+    Timeout {
+        operation: Operation,
+        timeout_ns: u64,
+    },
+    /// The `len` bytes at `addr` are locked against program and erase.
+    WriteProtected {
+        addr: u32,
+        len: usize,
+    },
+    /// Read-back after programming or erasing differed from the expected
+    /// data, first at `addr` where `found` was read instead of `expected`.
+    VerifyFailed {
+        addr: u32,
+        expected: u8,
+        found: u8,
+    },
+    /// The `len` bytes at `addr` do not start and end on an erase boundary.
+    Unaligned {
+        addr: u32,
+        len: usize,
+    },
 }
 
 impl<T> From<T> for Error<T> {
@@ -176,36 +512,271 @@ impl<T> From<T> for Error<T> {
     }
 }
 
-impl<T: spi::SpiDevice> W25Q80<T> {
-    pub async fn new(spi: T) -> Result<Self, Error<T::Error>> {
-        let mut new = Self { spi };
+impl<T: spi::SpiDevice, D: DelayNs> W25Qxx<T, D> {
+    /// Probe the device on `spi` and bind the driver to its geometry.
+    ///
+    /// A device found busy or with its write enable latch set, as after a
+    /// brown-out mid-erase, is reset first.
+    pub async fn new(spi: T, delay: D) -> Result<Self, Error<T::Error>> {
+        let mut new = Self {
+            spi,
+            delay,
+            info: FlashInfo::from_block_count(0, 0),
+            address_mode: AddressMode::ThreeByte,
+            read_mode: ReadMode::Normal,
+            verify: false,
+            in_flight: None,
+            suspended: false,
+            resumed: false,
+            // The device may have been left asleep by a previous run: the
+            // first command wakes it up, which is harmless if it is awake.
+            powered_down: true,
+            #[cfg(feature = "time")]
+            auto_sleep: None,
+            #[cfg(feature = "time")]
+            last_active: Instant::now(),
+        };
         let status = new.read_status().await?;
-        if !(status & (Status::BUSY | Status::WEL)).is_empty() {
-            return Err(Error::UnexpectedStatus);
+        if (status & (Status::BUSY | Status::WEL)).is_empty() {
+            new.probe().await?;
+        } else {
+            new.recover().await?;
         }
         Ok(new)
     }
 
-    async fn delay(&mut self, delay_ns: u32) -> Result<(), T::Error> {
-        let mut buf = [spi::Operation::DelayNs(delay_ns)];
-        self.spi.transaction(&mut buf).await?;
+    /// Geometry of the probed device.
+    pub fn info(&self) -> &FlashInfo {
+        &self.info
+    }
+
+    /// The address mode the driver currently assumes the device to be in.
+    pub fn address_mode(&self) -> AddressMode {
+        self.address_mode
+    }
+
+    pub fn read_mode(&self) -> ReadMode {
+        self.read_mode
+    }
+
+    /// Select the command used by `read`.
+    pub fn set_read_mode(&mut self, read_mode: ReadMode) {
+        self.read_mode = read_mode;
+    }
+
+    pub fn verify(&self) -> bool {
+        self.verify
+    }
+
+    /// Make `write` read back each programmed page and fail with
+    /// [`Error::VerifyFailed`] if it does not match.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    fn check_range(&self, addr: u32, len: usize) -> Result<(), Error<T::Error>> {
+        let end = addr as u64 + len as u64;
+        if !self.info.contains(addr, len) || end > self.address_mode.reach() {
+            return Err(Error::OutOfRange { addr, len });
+        }
+        Ok(())
+    }
+
+    /// Fail with [`Error::WriteProtected`] if block protection covers any of
+    /// the `len` bytes at `addr`: the device would silently ignore a program
+    /// or erase there, only clearing WEL.
+    ///
+    /// With `WPS` set the device uses individual block locks instead of the BP
+    /// bits; those are not tracked, so nothing is checked.
+    async fn check_writable(&mut self, addr: u32, len: usize) -> Result<(), Error<T::Error>> {
+        if self.read_status3().await?.contains(Status3::WPS) {
+            return Ok(());
+        }
+        let protection = self.read_block_protection().await?;
+        if protection.protects(addr, len, &self.info) {
+            return Err(Error::WriteProtected { addr, len });
+        }
+        Ok(())
+    }
+
+    /// Encode `opcode` with `addr` for the current address mode.
+    ///
+    /// In 4-byte mode the dedicated 4-byte opcodes are preferred: they do not
+    /// depend on the volatile address mode bit, so a device that silently
+    /// fell back to 3-byte mode (e.g. after a reset) is still addressed correctly.
+    fn command(&self, opcode: Opcode, addr: u32) -> AddressedCommand {
+        let opcode = match self.address_mode {
+            AddressMode::ThreeByte => opcode,
+            AddressMode::FourByte => opcode.four_byte().unwrap_or(opcode),
+        };
+        AddressedCommand::new(opcode, addr, self.address_mode)
+    }
+
+    /// Switch the device to 4-byte addresses.
+    pub async fn enter_4byte_mode(&mut self) -> Result<(), T::Error> {
+        self.transfer(&[Opcode::Enter4ByteMode as u8]).await?;
+        self.address_mode = AddressMode::FourByte;
+        Ok(())
+    }
+
+    /// Switch the device back to 3-byte addresses.
+    ///
+    /// Only the first 16 MiB of the device are reachable afterwards.
+    pub async fn exit_4byte_mode(&mut self) -> Result<(), T::Error> {
+        self.transfer(&[Opcode::Exit4ByteMode as u8]).await?;
+        self.address_mode = AddressMode::ThreeByte;
         Ok(())
     }
 
     async fn transfer(&mut self, bytes: &[u8]) -> Result<(), T::Error> {
+        self.wake().await?;
         self.spi.write(bytes).await?;
         Ok(())
     }
 
     async fn transfer_in_place(&mut self, bytes: &mut [u8]) -> Result<(), T::Error> {
+        self.wake().await?;
         self.spi.transfer_in_place(bytes).await?;
         Ok(())
     }
 
-    pub async fn read_status(&mut self) -> Result<Status, T::Error> {
-        let mut buf = [Opcode::ReadStatus as u8, 0];
+    async fn transaction(&mut self, ops: &mut [spi::Operation<'_, u8>]) -> Result<(), T::Error> {
+        self.wake().await?;
+        self.spi.transaction(ops).await?;
+        Ok(())
+    }
+
+    /// Release the device from deep power-down if it is in it, and note the
+    /// activity for the auto-sleep policy.
+    async fn wake(&mut self) -> Result<(), T::Error> {
+        if self.powered_down {
+            self.spi.write(&[Opcode::ReadDeviceId as u8]).await?;
+            self.delay.delay_ns(POWER_UP_LATENCY_NS).await;
+            self.powered_down = false;
+        }
+        #[cfg(feature = "time")]
+        {
+            self.last_active = Instant::now();
+        }
+        Ok(())
+    }
+
+    async fn read_register(&mut self, opcode: Opcode) -> Result<u8, T::Error> {
+        let mut buf = [opcode as u8, 0];
         self.transfer_in_place(&mut buf).await?;
-        Ok(Status::from_bits_truncate(buf[1]))
+        Ok(buf[1])
+    }
+
+    async fn write_register(
+        &mut self,
+        opcode: Opcode,
+        value: u8,
+        persistence: Persistence,
+    ) -> Result<(), Error<T::Error>> {
+        match persistence {
+            Persistence::Volatile => {
+                self.transfer(&[Opcode::VolatileWriteEnable as u8]).await?;
+                self.transfer(&[opcode as u8, value]).await?;
+            }
+            Persistence::NonVolatile => {
+                self.wait_done().await?;
+                self.write_enable().await?;
+                self.transfer(&[opcode as u8, value]).await?;
+                self.start(Operation::StatusWrite).wait().await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn read_status(&mut self) -> Result<Status, T::Error> {
+        Ok(Status::from_bits_retain(
+            self.read_register(Opcode::ReadStatus).await?,
+        ))
+    }
+
+    pub async fn read_status2(&mut self) -> Result<Status2, T::Error> {
+        Ok(Status2::from_bits_retain(
+            self.read_register(Opcode::ReadStatus2).await?,
+        ))
+    }
+
+    pub async fn read_status3(&mut self) -> Result<Status3, T::Error> {
+        Ok(Status3::from_bits_retain(
+            self.read_register(Opcode::ReadStatus3).await?,
+        ))
+    }
+
+    /// Write status register 1. `BUSY` and `WEL` are read-only and ignored.
+    pub async fn write_status(
+        &mut self,
+        status: Status,
+        persistence: Persistence,
+    ) -> Result<(), Error<T::Error>> {
+        self.write_register(Opcode::WriteStatus, status.bits(), persistence)
+            .await
+    }
+
+    /// Write status register 2. `SUS` is read-only and the `LB` bits can only
+    /// ever be set: setting them is permanent, even with [`Persistence::Volatile`].
+    pub async fn write_status2(
+        &mut self,
+        status: Status2,
+        persistence: Persistence,
+    ) -> Result<(), Error<T::Error>> {
+        self.write_register(Opcode::WriteStatus2, status.bits(), persistence)
+            .await
+    }
+
+    /// Write status register 3. `ADS` is read-only.
+    pub async fn write_status3(
+        &mut self,
+        status: Status3,
+        persistence: Persistence,
+    ) -> Result<(), Error<T::Error>> {
+        self.write_register(Opcode::WriteStatus3, status.bits(), persistence)
+            .await
+    }
+
+    pub async fn read_block_protection(&mut self) -> Result<BlockProtection, T::Error> {
+        let status = self.read_status().await?;
+        let status2 = self.read_status2().await?;
+        Ok(BlockProtection::from_status(status, status2, &self.info))
+    }
+
+    /// Write-protect the region selected by `protection`, leaving the other
+    /// status register bits untouched.
+    pub async fn set_block_protection(
+        &mut self,
+        protection: BlockProtection,
+        persistence: Persistence,
+    ) -> Result<(), Error<T::Error>> {
+        let status = self.read_status().await?;
+        let status2 = self.read_status2().await?;
+        let (new_status, new_status2) = protection.apply(status, status2, &self.info);
+        // The lock bits are written back as read: they can only ever be set.
+        if new_status2 != status2 {
+            self.write_status2(new_status2 - Status2::SUS, persistence)
+                .await?;
+        }
+        if new_status != status {
+            self.write_status(new_status, persistence).await?;
+        }
+        Ok(())
+    }
+
+    /// Set or clear the QE bit required by quad output reads.
+    pub async fn set_quad_enable(
+        &mut self,
+        enable: bool,
+        persistence: Persistence,
+    ) -> Result<(), Error<T::Error>> {
+        let status2 = self.read_status2().await?;
+        if status2.contains(Status2::QE) != enable {
+            let mut new_status2 = status2 - Status2::SUS;
+            new_status2.set(Status2::QE, enable);
+            self.write_status2(new_status2, persistence).await?;
+        }
+        Ok(())
     }
 
     pub async fn read_jedec_id(&mut self) -> Result<Identification, T::Error> {
@@ -218,38 +789,67 @@ impl<T: spi::SpiDevice> W25Q80<T> {
         Ok(Identification::from_jedec_id(&buf[1..]))
     }
 
-    pub async fn get_device_info(&mut self) -> Result<FlashInfo, T::Error> {
-        let mut buf: [u8; 12] = [0; 12];
-        buf[0] = Opcode::ReadJedecId as u8;
-        self.transfer_in_place(&mut buf).await?;
-
-        let full_id: u32 =
-            (((buf[1] as u32) << 16) | ((buf[2] as u32) << 8) | (buf[3] as u32)) & 0x000000FF;
+    /// Identify the device, falling back to its SFDP tables for non-Winbond parts.
+    pub async fn get_device_info(&mut self) -> Result<FlashInfo, Error<T::Error>> {
+        let id = self.read_jedec_id().await?;
+        if let Some(info) = FlashInfo::from_jedec_id(&id) {
+            return Ok(info);
+        }
 
-        let block_count = match full_id {
-            0x20 => 1024, // W25Q512
-            0x19 => 512,  // W25Q256
-            0x18 => 256,  // W25Q128
-            0x17 => 128,  // W25Q64
-            0x16 => 64,   // W25Q32
-            0x15 => 32,   // W25Q16
-            0x14 => 16,   // W25Q80
-            0x13 => 8,    // W25Q40
-            0x12 => 4,    // W25Q20
-            0x11 => 2,    // W25Q10
-            33_u32..=u32::MAX => 0,
-            0_u32..=16_u32 => 0,
-            26_u32..=31_u32 => 0,
+        let unknown = Error::UnknownDevice {
+            jedec_id: id.jedec_id(),
         };
+        match self.read_sfdp().await {
+            Ok(sfdp) => FlashInfo::from_sfdp(&id, &sfdp.basic).ok_or(unknown),
+            Err(Error::Sfdp(_)) => Err(unknown),
+            Err(e) => Err(e),
+        }
+    }
 
-        let device_info = FlashInfo {
-            id: 0,
-            sector_count: block_count * 16,
-            page_count: (block_count * BLOCK_SIZE as u32) / PAGE_SIZE as u32,
-            block_count,
-            capacity_kb: (BLOCK_SIZE as u32 * block_count) / 1024,
-        };
-        return Ok(device_info);
+    /// Read raw bytes from the SFDP space.
+    pub async fn read_sfdp_bytes(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), T::Error> {
+        let cmd = AddressedCommand::new(Opcode::ReadSfdp, addr, AddressMode::ThreeByte);
+        let dummy = [0];
+
+        let mut ops = [
+            spi::Operation::Write(cmd.as_bytes()),
+            spi::Operation::Write(&dummy),
+            spi::Operation::Read(buf),
+        ];
+        self.transaction(&mut ops).await?;
+        Ok(())
+    }
+
+    /// Read and parse the SFDP header and the Basic Flash Parameter Table.
+    pub async fn read_sfdp(&mut self) -> Result<Sfdp, Error<T::Error>> {
+        let mut buf = [0; sfdp::HEADER_LEN];
+        self.read_sfdp_bytes(0, &mut buf).await?;
+        let header = SfdpHeader::parse(&buf).map_err(Error::Sfdp)?;
+
+        // Pick the newest revision of the basic table.
+        let mut basic_header: Option<ParameterHeader> = None;
+        for i in 0..header.parameter_headers {
+            self.read_sfdp_bytes(ParameterHeader::address(i), &mut buf)
+                .await?;
+            let parameter_header = ParameterHeader::parse(&buf);
+            if !parameter_header.is_basic_flash() {
+                continue;
+            }
+            match basic_header {
+                Some(h)
+                    if (h.major, h.minor) >= (parameter_header.major, parameter_header.minor) => {}
+                _ => basic_header = Some(parameter_header),
+            }
+        }
+        let basic_header = basic_header.ok_or(Error::Sfdp(SfdpError::NoBasicTable))?;
+
+        let mut table = [0; sfdp::BASIC_TABLE_MAX_DWORDS * 4];
+        let len = (basic_header.length as usize).min(sfdp::BASIC_TABLE_MAX_DWORDS) * 4;
+        self.read_sfdp_bytes(basic_header.pointer, &mut table[..len])
+            .await?;
+        let basic = BasicFlashParameters::parse(&table[..len]).map_err(Error::Sfdp)?;
+
+        Ok(Sfdp { header, basic })
     }
 
     pub async fn write_enable(&mut self) -> Result<(), T::Error> {
@@ -258,100 +858,499 @@ impl<T: spi::SpiDevice> W25Q80<T> {
         Ok(())
     }
 
-    pub async fn wait_done(&mut self, delay_ns: u32) -> Result<(), T::Error> {
-        while self.read_status().await?.contains(Status::BUSY) {
-            self.delay(delay_ns).await?;
+    /// Wait for the operation in progress to complete, resuming it first if
+    /// it is suspended.
+    pub async fn wait_done(&mut self) -> Result<(), Error<T::Error>> {
+        self.resume().await?;
+        match self.in_flight {
+            Some(operation) => Pending::new(self, operation).wait().await,
+            None => Ok(()),
+        }
+    }
+
+    /// The program or erase operation started last, until it is known to be done.
+    pub fn in_flight(&self) -> Option<Operation> {
+        self.in_flight
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    fn start(&mut self, operation: Operation) -> Pending<'_, T, D> {
+        self.in_flight = Some(operation);
+        self.suspended = false;
+        self.resumed = false;
+        Pending::new(self, operation)
+    }
+
+    fn finished(&mut self) {
+        self.in_flight = None;
+        self.resumed = false;
+    }
+
+    /// Suspend the operation in progress so that the array can be read.
+    ///
+    /// Returns whether an operation is now suspended. Returns `false` if no
+    /// suspendable operation is running, including when it completed before
+    /// the suspend took effect. Addresses being erased or programmed must not
+    /// be read while suspended.
+    pub async fn suspend(&mut self) -> Result<bool, T::Error> {
+        if self.suspended {
+            return Ok(true);
+        }
+        match self.in_flight {
+            Some(operation) if operation.is_suspendable() => {}
+            _ => return Ok(false),
+        }
+        if self.resumed {
+            self.delay.delay_ns(RESUME_TO_SUSPEND_NS).await;
         }
+
+        self.transfer(&[Opcode::Suspend as u8]).await?;
+        self.delay.delay_ns(SUSPEND_LATENCY_NS).await;
+
+        if self.read_status2().await?.contains(Status2::SUS) {
+            self.suspended = true;
+        } else if !self.read_status().await?.contains(Status::BUSY) {
+            self.finished();
+        }
+        Ok(self.suspended)
+    }
+
+    /// Resume a suspended operation. Does nothing if none is suspended.
+    pub async fn resume(&mut self) -> Result<(), T::Error> {
+        if !self.suspended {
+            return Ok(());
+        }
+        self.transfer(&[Opcode::Resume as u8]).await?;
+        self.suspended = false;
+        self.resumed = true;
         Ok(())
     }
 
-    pub async fn power_down(&mut self) -> Result<(), T::Error> {
-        let buf = [Opcode::PowerDown as u8];
-        self.transfer(&buf).await?;
+    /// Get the array ready for a read, suspending or finishing the operation
+    /// in progress. Returns whether the caller has to resume afterwards.
+    async fn pause_in_flight(&mut self) -> Result<bool, Error<T::Error>> {
+        if self.in_flight.is_none() || self.suspended {
+            return Ok(false);
+        }
+        if self.suspend().await? {
+            return Ok(true);
+        }
+        self.wait_done().await?;
+        Ok(false)
+    }
+
+    /// Put the device in deep power-down, finishing the operation in progress first.
+    ///
+    /// Any later command wakes the device up again.
+    pub async fn power_down(&mut self) -> Result<(), Error<T::Error>> {
+        if self.powered_down {
+            return Ok(());
+        }
+        self.wait_done().await?;
+        self.transfer(&[Opcode::PowerDown as u8]).await?;
+        self.delay.delay_ns(POWER_DOWN_LATENCY_NS).await;
+        self.powered_down = true;
         Ok(())
     }
 
+    /// Wake the device from deep power-down ahead of the next command.
     pub async fn release_power_down(&mut self) -> Result<(), T::Error> {
-        let buf = [Opcode::ReadDeviceId as u8];
-        self.transfer(&buf).await?;
-        self.delay(3000).await?;
+        self.wake().await
+    }
+
+    /// Whether the driver has put the device in deep power-down.
+    pub fn is_powered_down(&self) -> bool {
+        self.powered_down
+    }
+
+    /// Power the device down after `idle` without commands, or never if `None`.
+    ///
+    /// The driver has no timer of its own: the policy is applied by
+    /// [`sleep_if_idle`](Self::sleep_if_idle), e.g. at [`idle_deadline`](Self::idle_deadline).
+    ///
+    /// Activity is timestamped with `embassy_time`, so the policy needs the
+    /// `time` feature and a time driver.
+    #[cfg(feature = "time")]
+    pub fn set_auto_sleep(&mut self, idle: Option<Duration>) {
+        self.auto_sleep = idle;
+    }
+
+    /// When the auto-sleep policy will next power the device down, if ever.
+    ///
+    /// An operation left running keeps the device awake until it is waited for.
+    #[cfg(feature = "time")]
+    pub fn idle_deadline(&self) -> Option<Instant> {
+        if self.powered_down || self.in_flight.is_some() {
+            return None;
+        }
+        Some(self.last_active + self.auto_sleep?)
+    }
+
+    /// Apply the auto-sleep policy. Returns whether the device was powered down.
+    #[cfg(feature = "time")]
+    pub async fn sleep_if_idle(&mut self) -> Result<bool, Error<T::Error>> {
+        match self.idle_deadline() {
+            Some(deadline) if Instant::now() >= deadline => {
+                self.power_down().await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
 
+    /// Clock out the bytes that take the device out of continuous read mode,
+    /// in which it would take the next command for an address.
+    ///
+    /// Sixteen high clocks are needed in case it was left in a dual I/O read.
+    pub async fn exit_continuous_read(&mut self) -> Result<(), T::Error> {
+        self.transfer(&[Opcode::ModeBitReset as u8; 2]).await
+    }
+
+    /// Reset the device to its power-on state.
+    ///
+    /// An erase or program in progress is abandoned and leaves its region
+    /// with undefined contents. Volatile status bits and the address mode
+    /// revert to their non-volatile settings.
+    pub async fn reset(&mut self) -> Result<(), T::Error> {
+        self.exit_continuous_read().await?;
+        self.transfer(&[Opcode::EnableReset as u8]).await?;
+        self.transfer(&[Opcode::Reset as u8]).await?;
+        self.delay.delay_ns(RESET_LATENCY_NS).await;
+
+        self.address_mode = AddressMode::ThreeByte;
+        self.in_flight = None;
+        self.suspended = false;
+        self.resumed = false;
         Ok(())
     }
 
-    pub async fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), T::Error> {
-        let cmd_buf = [
-            Opcode::Read as u8,
-            (addr >> 16) as u8,
-            (addr >> 8) as u8,
-            addr as u8,
-        ];
+    /// Bring the device back from whatever state it was left in, e.g. by a
+    /// brown-out during an erase: wake it, reset it and check it is still the
+    /// part the driver was bound to.
+    pub async fn recover(&mut self) -> Result<(), Error<T::Error>> {
+        // A device in deep power-down ignores the reset.
+        self.powered_down = true;
+        self.reset().await?;
+        self.probe().await
+    }
 
-        let mut ops = [spi::Operation::Write(&cmd_buf), spi::Operation::Read(buf)];
+    /// Check the device is idle, identify it and set up its address mode.
+    async fn probe(&mut self) -> Result<(), Error<T::Error>> {
+        let status = self.read_status().await?;
+        if !(status & (Status::BUSY | Status::WEL)).is_empty() {
+            return Err(Error::UnexpectedStatus { status });
+        }
+        let info = self.get_device_info().await?;
+        if self.info.capacity() != 0 && info.id != self.info.id {
+            return Err(Error::UnknownDevice { jedec_id: info.id });
+        }
+        self.info = info;
+        if self.info.capacity() as u64 > AddressMode::ThreeByte.reach() {
+            self.enter_4byte_mode().await?;
+        }
+        Ok(())
+    }
+
+    /// Read `buf.len()` bytes at `addr`.
+    ///
+    /// An erase or program still in progress is suspended for the duration
+    /// of the read, or waited for if it cannot be suspended.
+    pub async fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<T::Error>> {
+        self.check_range(addr, buf.len())?;
 
-        self.spi.transaction(&mut ops).await?;
+        let resume = self.pause_in_flight().await?;
+        match self.read_mode {
+            ReadMode::Normal => {
+                let cmd = self.command(Opcode::Read, addr);
+                let mut ops = [
+                    spi::Operation::Write(cmd.as_bytes()),
+                    spi::Operation::Read(buf),
+                ];
+                self.transaction(&mut ops).await?;
+            }
+            ReadMode::Fast => {
+                let cmd = self.command(Opcode::FastRead, addr);
+                let dummy = [0; FAST_READ_DUMMY_CYCLES as usize / 8];
+                let mut ops = [
+                    spi::Operation::Write(cmd.as_bytes()),
+                    spi::Operation::Write(&dummy),
+                    spi::Operation::Read(buf),
+                ];
+                self.transaction(&mut ops).await?;
+            }
+        }
+        if resume {
+            self.resume().await?;
+        }
         Ok(())
     }
 
-    pub async fn write(&mut self, addr: u32, data: &[u8], delay_ns: u32) -> Result<(), T::Error> {
-        for (c, chunk) in data.chunks(256).enumerate() {
-            self.write_enable().await?;
+    /// Program `data` at `addr`.
+    ///
+    /// The data is split on page boundaries, since a page program that runs
+    /// past the end of a page wraps around to its start. In verify mode each
+    /// page is read back once programmed.
+    ///
+    /// Fails with [`Error::WriteProtected`] without programming anything if
+    /// block protection covers part of the range.
+    pub async fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error<T::Error>> {
+        self.check_range(addr, data.len())?;
+        self.wait_done().await?;
+        self.check_writable(addr, data.len()).await?;
 
-            let current_addr: u32 = (addr as usize + c * 256).try_into().unwrap();
-            let cmd_buf = [
-                Opcode::PageProg as u8,
-                (current_addr >> 16) as u8,
-                (current_addr >> 8) as u8,
-                current_addr as u8,
-            ];
+        let mut current_addr = addr;
+        let mut data = data;
+        while !data.is_empty() {
+            let page_left = PAGE_SIZE - current_addr as usize % PAGE_SIZE;
+            let (chunk, rest) = data.split_at(page_left.min(data.len()));
 
+            self.write_enable().await?;
+            let cmd = self.command(Opcode::PageProg, current_addr);
             let mut ops = [
-                spi::Operation::Write(&cmd_buf),
-                spi::Operation::Write(&chunk),
+                spi::Operation::Write(cmd.as_bytes()),
+                spi::Operation::Write(chunk),
             ];
+            self.transaction(&mut ops).await?;
+            self.start(Operation::PageProgram).wait().await?;
+            if self.verify {
+                self.verify_page(current_addr, chunk).await?;
+            }
 
-            self.spi.transaction(&mut ops).await?;
-            self.wait_done(delay_ns).await?;
+            current_addr += chunk.len() as u32;
+            data = rest;
         }
         Ok(())
     }
 
-    pub async fn erase_sector(&mut self, addr: u32, delay_ns: u32) -> Result<(), T::Error> {
-        self.write_enable().await?;
+    async fn verify_page(&mut self, addr: u32, data: &[u8]) -> Result<(), Error<T::Error>> {
+        let mut buf = [0; PAGE_SIZE];
+        let buf = &mut buf[..data.len()];
+        self.read(addr, buf).await?;
+        match buf
+            .iter()
+            .zip(data)
+            .position(|(read, written)| read != written)
+        {
+            Some(offset) => Err(Error::VerifyFailed {
+                addr: addr + offset as u32,
+                expected: data[offset],
+                found: buf[offset],
+            }),
+            None => Ok(()),
+        }
+    }
 
-        let cmd_buf = [
-            Opcode::SectorErase as u8,
-            (addr >> 16) as u8,
-            (addr >> 8) as u8,
-            addr as u8,
-        ];
-        self.transfer(&cmd_buf).await?;
-        self.wait_done(delay_ns).await?;
+    /// Check that the `len` bytes at `addr` read back erased, a page at a
+    /// time, failing with [`Error::VerifyFailed`] at the first one that does not.
+    pub async fn verify_erased(&mut self, addr: u32, len: usize) -> Result<(), Error<T::Error>> {
+        self.check_range(addr, len)?;
 
+        let mut buf = [0; PAGE_SIZE];
+        let mut current_addr = addr;
+        let mut left = len;
+        while left > 0 {
+            let chunk = &mut buf[..left.min(PAGE_SIZE)];
+            self.read(current_addr, chunk).await?;
+            if let Some(offset) = chunk.iter().position(|&b| b != 0xFF) {
+                return Err(Error::VerifyFailed {
+                    addr: current_addr + offset as u32,
+                    expected: 0xFF,
+                    found: chunk[offset],
+                });
+            }
+            current_addr += chunk.len() as u32;
+            left -= chunk.len();
+        }
         Ok(())
     }
 
-    pub async fn erase_block(&mut self, addr: u32, delay_ns: u32) -> Result<(), T::Error> {
+    /// CRC-32 of the `len` bytes at `addr`, read a page at a time.
+    pub async fn crc32_range(&mut self, addr: u32, len: usize) -> Result<u32, Error<T::Error>> {
+        self.check_range(addr, len)?;
+
+        let mut crc = Crc32::new();
+        let mut buf = [0; PAGE_SIZE];
+        let mut current_addr = addr;
+        let mut left = len;
+        while left > 0 {
+            let chunk = &mut buf[..left.min(PAGE_SIZE)];
+            self.read(current_addr, chunk).await?;
+            crc.update(chunk);
+            current_addr += chunk.len() as u32;
+            left -= chunk.len();
+        }
+        Ok(crc.finish())
+    }
+
+    /// Start erasing the sector containing `addr` without waiting for it.
+    ///
+    /// If the returned handle is dropped, reads issued meanwhile suspend the
+    /// erase and `wait_done` finishes it.
+    pub async fn start_erase_sector(
+        &mut self,
+        addr: u32,
+    ) -> Result<Pending<'_, T, D>, Error<T::Error>> {
+        let start = addr - addr % SECTOR_SIZE as u32;
+        self.check_range(start, SECTOR_SIZE)?;
+        self.wait_done().await?;
+        self.check_writable(start, SECTOR_SIZE).await?;
         self.write_enable().await?;
 
-        let cmd_buf = [
-            Opcode::BlockErase as u8,
-            (addr >> 16) as u8,
-            (addr >> 8) as u8,
-            addr as u8,
-        ];
+        let cmd = self.command(Opcode::SectorErase, addr);
+        self.transfer(cmd.as_bytes()).await?;
+        Ok(self.start(Operation::SectorErase))
+    }
 
-        self.transfer(&cmd_buf).await?;
-        self.wait_done(delay_ns).await?;
+    pub async fn erase_sector(&mut self, addr: u32) -> Result<(), Error<T::Error>> {
+        self.start_erase_sector(addr).await?.wait().await
+    }
+
+    /// Start erasing the block containing `addr` without waiting for it.
+    ///
+    /// If the returned handle is dropped, reads issued meanwhile suspend the
+    /// erase and `wait_done` finishes it.
+    pub async fn start_erase_block(
+        &mut self,
+        addr: u32,
+    ) -> Result<Pending<'_, T, D>, Error<T::Error>> {
+        let start = addr - addr % BLOCK_SIZE as u32;
+        self.check_range(start, BLOCK_SIZE)?;
+        self.wait_done().await?;
+        self.check_writable(start, BLOCK_SIZE).await?;
+        self.write_enable().await?;
+
+        let cmd = self.command(Opcode::BlockErase, addr);
+        self.transfer(cmd.as_bytes()).await?;
+        Ok(self.start(Operation::BlockErase))
+    }
+
+    pub async fn erase_block(&mut self, addr: u32) -> Result<(), Error<T::Error>> {
+        self.start_erase_block(addr).await?.wait().await
+    }
+
+    /// Start erasing the 32 KiB half block containing `addr` without waiting for it.
+    ///
+    /// If the returned handle is dropped, reads issued meanwhile suspend the
+    /// erase and `wait_done` finishes it.
+    pub async fn start_erase_half_block(
+        &mut self,
+        addr: u32,
+    ) -> Result<Pending<'_, T, D>, Error<T::Error>> {
+        let start = addr - addr % HALF_BLOCK_SIZE as u32;
+        self.check_range(start, HALF_BLOCK_SIZE)?;
+        self.wait_done().await?;
+        self.check_writable(start, HALF_BLOCK_SIZE).await?;
+        self.write_enable().await?;
+
+        let cmd = self.command(Opcode::HalfBlockErase, addr);
+        self.transfer(cmd.as_bytes()).await?;
+        Ok(self.start(Operation::HalfBlockErase))
+    }
+
+    pub async fn erase_half_block(&mut self, addr: u32) -> Result<(), Error<T::Error>> {
+        self.start_erase_half_block(addr).await?.wait().await
+    }
 
+    /// Erase the `len` bytes at `addr`, which must be sector-aligned.
+    ///
+    /// Each step uses the largest erase that is aligned and fits in what is
+    /// left: a chip erase for the whole array, then 64 KiB blocks, 32 KiB half
+    /// blocks and 4 KiB sectors.
+    pub async fn erase_range(&mut self, addr: u32, len: usize) -> Result<(), Error<T::Error>> {
+        self.check_range(addr, len)?;
+        if !(addr as usize).is_multiple_of(SECTOR_SIZE) || !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(Error::Unaligned { addr, len });
+        }
+        // Checked up front so that a protected range is not left half erased.
+        self.wait_done().await?;
+        self.check_writable(addr, len).await?;
+        if addr == 0 && len == self.info.capacity() as usize {
+            return self.erase_all().await;
+        }
+
+        let end = addr as usize + len;
+        let mut current_addr = addr as usize;
+        while current_addr < end {
+            let left = end - current_addr;
+            let size = if current_addr.is_multiple_of(BLOCK_SIZE) && left >= BLOCK_SIZE {
+                self.erase_block(current_addr as u32).await?;
+                BLOCK_SIZE
+            } else if current_addr.is_multiple_of(HALF_BLOCK_SIZE) && left >= HALF_BLOCK_SIZE {
+                self.erase_half_block(current_addr as u32).await?;
+                HALF_BLOCK_SIZE
+            } else {
+                self.erase_sector(current_addr as u32).await?;
+                SECTOR_SIZE
+            };
+            current_addr += size;
+        }
         Ok(())
     }
 
-    pub async fn erase_all(&mut self, delay_ns: u32) -> Result<(), T::Error> {
+    /// Start erasing the whole array without waiting for it.
+    ///
+    /// A chip erase cannot be suspended: reads issued while it runs wait for it.
+    /// The device refuses it if any block is protected.
+    pub async fn start_erase_all(&mut self) -> Result<Pending<'_, T, D>, Error<T::Error>> {
+        self.wait_done().await?;
+        self.check_writable(0, self.info.capacity() as usize)
+            .await?;
         self.write_enable().await?;
         let cmd_buf = [Opcode::ChipErase as u8];
         self.transfer(&cmd_buf).await?;
-        self.wait_done(delay_ns).await?;
+        Ok(self.start(Operation::ChipErase))
+    }
+
+    pub async fn erase_all(&mut self) -> Result<(), Error<T::Error>> {
+        self.start_erase_all().await?.wait().await
+    }
+}
+
+impl<T: MultiLaneSpiDevice, D: DelayNs> W25Qxx<T, D> {
+    async fn read_multi_lane(
+        &mut self,
+        opcode: Opcode,
+        lanes: Lanes,
+        addr: u32,
+        buf: &mut [u8],
+    ) -> Result<(), Error<T::Error>> {
+        self.check_range(addr, buf.len())?;
+
+        let resume = self.pause_in_flight().await?;
+        self.wake().await?;
+        let cmd = self.command(opcode, addr);
+        self.spi
+            .read_multi_lane(cmd.as_bytes(), FAST_READ_DUMMY_CYCLES, lanes, buf)
+            .await?;
+        if resume {
+            self.resume().await?;
+        }
         Ok(())
     }
+
+    /// Read with the data phase clocked over two lines (`3Bh`).
+    pub async fn read_dual_output(
+        &mut self,
+        addr: u32,
+        buf: &mut [u8],
+    ) -> Result<(), Error<T::Error>> {
+        self.read_multi_lane(Opcode::DualOutputRead, Lanes::Dual, addr, buf)
+            .await
+    }
+
+    /// Read with the data phase clocked over four lines (`6Bh`).
+    ///
+    /// The QE bit of status register 2 must be set, otherwise the device
+    /// keeps using `/WP` and `/HOLD` as control pins and the data is garbage.
+    pub async fn read_quad_output(
+        &mut self,
+        addr: u32,
+        buf: &mut [u8],
+    ) -> Result<(), Error<T::Error>> {
+        self.read_multi_lane(Opcode::QuadOutputRead, Lanes::Quad, addr, buf)
+            .await
+    }
 }
@@ -0,0 +1,188 @@
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::spi;
+
+use crate::{Error, FlashInfo, Status, W25Qxx};
+
+/// A program or erase operation started on the device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Operation {
+    PageProgram,
+    SectorErase,
+    HalfBlockErase,
+    BlockErase,
+    ChipErase,
+    StatusWrite,
+}
+
+/// Typical and worst-case duration of an operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    pub typical_ns: u64,
+    pub max_ns: u64,
+}
+
+impl Operation {
+    /// Whether the operation can be paused with Erase/Program Suspend.
+    pub const fn is_suspendable(self) -> bool {
+        matches!(
+            self,
+            Operation::PageProgram
+                | Operation::SectorErase
+                | Operation::HalfBlockErase
+                | Operation::BlockErase
+        )
+    }
+
+    /// Duration of the operation on the device described by `info`, after the
+    /// W25Q datasheets (tPP, tSE, tBE1, tBE2, tCE and tW).
+    pub const fn timing(self, info: &FlashInfo) -> Timing {
+        const MS: u64 = 1_000_000;
+        match self {
+            Operation::PageProgram => Timing {
+                typical_ns: 700_000,
+                max_ns: 3 * MS,
+            },
+            Operation::SectorErase => Timing {
+                typical_ns: 45 * MS,
+                max_ns: 400 * MS,
+            },
+            Operation::HalfBlockErase => Timing {
+                typical_ns: 120 * MS,
+                max_ns: 1600 * MS,
+            },
+            Operation::BlockErase => Timing {
+                typical_ns: 150 * MS,
+                max_ns: 2000 * MS,
+            },
+            Operation::ChipErase => {
+                // Scales with the array: ~2.5 s typical, 12.5 s worst case per MiB.
+                let mib = (info.capacity() as u64 >> 20) + 1;
+                Timing {
+                    typical_ns: 2500 * MS * mib,
+                    max_ns: 12500 * MS * mib,
+                }
+            }
+            Operation::StatusWrite => Timing {
+                typical_ns: 10 * MS,
+                max_ns: 15 * MS,
+            },
+        }
+    }
+}
+
+/// Longest single delay between two status polls.
+const MAX_POLL_INTERVAL_NS: u64 = 100_000_000;
+
+/// Status polling schedule for one operation.
+///
+/// The first poll comes after an eighth of the typical duration and the
+/// interval doubles up to half of it, so short operations are noticed quickly
+/// without hammering the bus during long erases. Time is accounted from the
+/// poll delays alone.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    interval_ns: u64,
+    max_interval_ns: u64,
+    elapsed_ns: u64,
+    timeout_ns: u64,
+}
+
+impl Backoff {
+    /// Schedule for `operation`, timing out after its worst-case duration.
+    pub const fn new(operation: Operation, info: &FlashInfo) -> Self {
+        let timing = operation.timing(info);
+        let max_interval_ns = if timing.typical_ns / 2 < MAX_POLL_INTERVAL_NS {
+            timing.typical_ns / 2
+        } else {
+            MAX_POLL_INTERVAL_NS
+        };
+        Self {
+            interval_ns: timing.typical_ns / 8,
+            max_interval_ns,
+            elapsed_ns: 0,
+            timeout_ns: timing.max_ns,
+        }
+    }
+
+    pub const fn with_timeout(mut self, timeout_ns: u64) -> Self {
+        self.timeout_ns = timeout_ns;
+        self
+    }
+
+    pub const fn timeout_ns(&self) -> u64 {
+        self.timeout_ns
+    }
+
+    /// Delay before the next poll, or `None` once the timeout has passed.
+    pub fn next_delay_ns(&mut self) -> Option<u32> {
+        if self.elapsed_ns >= self.timeout_ns {
+            return None;
+        }
+        let delay = self
+            .interval_ns
+            .min(self.timeout_ns - self.elapsed_ns)
+            .max(1);
+        self.elapsed_ns += delay;
+        self.interval_ns = (self.interval_ns * 2).min(self.max_interval_ns);
+        Some(delay as u32)
+    }
+}
+
+/// A program or erase operation running on the device.
+///
+/// Dropping the handle leaves the operation running: the driver keeps track of
+/// it, suspends it for reads and finishes it before the next conflicting command.
+pub struct Pending<'a, T, D> {
+    flash: &'a mut W25Qxx<T, D>,
+    operation: Operation,
+    backoff: Backoff,
+}
+
+impl<'a, T: spi::SpiDevice, D: DelayNs> Pending<'a, T, D> {
+    pub(crate) fn new(flash: &'a mut W25Qxx<T, D>, operation: Operation) -> Self {
+        let backoff = Backoff::new(operation, &flash.info);
+        Self {
+            flash,
+            operation,
+            backoff,
+        }
+    }
+
+    pub fn operation(&self) -> Operation {
+        self.operation
+    }
+
+    /// Fail with [`Error::Timeout`] after `timeout_ns` instead of the
+    /// worst-case duration of the operation.
+    pub fn with_timeout(mut self, timeout_ns: u64) -> Self {
+        self.backoff = self.backoff.with_timeout(timeout_ns);
+        self
+    }
+
+    /// Check once whether the operation has completed.
+    pub async fn is_done(&mut self) -> Result<bool, Error<T::Error>> {
+        if self.flash.read_status().await?.contains(Status::BUSY) {
+            return Ok(false);
+        }
+        self.flash.finished();
+        Ok(true)
+    }
+
+    /// Poll until the operation completes, sleeping on the driver's delay
+    /// between polls with the bus released.
+    pub async fn wait(mut self) -> Result<(), Error<T::Error>> {
+        loop {
+            let Some(delay_ns) = self.backoff.next_delay_ns() else {
+                return Err(Error::Timeout {
+                    operation: self.operation,
+                    timeout_ns: self.backoff.timeout_ns(),
+                });
+            };
+            self.flash.delay.delay_ns(delay_ns).await;
+            if self.is_done().await? {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::spi;
+
+use crate::{AddressMode, Error, Opcode, Operation, Persistence, Status2, W25Qxx};
+
+/// Size of each security register.
+pub const SECURITY_REGISTER_SIZE: usize = 0x100;
+
+/// One of the three one-time-programmable security registers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecurityRegister {
+    One = 1,
+    Two = 2,
+    Three = 3,
+}
+
+impl SecurityRegister {
+    /// Address of byte `offset` of the register in the security register space.
+    pub const fn address(self, offset: u8) -> u32 {
+        ((self as u32) << 12) | offset as u32
+    }
+
+    /// The status register 2 bit that permanently locks the register.
+    pub const fn lock_bit(self) -> Status2 {
+        match self {
+            SecurityRegister::One => Status2::LB1,
+            SecurityRegister::Two => Status2::LB2,
+            SecurityRegister::Three => Status2::LB3,
+        }
+    }
+}
+
+impl<T: spi::SpiDevice, D: DelayNs> W25Qxx<T, D> {
+    /// Read the factory-programmed 64-bit unique ID of the device.
+    pub async fn read_unique_id(&mut self) -> Result<u64, Error<T::Error>> {
+        self.wait_done().await?;
+
+        // The ID follows 4 dummy bytes, or 5 in 4-byte address mode.
+        let dummy_len = match self.address_mode {
+            AddressMode::ThreeByte => 4,
+            AddressMode::FourByte => 5,
+        };
+        let mut buf = [0; 1 + 5 + 8];
+        buf[0] = Opcode::ReadUniqueId as u8;
+        let buf = &mut buf[..1 + dummy_len + 8];
+        self.transfer_in_place(buf).await?;
+
+        let mut id = [0; 8];
+        id.copy_from_slice(&buf[1 + dummy_len..]);
+        Ok(u64::from_be_bytes(id))
+    }
+
+    fn check_security_range(
+        register: SecurityRegister,
+        offset: u8,
+        len: usize,
+    ) -> Result<(), Error<T::Error>> {
+        if offset as usize + len > SECURITY_REGISTER_SIZE {
+            return Err(Error::OutOfRange {
+                addr: register.address(offset),
+                len,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether `register` has been permanently locked.
+    pub async fn is_security_register_locked(
+        &mut self,
+        register: SecurityRegister,
+    ) -> Result<bool, Error<T::Error>> {
+        Ok(self.read_status2().await?.contains(register.lock_bit()))
+    }
+
+    async fn check_unlocked(&mut self, register: SecurityRegister) -> Result<(), Error<T::Error>> {
+        if self.is_security_register_locked(register).await? {
+            return Err(Error::WriteProtected {
+                addr: register.address(0),
+                len: SECURITY_REGISTER_SIZE,
+            });
+        }
+        Ok(())
+    }
+
+    pub async fn read_security_register(
+        &mut self,
+        register: SecurityRegister,
+        offset: u8,
+        buf: &mut [u8],
+    ) -> Result<(), Error<T::Error>> {
+        Self::check_security_range(register, offset, buf.len())?;
+        self.wait_done().await?;
+
+        let cmd = self.command(Opcode::ReadSecurityRegister, register.address(offset));
+        let dummy = [0];
+        let mut ops = [
+            spi::Operation::Write(cmd.as_bytes()),
+            spi::Operation::Write(&dummy),
+            spi::Operation::Read(buf),
+        ];
+        self.transaction(&mut ops).await?;
+        Ok(())
+    }
+
+    /// Program `data` into `register` at `offset`. Like the main array, only
+    /// erased bits can be programmed.
+    pub async fn program_security_register(
+        &mut self,
+        register: SecurityRegister,
+        offset: u8,
+        data: &[u8],
+    ) -> Result<(), Error<T::Error>> {
+        Self::check_security_range(register, offset, data.len())?;
+        self.wait_done().await?;
+        self.check_unlocked(register).await?;
+        self.write_enable().await?;
+
+        let cmd = self.command(Opcode::ProgramSecurityRegister, register.address(offset));
+        let mut ops = [
+            spi::Operation::Write(cmd.as_bytes()),
+            spi::Operation::Write(data),
+        ];
+        self.transaction(&mut ops).await?;
+        // Security register operations cannot be suspended, so wait right away.
+        self.start(Operation::PageProgram).wait().await
+    }
+
+    pub async fn erase_security_register(
+        &mut self,
+        register: SecurityRegister,
+    ) -> Result<(), Error<T::Error>> {
+        self.wait_done().await?;
+        self.check_unlocked(register).await?;
+        self.write_enable().await?;
+
+        let cmd = self.command(Opcode::EraseSecurityRegister, register.address(0));
+        self.transfer(cmd.as_bytes()).await?;
+        self.start(Operation::SectorErase).wait().await
+    }
+
+    /// Permanently lock `register` against program and erase.
+    ///
+    /// This sets a one-time-programmable bit and cannot be undone.
+    pub async fn lock_security_register(
+        &mut self,
+        register: SecurityRegister,
+    ) -> Result<(), Error<T::Error>> {
+        let status2 = self.read_status2().await?;
+        if !status2.contains(register.lock_bit()) {
+            self.write_status2(
+                (status2 | register.lock_bit()) - Status2::SUS,
+                Persistence::NonVolatile,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+//! JEDEC JESD216 Serial Flash Discoverable Parameters.
+//!
+//! The parsers work on raw bytes read from the SFDP address space, so they can
+//! be fed either from a live device (see `W25Qxx::read_sfdp`) or from a dump.
+
+use bitflags::bitflags;
+
+/// "SFDP" in little-endian byte order.
+const SIGNATURE: u32 = 0x5044_4653;
+
+/// Parameter ID of the Basic Flash Parameter Table.
+pub const BASIC_FLASH_PARAMETER_ID: u16 = 0xFF00;
+
+/// Size of the SFDP header and of each parameter header.
+pub const HEADER_LEN: usize = 8;
+
+/// Number of Basic Flash Parameter Table DWORDs defined up to JESD216D.
+pub const BASIC_TABLE_MAX_DWORDS: usize = 20;
+
+/// Number of DWORDs of the original JESD216 Basic Flash Parameter Table.
+const BASIC_TABLE_MIN_DWORDS: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SfdpError {
+    /// The SFDP header does not start with the "SFDP" signature.
+    BadSignature,
+    /// None of the parameter headers describes a Basic Flash Parameter Table.
+    NoBasicTable,
+    /// The Basic Flash Parameter Table is shorter than the JESD216 minimum.
+    TableTooShort,
+    /// The density of the Basic Flash Parameter Table does not fit in 64 bits.
+    BadDensity,
+}
+
+/// The SFDP header found at address 0 of the SFDP space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SfdpHeader {
+    pub major: u8,
+    pub minor: u8,
+    /// Number of parameter headers following this header, 1 to 256.
+    pub parameter_headers: u16,
+}
+
+impl SfdpHeader {
+    pub fn parse(buf: &[u8; HEADER_LEN]) -> Result<Self, SfdpError> {
+        if u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) != SIGNATURE {
+            return Err(SfdpError::BadSignature);
+        }
+        Ok(Self {
+            minor: buf[4],
+            major: buf[5],
+            parameter_headers: u16::from(buf[6]) + 1,
+        })
+    }
+}
+
+/// Location and revision of one parameter table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParameterHeader {
+    pub id: u16,
+    pub major: u8,
+    pub minor: u8,
+    /// Length of the table in DWORDs.
+    pub length: u8,
+    /// SFDP address of the table.
+    pub pointer: u32,
+}
+
+impl ParameterHeader {
+    pub fn parse(buf: &[u8; HEADER_LEN]) -> Self {
+        Self {
+            id: u16::from_le_bytes([buf[0], buf[7]]),
+            minor: buf[1],
+            major: buf[2],
+            length: buf[3],
+            pointer: u32::from_le_bytes([buf[4], buf[5], buf[6], 0]),
+        }
+    }
+
+    /// SFDP address of the `index`th parameter header.
+    pub const fn address(index: u16) -> u32 {
+        (HEADER_LEN * (index as usize + 1)) as u32
+    }
+
+    pub fn is_basic_flash(&self) -> bool {
+        self.id == BASIC_FLASH_PARAMETER_ID
+    }
+}
+
+/// An erase command and the size of the region it erases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EraseType {
+    pub size: u32,
+    pub opcode: u8,
+}
+
+/// Instruction parameters of a fast read mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FastRead {
+    pub opcode: u8,
+    /// Dummy clocks between the address and the data.
+    pub dummy_cycles: u8,
+    /// Mode clocks between the address and the dummy clocks.
+    pub mode_cycles: u8,
+}
+
+impl FastRead {
+    fn parse(bits: u16) -> Self {
+        Self {
+            dummy_cycles: (bits & 0x1F) as u8,
+            mode_cycles: ((bits >> 5) & 0x07) as u8,
+            opcode: (bits >> 8) as u8,
+        }
+    }
+}
+
+/// Supported fast read modes, named after their command-address-data lane counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FastReadModes {
+    pub fast_read_1_1_2: Option<FastRead>,
+    pub fast_read_1_2_2: Option<FastRead>,
+    pub fast_read_1_1_4: Option<FastRead>,
+    pub fast_read_1_4_4: Option<FastRead>,
+    pub fast_read_2_2_2: Option<FastRead>,
+    pub fast_read_4_4_4: Option<FastRead>,
+}
+
+/// Address lengths accepted by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressBytes {
+    ThreeOnly,
+    ThreeOrFour,
+    FourOnly,
+}
+
+bitflags! {
+    /// Methods to enter 4-byte address mode (BFPT DWORD 16).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FourByteEntry: u8 {
+        /// Issue `B7h`.
+        const ENTER_B7 = 1 << 0;
+        /// Issue `06h` then `B7h`.
+        const WRITE_ENABLE_B7 = 1 << 1;
+        /// Set bit 7 of an extended address register.
+        const EXTENDED_REGISTER = 1 << 2;
+        /// Set bit 7 of a bank register.
+        const BANK_REGISTER = 1 << 3;
+        /// Use dedicated 4-byte address instructions.
+        const DEDICATED_INSTRUCTIONS = 1 << 5;
+        /// The device always uses 4-byte addresses.
+        const ALWAYS = 1 << 6;
+    }
+}
+
+/// Contents of the Basic Flash Parameter Table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BasicFlashParameters {
+    /// Device density in bits.
+    pub density_bits: u64,
+    /// Erase types 1 to 4; unsupported types are `None`.
+    pub erase_types: [Option<EraseType>; 4],
+    /// Program page size in bytes.
+    pub page_size: u32,
+    pub fast_read: FastReadModes,
+    pub address_bytes: AddressBytes,
+    /// Methods to enter 4-byte address mode, empty if the table predates JESD216B.
+    pub four_byte_entry: FourByteEntry,
+}
+
+impl BasicFlashParameters {
+    /// Parse the table from its raw little-endian DWORDs.
+    pub fn parse(buf: &[u8]) -> Result<Self, SfdpError> {
+        let dwords = buf.len() / 4;
+        if dwords < BASIC_TABLE_MIN_DWORDS {
+            return Err(SfdpError::TableTooShort);
+        }
+        // DWORDs are numbered from 1, as in the standard.
+        let dword = |n: usize| -> Option<u32> {
+            let i = (n - 1) * 4;
+            (n <= dwords).then(|| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]))
+        };
+        let dw1 = dword(1).unwrap();
+        let dw2 = dword(2).unwrap();
+        let dw3 = dword(3).unwrap();
+        let dw4 = dword(4).unwrap();
+        let dw5 = dword(5).unwrap();
+        let dw6 = dword(6).unwrap();
+        let dw7 = dword(7).unwrap();
+        let dw8 = dword(8).unwrap();
+        let dw9 = dword(9).unwrap();
+
+        let density_bits = if dw2 & (1 << 31) == 0 {
+            dw2 as u64 + 1
+        } else {
+            1u64.checked_shl(dw2 & 0x7FFF_FFFF)
+                .ok_or(SfdpError::BadDensity)?
+        };
+
+        let erase_type = |bits: u32| -> Option<EraseType> {
+            let size = bits & 0xFF;
+            // Sizes are powers of two up to 2^31; anything else is garbage.
+            (size != 0 && size < 32).then(|| EraseType {
+                size: 1 << size,
+                opcode: (bits >> 8) as u8,
+            })
+        };
+        let erase_types = [
+            erase_type(dw8),
+            erase_type(dw8 >> 16),
+            erase_type(dw9),
+            erase_type(dw9 >> 16),
+        ];
+
+        let bit = |dw: u32, n: u32| dw & (1 << n) != 0;
+        let fast_read = FastReadModes {
+            fast_read_1_1_2: bit(dw1, 16).then(|| FastRead::parse(dw4 as u16)),
+            fast_read_1_2_2: bit(dw1, 20).then(|| FastRead::parse((dw4 >> 16) as u16)),
+            fast_read_1_1_4: bit(dw1, 22).then(|| FastRead::parse((dw3 >> 16) as u16)),
+            fast_read_1_4_4: bit(dw1, 21).then(|| FastRead::parse(dw3 as u16)),
+            fast_read_2_2_2: bit(dw5, 0).then(|| FastRead::parse((dw6 >> 16) as u16)),
+            fast_read_4_4_4: bit(dw5, 4).then(|| FastRead::parse((dw7 >> 16) as u16)),
+        };
+
+        let address_bytes = match (dw1 >> 17) & 0x3 {
+            0b00 => AddressBytes::ThreeOnly,
+            0b10 => AddressBytes::FourOnly,
+            _ => AddressBytes::ThreeOrFour,
+        };
+
+        // JESD216A moved the page size into DWORD 11; older tables only state
+        // whether the write granularity is at least 64 bytes.
+        let page_size = match dword(11) {
+            Some(dw11) => 1 << ((dw11 >> 4) & 0xF),
+            None if bit(dw1, 2) => 64,
+            None => 1,
+        };
+
+        let four_byte_entry = dword(16)
+            .map(|dw16| FourByteEntry::from_bits_truncate((dw16 >> 24) as u8))
+            .unwrap_or(FourByteEntry::empty());
+
+        Ok(Self {
+            density_bits,
+            erase_types,
+            page_size,
+            fast_read,
+            address_bytes,
+            four_byte_entry,
+        })
+    }
+
+    /// Device capacity in bytes.
+    pub fn capacity(&self) -> u64 {
+        self.density_bits / 8
+    }
+
+    /// The erase type that erases exactly `size` bytes, if any.
+    pub fn erase_type(&self, size: u32) -> Option<EraseType> {
+        self.erase_types
+            .iter()
+            .flatten()
+            .copied()
+            .find(|e| e.size == size)
+    }
+}
+
+/// The parsed SFDP space of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sfdp {
+    pub header: SfdpHeader,
+    pub basic: BasicFlashParameters,
+}
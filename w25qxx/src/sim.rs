@@ -0,0 +1,746 @@
+//! In-memory W25Qxx simulator for host tests.
+//!
+//! [`FlashSim`] implements [`SpiDevice`] by decoding the command stream the
+//! way the real part does: programming can only clear bits, erasing sets them,
+//! WEL is consumed by every program, erase and status write, and BUSY lasts for
+//! a configurable amount of simulated time. Simulated time only advances with
+//! the [`SimDelay`] of the device, `DelayNs` operations and a fixed cost per
+//! transaction, so tests are deterministic.
+//!
+//! The simulator is a cheap handle to shared state: keep a clone to inspect
+//! the array or inject faults after handing the device to a driver.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::vec;
+use std::vec::Vec;
+
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::spi::{self, ErrorKind, ErrorType, Operation, SpiDevice};
+
+use crate::{
+    BlockProtection, FlashInfo, Identification, Lanes, MultiLaneSpiDevice, Status, Status2,
+    Status3, PAGE_SIZE, SECTOR_SIZE, SECURITY_REGISTER_SIZE,
+};
+
+/// Durations of the simulated operations, in nanoseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SimTiming {
+    pub page_program_ns: u64,
+    pub sector_erase_ns: u64,
+    pub block_32k_erase_ns: u64,
+    pub block_erase_ns: u64,
+    pub chip_erase_ns: u64,
+    pub status_write_ns: u64,
+    /// Time for a suspend to take effect (tSUS).
+    pub suspend_latency_ns: u64,
+    /// Time a resumed operation runs before it can be suspended again (tRS).
+    pub resume_to_suspend_ns: u64,
+    /// Time to leave deep power-down, during which commands are ignored (tRES1).
+    pub power_up_ns: u64,
+    /// Time to come back from a software reset, during which commands are ignored (tRST).
+    pub reset_ns: u64,
+    /// Time taken by every transaction, on top of its `DelayNs` operations.
+    pub transaction_ns: u64,
+}
+
+impl Default for SimTiming {
+    fn default() -> Self {
+        const MS: u64 = 1_000_000;
+        Self {
+            page_program_ns: 700_000,
+            sector_erase_ns: 45 * MS,
+            block_32k_erase_ns: 120 * MS,
+            block_erase_ns: 150 * MS,
+            chip_erase_ns: 2500 * MS,
+            status_write_ns: 10 * MS,
+            suspend_latency_ns: 20_000,
+            resume_to_suspend_ns: 20_000,
+            power_up_ns: 3_000,
+            reset_ns: 30_000,
+            transaction_ns: 1_000,
+        }
+    }
+}
+
+/// Error returned for every transaction while the simulated device has no power.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SimError {
+    PoweredOff,
+}
+
+impl spi::Error for SimError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SimOperation {
+    Program,
+    Erase,
+    ChipErase,
+    StatusWrite,
+    Security,
+}
+
+impl SimOperation {
+    fn is_suspendable(self) -> bool {
+        matches!(self, SimOperation::Program | SimOperation::Erase)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Busy {
+    operation: SimOperation,
+    until_ns: u64,
+    suspend_at_ns: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Suspended {
+    operation: SimOperation,
+    remaining_ns: u64,
+}
+
+struct State {
+    info: FlashInfo,
+    jedec_id: [u8; 3],
+    unique_id: u64,
+    sfdp: Vec<u8>,
+    timing: SimTiming,
+
+    array: Vec<u8>,
+    security: [[u8; SECURITY_REGISTER_SIZE]; 3],
+    /// Status registers as stored in non-volatile memory.
+    stored_status: [u8; 3],
+    /// Status registers currently in effect.
+    status: [u8; 3],
+
+    now_ns: u64,
+    wel: bool,
+    volatile_write_enable: bool,
+    reset_enable: bool,
+    four_byte: bool,
+    powered_down: bool,
+    awake_at_ns: u64,
+    busy: Option<Busy>,
+    suspended: Option<Suspended>,
+    resumed_at_ns: Option<u64>,
+
+    powered: bool,
+    power_loss_after: Option<usize>,
+    stuck_bits: Vec<(u32, u8, bool)>,
+    commands: Vec<u8>,
+}
+
+impl State {
+    fn tick(&mut self) {
+        let Some(busy) = self.busy else {
+            return;
+        };
+        match busy.suspend_at_ns {
+            Some(suspend_at) if suspend_at < busy.until_ns => {
+                if self.now_ns >= suspend_at {
+                    self.busy = None;
+                    self.suspended = Some(Suspended {
+                        operation: busy.operation,
+                        remaining_ns: busy.until_ns - suspend_at,
+                    });
+                    self.status[1] |= Status2::SUS.bits();
+                }
+            }
+            _ => {
+                if self.now_ns >= busy.until_ns {
+                    self.busy = None;
+                    self.wel = false;
+                }
+            }
+        }
+    }
+
+    fn start(&mut self, operation: SimOperation, duration_ns: u64) {
+        self.busy = Some(Busy {
+            operation,
+            until_ns: self.now_ns + duration_ns,
+            suspend_at_ns: None,
+        });
+        self.resumed_at_ns = None;
+    }
+
+    fn status1(&self) -> u8 {
+        let mut status = Status::from_bits_retain(self.status[0]);
+        status.set(Status::BUSY, self.busy.is_some());
+        status.set(Status::WEL, self.wel);
+        status.bits()
+    }
+
+    fn status3(&self) -> u8 {
+        let mut status = Status3::from_bits_retain(self.status[2]);
+        status.set(Status3::ADS, self.four_byte);
+        status.bits()
+    }
+
+    fn protection(&self) -> BlockProtection {
+        BlockProtection::from_status(
+            Status::from_bits_retain(self.status[0]),
+            Status2::from_bits_retain(self.status[1]),
+            &self.info,
+        )
+    }
+
+    /// Address and dummy byte counts of `opcode`.
+    fn layout(&self, opcode: u8) -> (usize, usize) {
+        let addr = if self.four_byte { 4 } else { 3 };
+        match opcode {
+            0x03 | 0x02 | 0x20 | 0x52 | 0xD8 | 0x42 | 0x44 => (addr, 0),
+            0x13 | 0x12 | 0x21 | 0x5C | 0xDC => (4, 0),
+            0x0B | 0x3B | 0x6B | 0x48 => (addr, 1),
+            0x0C | 0x3C | 0x6C => (4, 1),
+            0x5A => (3, 1),
+            0x4B => (0, addr + 1),
+            0xAB => (0, 3),
+            _ => (0, 0),
+        }
+    }
+
+    /// Whether the device reacts to `opcode` in its current state.
+    fn accepts(&self, opcode: u8) -> bool {
+        if self.now_ns < self.awake_at_ns {
+            return false;
+        }
+        if self.powered_down {
+            return opcode == 0xAB;
+        }
+        if self.busy.is_some() {
+            return matches!(opcode, 0x05 | 0x35 | 0x15 | 0x75 | 0x66 | 0x99);
+        }
+        true
+    }
+
+    fn address(&self, bytes: &[u8]) -> u32 {
+        let (len, _) = self.layout(bytes[0]);
+        bytes[1..1 + len]
+            .iter()
+            .fold(0, |addr, &b| (addr << 8) | b as u32)
+    }
+
+    /// The byte clocked out at position `bytes.len() - 1` of a command.
+    fn output(&self, bytes: &[u8]) -> u8 {
+        let opcode = bytes[0];
+        let n = bytes.len() - 1;
+        if n == 0 || !self.accepts(opcode) {
+            return 0xFF;
+        }
+        let (addr_len, dummy_len) = self.layout(opcode);
+        let header = addr_len + dummy_len;
+        if n <= header {
+            return 0xFF;
+        }
+        let index = n - 1 - header;
+        match opcode {
+            0x05 => self.status1(),
+            0x35 => self.status[1],
+            0x15 => self.status3(),
+            0x9F => self.jedec_id.get(index).copied().unwrap_or(0xFF),
+            0xAB => self.jedec_id[2] - 1,
+            0x4B => self
+                .unique_id
+                .to_be_bytes()
+                .get(index)
+                .copied()
+                .unwrap_or(0xFF),
+            0x5A => {
+                let addr = self.address(bytes) as usize + index;
+                self.sfdp.get(addr).copied().unwrap_or(0xFF)
+            }
+            0x48 => {
+                let addr = self.address(bytes);
+                match (addr >> 12) as usize {
+                    r @ 1..=3 => {
+                        let offset = (addr as usize + index) % SECURITY_REGISTER_SIZE;
+                        self.security[r - 1][offset]
+                    }
+                    _ => 0xFF,
+                }
+            }
+            0x03 | 0x13 | 0x0B | 0x0C | 0x3B | 0x3C | 0x6B | 0x6C => {
+                let addr = self.address(bytes) as usize + index;
+                self.array[addr % self.array.len()]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    /// Consume up to `len` bytes of the power loss budget, returning how many
+    /// bytes can still be modified before the power goes out.
+    fn spend(&mut self, len: usize) -> usize {
+        match self.power_loss_after {
+            Some(budget) if budget < len => {
+                self.power_loss_after = None;
+                self.powered = false;
+                budget
+            }
+            Some(budget) => {
+                self.power_loss_after = Some(budget - len);
+                len
+            }
+            None => len,
+        }
+    }
+
+    fn apply_stuck_bits(&mut self) {
+        for &(addr, bit, value) in &self.stuck_bits {
+            let byte = &mut self.array[addr as usize];
+            if value {
+                *byte |= 1 << bit;
+            } else {
+                *byte &= !(1 << bit);
+            }
+        }
+    }
+
+    fn program(&mut self, addr: u32, data: &[u8]) {
+        let protection = self.protection();
+        if protection.protects(addr & !(PAGE_SIZE as u32 - 1), PAGE_SIZE, &self.info) {
+            self.wel = false;
+            return;
+        }
+
+        // Data wraps around within the page buffer: only the last 256 bytes land.
+        let base = addr as usize & !(PAGE_SIZE - 1);
+        let mut page = [0xFF; PAGE_SIZE];
+        for (i, &byte) in data.iter().enumerate() {
+            page[(addr as usize + i) % PAGE_SIZE] = byte;
+        }
+        let len = data.len().min(PAGE_SIZE);
+        let allowed = self.spend(len);
+        for i in 0..allowed {
+            let offset = (addr as usize + i) % PAGE_SIZE;
+            self.array[base + offset] &= page[offset];
+        }
+        self.apply_stuck_bits();
+        self.start(SimOperation::Program, self.timing.page_program_ns);
+    }
+
+    fn erase(&mut self, addr: u32, size: usize, duration_ns: u64) {
+        let start = addr as usize & !(size - 1);
+        let protection = self.protection();
+        if start >= self.array.len() || protection.protects(start as u32, size, &self.info) {
+            self.wel = false;
+            return;
+        }
+        let allowed = self.spend(size);
+        self.array[start..start + allowed].fill(0xFF);
+        self.apply_stuck_bits();
+        let operation = if size == self.array.len() {
+            SimOperation::ChipErase
+        } else {
+            SimOperation::Erase
+        };
+        self.start(operation, duration_ns);
+    }
+
+    fn write_status(&mut self, register: usize, value: u8) {
+        let writable = match register {
+            0 => 0b1111_1100,
+            1 => (Status2::SRL | Status2::QE | Status2::CMP).bits(),
+            _ => (Status3::ADP | Status3::WPS | Status3::DRV | Status3::HOLD_RST).bits(),
+        };
+        // Lock bits are one-time programmable: they can be set but never cleared.
+        let otp = match register {
+            1 => (Status2::LB1 | Status2::LB2 | Status2::LB3).bits(),
+            _ => 0,
+        };
+        let update = |old: u8| (old & !writable) | (value & writable) | (old | value) & otp;
+        self.status[register] = update(self.status[register]);
+        if self.volatile_write_enable {
+            self.stored_status[register] |= value & otp;
+        } else {
+            self.stored_status[register] = update(self.stored_status[register]);
+        }
+    }
+
+    /// Execute a command when chip select is released.
+    fn execute(&mut self, bytes: &[u8]) {
+        let opcode = bytes[0];
+        self.commands.push(opcode);
+        if !self.accepts(opcode) {
+            return;
+        }
+        if opcode != 0x99 {
+            self.reset_enable = opcode == 0x66;
+        }
+
+        let (addr_len, dummy_len) = self.layout(opcode);
+        if bytes.len() < 1 + addr_len {
+            return;
+        }
+        let data = bytes.get(1 + addr_len + dummy_len..).unwrap_or(&[]);
+        let writing = self.wel && self.suspended.is_none();
+
+        match opcode {
+            0x06 => self.wel = true,
+            0x04 => self.wel = false,
+            0x50 => self.volatile_write_enable = true,
+            0x01 | 0x31 | 0x11 if !data.is_empty() => {
+                if !(self.wel || self.volatile_write_enable) {
+                    return;
+                }
+                let register = match opcode {
+                    0x01 => 0,
+                    0x31 => 1,
+                    _ => 2,
+                };
+                for (i, &value) in data.iter().take(3 - register).enumerate() {
+                    self.write_status(register + i, value);
+                }
+                if self.volatile_write_enable {
+                    self.volatile_write_enable = false;
+                } else {
+                    let duration = self.timing.status_write_ns;
+                    self.start(SimOperation::StatusWrite, duration);
+                }
+            }
+            0x02 | 0x12 if writing && !data.is_empty() => {
+                let addr = self.address(bytes);
+                self.program(addr, data);
+            }
+            0x20 | 0x21 if writing => {
+                let addr = self.address(bytes);
+                self.erase(addr, SECTOR_SIZE, self.timing.sector_erase_ns);
+            }
+            0x52 | 0x5C if writing => {
+                let addr = self.address(bytes);
+                self.erase(addr, 0x8000, self.timing.block_32k_erase_ns);
+            }
+            0xD8 | 0xDC if writing => {
+                let addr = self.address(bytes);
+                self.erase(addr, 0x10000, self.timing.block_erase_ns);
+            }
+            0xC7 | 0x60 if writing => {
+                let len = self.array.len();
+                self.erase(0, len, self.timing.chip_erase_ns);
+            }
+            0x42 | 0x44 if writing => {
+                let addr = self.address(bytes);
+                let register = (addr >> 12) as usize;
+                let locked = match register {
+                    1 => Status2::LB1,
+                    2 => Status2::LB2,
+                    3 => Status2::LB3,
+                    _ => return,
+                };
+                if self.status[1] & locked.bits() != 0 {
+                    self.wel = false;
+                    return;
+                }
+                let register = &mut self.security[register - 1];
+                if opcode == 0x44 {
+                    register.fill(0xFF);
+                    self.start(SimOperation::Security, self.timing.sector_erase_ns);
+                } else {
+                    for (i, &byte) in data.iter().enumerate() {
+                        register[(addr as usize + i) % SECURITY_REGISTER_SIZE] &= byte;
+                    }
+                    self.start(SimOperation::Security, self.timing.page_program_ns);
+                }
+            }
+            0x75 => {
+                if let Some(busy) = &mut self.busy {
+                    if busy.operation.is_suspendable() && busy.suspend_at_ns.is_none() {
+                        let earliest = self
+                            .resumed_at_ns
+                            .map_or(self.now_ns, |t| t + self.timing.resume_to_suspend_ns)
+                            .max(self.now_ns);
+                        busy.suspend_at_ns = Some(earliest + self.timing.suspend_latency_ns);
+                    }
+                }
+            }
+            0x7A => {
+                if let Some(suspended) = self.suspended.take() {
+                    self.busy = Some(Busy {
+                        operation: suspended.operation,
+                        until_ns: self.now_ns + suspended.remaining_ns,
+                        suspend_at_ns: None,
+                    });
+                    self.resumed_at_ns = Some(self.now_ns);
+                    self.status[1] &= !Status2::SUS.bits();
+                }
+            }
+            0xB7 => self.four_byte = true,
+            0xE9 => self.four_byte = false,
+            0xB9 => self.powered_down = true,
+            0xAB if self.powered_down => {
+                self.powered_down = false;
+                self.awake_at_ns = self.now_ns + self.timing.power_up_ns;
+            }
+            0x99 if self.reset_enable => {
+                self.reset();
+                self.awake_at_ns = self.now_ns + self.timing.reset_ns;
+            }
+            _ => {}
+        }
+    }
+
+    /// Return every volatile bit of state to its power-on value.
+    fn reset(&mut self) {
+        self.status = self.stored_status;
+        self.wel = false;
+        self.volatile_write_enable = false;
+        self.reset_enable = false;
+        self.four_byte = self.stored_status[2] & Status3::ADP.bits() != 0;
+        self.powered_down = false;
+        self.awake_at_ns = 0;
+        self.busy = None;
+        self.suspended = None;
+        self.resumed_at_ns = None;
+    }
+}
+
+/// A simulated W25Qxx device on its own chip select.
+#[derive(Clone)]
+pub struct FlashSim {
+    state: Arc<Mutex<State>>,
+}
+
+impl FlashSim {
+    /// An erased device identifying itself with `jedec_id`.
+    ///
+    /// Panics if the ID does not belong to a supported W25Qxx part.
+    pub fn new(jedec_id: u32) -> Self {
+        let bytes = jedec_id.to_be_bytes();
+        let jedec_id = [bytes[1], bytes[2], bytes[3]];
+        let info = FlashInfo::from_jedec_id(&Identification::from_jedec_id(&jedec_id))
+            .expect("unsupported JEDEC ID");
+        let capacity = info.capacity() as usize;
+        Self {
+            state: Arc::new(Mutex::new(State {
+                info,
+                jedec_id,
+                unique_id: 0xD5_63_84_27_3B_A1_0C_02,
+                sfdp: Vec::new(),
+                timing: SimTiming::default(),
+                array: vec![0xFF; capacity],
+                security: [[0xFF; SECURITY_REGISTER_SIZE]; 3],
+                stored_status: [0; 3],
+                status: [0; 3],
+                now_ns: 0,
+                wel: false,
+                volatile_write_enable: false,
+                reset_enable: false,
+                four_byte: false,
+                powered_down: false,
+                awake_at_ns: 0,
+                busy: None,
+                suspended: None,
+                resumed_at_ns: None,
+                powered: true,
+                power_loss_after: None,
+                stuck_bits: Vec::new(),
+                commands: Vec::new(),
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn with_timing(self, timing: SimTiming) -> Self {
+        self.state().timing = timing;
+        self
+    }
+
+    /// Serve `sfdp` as the SFDP space, e.g. a dump taken from a real part.
+    pub fn with_sfdp(self, sfdp: &[u8]) -> Self {
+        self.state().sfdp = sfdp.to_vec();
+        self
+    }
+
+    pub fn with_unique_id(self, unique_id: u64) -> Self {
+        self.state().unique_id = unique_id;
+        self
+    }
+
+    /// Copy of the whole array.
+    pub fn contents(&self) -> Vec<u8> {
+        self.state().array.clone()
+    }
+
+    /// Copy `len` bytes of the array at `addr`.
+    pub fn read(&self, addr: u32, len: usize) -> Vec<u8> {
+        self.state().array[addr as usize..addr as usize + len].to_vec()
+    }
+
+    /// Overwrite the array at `addr` directly, bypassing NOR semantics.
+    pub fn load(&self, addr: u32, data: &[u8]) {
+        self.state().array[addr as usize..addr as usize + data.len()].copy_from_slice(data);
+    }
+
+    /// Current simulated time.
+    pub fn now_ns(&self) -> u64 {
+        self.state().now_ns
+    }
+
+    /// Advance simulated time, as if the host did something else meanwhile.
+    pub fn advance(&self, ns: u64) {
+        let mut state = self.state();
+        state.now_ns += ns;
+        state.tick();
+    }
+
+    /// A delay that advances the simulated time of this device, for the
+    /// driver to wait on.
+    pub fn delay(&self) -> SimDelay {
+        SimDelay(self.clone())
+    }
+
+    /// Opcodes of all commands received so far, in order.
+    pub fn commands(&self) -> Vec<u8> {
+        self.state().commands.clone()
+    }
+
+    pub fn clear_commands(&self) {
+        self.state().commands.clear();
+    }
+
+    /// Flip one bit of the array, as a retention error would.
+    pub fn flip_bit(&self, addr: u32, bit: u8) {
+        self.state().array[addr as usize] ^= 1 << bit;
+    }
+
+    /// Make one bit of the array stuck at `value`, as a worn-out cell would:
+    /// neither program nor erase can change it from now on.
+    pub fn stick_bit(&self, addr: u32, bit: u8, value: bool) {
+        let mut state = self.state();
+        state.stuck_bits.push((addr, bit, value));
+        state.apply_stuck_bits();
+    }
+
+    /// Cut the power once `bytes` more bytes of the array have been programmed
+    /// or erased. The operation in progress at that point is left incomplete
+    /// and every transaction fails with [`SimError::PoweredOff`] until
+    /// [`power_cycle`](Self::power_cycle).
+    pub fn arm_power_loss(&self, bytes: usize) {
+        self.state().power_loss_after = Some(bytes);
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.state().powered
+    }
+
+    /// Restore power, losing all volatile state.
+    pub fn power_cycle(&self) {
+        let mut state = self.state();
+        state.powered = true;
+        state.power_loss_after = None;
+        state.reset();
+    }
+
+    fn run(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), SimError> {
+        let mut state = self.state();
+        if !state.powered {
+            return Err(SimError::PoweredOff);
+        }
+        state.now_ns += state.timing.transaction_ns;
+        state.tick();
+
+        let mut bytes = Vec::new();
+        let mut clock = |state: &mut State, mosi: u8| {
+            bytes.push(mosi);
+            state.output(&bytes)
+        };
+        for operation in operations.iter_mut() {
+            match operation {
+                Operation::Write(data) => {
+                    for &b in data.iter() {
+                        clock(&mut state, b);
+                    }
+                }
+                Operation::Read(buf) => {
+                    for b in buf.iter_mut() {
+                        *b = clock(&mut state, 0);
+                    }
+                }
+                Operation::Transfer(read, write) => {
+                    for i in 0..read.len().max(write.len()) {
+                        let miso = clock(&mut state, write.get(i).copied().unwrap_or(0));
+                        if let Some(r) = read.get_mut(i) {
+                            *r = miso;
+                        }
+                    }
+                }
+                Operation::TransferInPlace(buf) => {
+                    for b in buf.iter_mut() {
+                        *b = clock(&mut state, *b);
+                    }
+                }
+                Operation::DelayNs(ns) => {
+                    state.now_ns += *ns as u64;
+                    state.tick();
+                }
+            }
+        }
+
+        if !bytes.is_empty() {
+            state.execute(&bytes);
+        }
+        Ok(())
+    }
+}
+
+/// Delay in the simulated time of a [`FlashSim`].
+#[derive(Clone)]
+pub struct SimDelay(FlashSim);
+
+impl DelayNs for SimDelay {
+    async fn delay_ns(&mut self, ns: u32) {
+        self.0.advance(ns as u64);
+    }
+}
+
+impl embedded_hal::delay::DelayNs for SimDelay {
+    fn delay_ns(&mut self, ns: u32) {
+        self.0.advance(ns as u64);
+    }
+}
+
+impl ErrorType for FlashSim {
+    type Error = SimError;
+}
+
+impl SpiDevice for FlashSim {
+    async fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), SimError> {
+        self.run(operations)
+    }
+}
+
+impl embedded_hal::spi::SpiDevice for FlashSim {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), SimError> {
+        self.run(operations)
+    }
+}
+
+impl MultiLaneSpiDevice for FlashSim {
+    async fn read_multi_lane(
+        &mut self,
+        cmd: &[u8],
+        dummy_cycles: u8,
+        lanes: Lanes,
+        buf: &mut [u8],
+    ) -> Result<(), SimError> {
+        let quad_enabled = self.state().status[1] & Status2::QE.bits() != 0;
+        let dummy = [0; 4];
+        let mut operations = [
+            Operation::Write(cmd),
+            Operation::Write(&dummy[..dummy_cycles as usize / 8]),
+            Operation::Read(buf),
+        ];
+        self.run(&mut operations)?;
+        // Without QE, IO2 and IO3 are not driven and the data is garbage.
+        if lanes == Lanes::Quad && !quad_enabled {
+            buf.fill(0xFF);
+        }
+        Ok(())
+    }
+}
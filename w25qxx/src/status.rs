@@ -0,0 +1,241 @@
+use bitflags::bitflags;
+use core::ops::Range;
+
+use crate::{FlashInfo, SECTOR_SIZE};
+
+bitflags! {
+    /// Status register 1 bits.
+    ///
+    /// On W25Q256 and larger bit 5 is `BP3` and bit 6 is `TB`; use
+    /// [`BlockProtection`] to decode the protection bits portably.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Status: u8 {
+        /// Erase or write in progress.
+        const BUSY = 1 << 0;
+        /// Status of the **W**rite **E**nable **L**atch.
+        const WEL = 1 << 1;
+        const BP0 = 1 << 2;
+        const BP1 = 1 << 3;
+        const BP2 = 1 << 4;
+        /// The 3 protection region bits.
+        const PROT = 0b00011100;
+        /// Protect from the **T**op or the **B**ottom of the array.
+        const TB = 1 << 5;
+        /// Protect 4 KiB **sec**tors instead of 64 KiB blocks.
+        const SEC = 1 << 6;
+        /// **S**tatus **R**egister **P**rotect bit 0.
+        const SRP = 1 << 7;
+    }
+}
+
+bitflags! {
+    /// Status register 2 bits.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Status2: u8 {
+        /// **S**tatus **R**egister **L**ock, also known as `SRP1`.
+        const SRL = 1 << 0;
+        /// **Q**uad **E**nable: turns `/WP` and `/HOLD` into data lines.
+        const QE = 1 << 1;
+        /// One-time program lock of security register 1.
+        const LB1 = 1 << 3;
+        /// One-time program lock of security register 2.
+        const LB2 = 1 << 4;
+        /// One-time program lock of security register 3.
+        const LB3 = 1 << 5;
+        /// **C**o**mp**lement the block protection region.
+        const CMP = 1 << 6;
+        /// An erase or program operation is suspended.
+        const SUS = 1 << 7;
+    }
+}
+
+bitflags! {
+    /// Status register 3 bits.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Status3: u8 {
+        /// Current address mode is 4-byte (W25Q256 and larger).
+        const ADS = 1 << 0;
+        /// Power up in 4-byte address mode (W25Q256 and larger).
+        const ADP = 1 << 1;
+        /// **W**rite **P**rotect **S**election: individual block locks
+        /// instead of the block protection bits.
+        const WPS = 1 << 2;
+        /// Output driver strength.
+        const DRV = 0b0110_0000;
+        /// Pin 7 is `/RESET` instead of `/HOLD`.
+        const HOLD_RST = 1 << 7;
+    }
+}
+
+/// Format the names of the flags set in `flags`.
+#[cfg(feature = "defmt")]
+fn format_flags<B: bitflags::Flags>(f: defmt::Formatter<'_>, name: &str, flags: &B) {
+    defmt::write!(f, "{=str}(", name);
+    for (i, (flag, _)) in flags.iter_names().enumerate() {
+        if i != 0 {
+            defmt::write!(f, " | ");
+        }
+        defmt::write!(f, "{=str}", flag);
+    }
+    defmt::write!(f, ")");
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Status {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        format_flags(f, "Status", self)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Status2 {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        format_flags(f, "Status2", self)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Status3 {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        format_flags(f, "Status3", self)
+    }
+}
+
+/// Whether a status register write survives a power cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Persistence {
+    /// Written after Write Enable for Volatile Status Register (`50h`). Takes
+    /// effect immediately and does not wear the status register.
+    Volatile,
+    /// Written after Write Enable (`06h`) and stored in the status register.
+    NonVolatile,
+}
+
+/// Block protection settings, spread over status registers 1 and 2.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockProtection {
+    /// Block protect bits: `BP2..BP0`, or `BP3..BP0` on W25Q256 and larger.
+    pub bp: u8,
+    /// Count the protected region from the bottom of the array instead of the top.
+    pub bottom: bool,
+    /// Protect 4 KiB sectors instead of 64 KiB blocks. Not on W25Q256 and larger.
+    pub sector: bool,
+    /// Protect everything except the region selected by the other fields.
+    pub complement: bool,
+}
+
+/// Whether `info` describes a part with four BP bits and no `SEC` bit.
+fn has_bp3(info: &FlashInfo) -> bool {
+    info.capacity() > 1 << 24
+}
+
+impl BlockProtection {
+    /// No part of the array is protected.
+    pub const NONE: Self = Self {
+        bp: 0,
+        bottom: false,
+        sector: false,
+        complement: false,
+    };
+
+    /// Decode the protection bits of a device described by `info`.
+    pub fn from_status(status: Status, status2: Status2, info: &FlashInfo) -> Self {
+        let bits = status.bits();
+        if has_bp3(info) {
+            Self {
+                bp: (bits >> 2) & 0xF,
+                bottom: bits & (1 << 6) != 0,
+                sector: false,
+                complement: status2.contains(Status2::CMP),
+            }
+        } else {
+            Self {
+                bp: (bits >> 2) & 0x7,
+                bottom: status.contains(Status::TB),
+                sector: status.contains(Status::SEC),
+                complement: status2.contains(Status2::CMP),
+            }
+        }
+    }
+
+    /// Encode these settings into the protection bits of `status` and `status2`.
+    pub fn apply(&self, status: Status, status2: Status2, info: &FlashInfo) -> (Status, Status2) {
+        let bits = if has_bp3(info) {
+            ((self.bp & 0xF) << 2) | ((self.bottom as u8) << 6)
+        } else {
+            ((self.bp & 0x7) << 2) | ((self.bottom as u8) << 5) | ((self.sector as u8) << 6)
+        };
+        let mask = 0b0111_1100;
+        let status = Status::from_bits_retain((status.bits() & !mask) | bits);
+        let mut status2 = status2;
+        status2.set(Status2::CMP, self.complement);
+        (status, status2)
+    }
+
+    /// A setting that protects exactly `len` bytes at the top or bottom of the array.
+    ///
+    /// Returns `None` if the protection tables have no such region.
+    pub fn covering(len: u32, bottom: bool, info: &FlashInfo) -> Option<Self> {
+        let max_bp = if has_bp3(info) { 0xF } else { 0x7 };
+        [false, true]
+            .into_iter()
+            .filter(|&sector| !(sector && has_bp3(info)))
+            .flat_map(|sector| {
+                (0..=max_bp).map(move |bp| Self {
+                    bp,
+                    bottom,
+                    sector,
+                    complement: false,
+                })
+            })
+            .find(|p| p.protected_range(info).len() as u32 == len)
+    }
+
+    /// The address range write-protected by these settings.
+    ///
+    /// Follows the protection tables of the W25Q datasheets: blocks grow from
+    /// `max(64 KiB, capacity / 64)` (64 KiB on W25Q256 and larger) and sectors
+    /// from 4 KiB, doubling with each BP step.
+    pub fn protected_range(&self, info: &FlashInfo) -> Range<u32> {
+        let capacity = info.capacity();
+        let max_bp = if has_bp3(info) { 0xF } else { 0x7 };
+
+        let len = if self.bp == 0 {
+            0
+        } else if self.bp >= max_bp {
+            capacity
+        } else if self.sector && !has_bp3(info) {
+            (SECTOR_SIZE as u32) << (self.bp - 1).min(3)
+        } else {
+            let unit = if has_bp3(info) {
+                0x10000
+            } else {
+                (capacity / 64).max(0x10000)
+            };
+            (unit as u64 * (1 << (self.bp - 1))).min(capacity as u64) as u32
+        };
+
+        let region = if self.bottom {
+            0..len
+        } else {
+            capacity - len..capacity
+        };
+
+        if !self.complement {
+            region
+        } else if region.is_empty() {
+            0..capacity
+        } else if region.start == 0 {
+            region.end..capacity
+        } else {
+            0..region.start
+        }
+    }
+
+    /// Whether any byte in `len` bytes at `addr` is write-protected.
+    pub fn protects(&self, addr: u32, len: usize, info: &FlashInfo) -> bool {
+        let range = self.protected_range(info);
+        let end = addr as u64 + len as u64;
+        len != 0 && (addr as u64) < range.end as u64 && end > range.start as u64
+    }
+}
@@ -0,0 +1,158 @@
+//! The async and blocking drivers run the same test vectors against
+//! identical simulators and must leave the same trace: results, command
+//! stream, simulated time and array contents.
+
+mod common;
+
+use w25qxx::sim::FlashSim;
+use w25qxx::{blocking, crc32, Status};
+
+use common::{block_on, W25Q80};
+
+/// Data written to a freshly erased device.
+struct Vector {
+    addr: u32,
+    data: &'static [u8],
+}
+
+const VECTORS: &[Vector] = &[
+    Vector {
+        addr: 0x0000,
+        data: b"w25qxx",
+    },
+    // Crosses a page boundary.
+    Vector {
+        addr: 0x00FE,
+        data: &[0x01, 0x02, 0x03, 0x04],
+    },
+    // Crosses a sector boundary.
+    Vector {
+        addr: 0x0FFF,
+        data: &[0xA5, 0x5A],
+    },
+    // More than a page.
+    Vector {
+        addr: 0x1180,
+        data: &[0x00; 300],
+    },
+];
+
+/// Range erased before the vectors are written.
+const ERASED: (u32, usize) = (0x0000, 0x2000);
+
+#[derive(Debug, PartialEq)]
+struct Trace {
+    reads: Vec<Vec<u8>>,
+    crc: u32,
+    status: Status,
+    unique_id: u64,
+    commands: Vec<u8>,
+    now_ns: u64,
+    contents: Vec<u8>,
+}
+
+impl Trace {
+    fn finish(
+        sim: &FlashSim,
+        reads: Vec<Vec<u8>>,
+        crc: u32,
+        status: Status,
+        unique_id: u64,
+    ) -> Self {
+        Self {
+            reads,
+            crc,
+            status,
+            unique_id,
+            commands: sim.commands(),
+            now_ns: sim.now_ns(),
+            contents: sim.contents(),
+        }
+    }
+}
+
+fn run_async(sim: &FlashSim) -> Trace {
+    block_on(async {
+        let mut flash = common::driver(sim).await;
+        flash.erase_range(ERASED.0, ERASED.1).await.unwrap();
+        for vector in VECTORS {
+            flash.write(vector.addr, vector.data).await.unwrap();
+        }
+
+        // Reads suspend the erase left running.
+        flash.start_erase_sector(0x3000).await.unwrap();
+        let mut reads = Vec::new();
+        for vector in VECTORS {
+            let mut buf = vec![0; vector.data.len()];
+            flash.read(vector.addr, &mut buf).await.unwrap();
+            reads.push(buf);
+        }
+        flash.wait_done().await.unwrap();
+
+        let crc = flash.crc32_range(ERASED.0, ERASED.1).await.unwrap();
+        let status = flash.read_status().await.unwrap();
+        let unique_id = flash.read_unique_id().await.unwrap();
+        Trace::finish(sim, reads, crc, status, unique_id)
+    })
+}
+
+fn run_blocking(sim: &FlashSim) -> Trace {
+    let mut flash = blocking::W25Qxx::new(sim.clone(), sim.delay()).unwrap();
+    flash.erase_range(ERASED.0, ERASED.1).unwrap();
+    for vector in VECTORS {
+        flash.write(vector.addr, vector.data).unwrap();
+    }
+
+    flash.start_erase_sector(0x3000).unwrap();
+    let mut reads = Vec::new();
+    for vector in VECTORS {
+        let mut buf = vec![0; vector.data.len()];
+        flash.read(vector.addr, &mut buf).unwrap();
+        reads.push(buf);
+    }
+    flash.wait_done().unwrap();
+
+    let crc = flash.crc32_range(ERASED.0, ERASED.1).unwrap();
+    let status = flash.read_status().unwrap();
+    let unique_id = flash.read_unique_id().unwrap();
+    Trace::finish(sim, reads, crc, status, unique_id)
+}
+
+#[test]
+fn vectors_read_back() {
+    let sim = FlashSim::new(W25Q80);
+    let trace = run_async(&sim);
+
+    for (vector, read) in VECTORS.iter().zip(&trace.reads) {
+        assert_eq!(read, vector.data, "at {:#x}", vector.addr);
+    }
+    let erased = sim.read(ERASED.0, ERASED.1);
+    assert_eq!(trace.crc, crc32(&erased));
+    assert_eq!(trace.status, Status::empty());
+}
+
+#[test]
+fn async_and_blocking_agree() {
+    let async_sim = FlashSim::new(W25Q80);
+    let blocking_sim = FlashSim::new(W25Q80);
+    assert_eq!(run_async(&async_sim), run_blocking(&blocking_sim));
+}
+
+#[test]
+fn errors_agree() {
+    let sim = FlashSim::new(W25Q80);
+    let capacity = block_on(common::driver(&sim)).info().capacity();
+
+    let async_error = block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.write(capacity - 1, &[0; 2]).await.unwrap_err()
+    });
+    let mut flash = blocking::W25Qxx::new(sim.clone(), sim.delay()).unwrap();
+    let blocking_error = flash.write(capacity - 1, &[0; 2]).unwrap_err();
+
+    assert!(matches!(
+        async_error,
+        w25qxx::Error::OutOfRange { len: 2, .. }
+    ));
+    assert_eq!(format!("{async_error:?}"), format!("{blocking_error:?}"));
+}
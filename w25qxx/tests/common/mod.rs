@@ -0,0 +1,32 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+use w25qxx::sim::{FlashSim, SimDelay};
+use w25qxx::W25Qxx;
+
+/// JEDEC ID of the W25Q80, 1 MiB.
+pub const W25Q80: u32 = 0xEF4014;
+/// JEDEC ID of the W25Q256, which needs 4-byte addresses past 16 MiB.
+pub const W25Q256: u32 = 0xEF4019;
+
+/// Poll `future` to completion. The simulator is always ready, so a no-op
+/// waker is enough.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+/// An async driver bound to `sim`, waiting in its simulated time.
+pub async fn driver(sim: &FlashSim) -> W25Qxx<FlashSim, SimDelay> {
+    W25Qxx::new(sim.clone(), sim.delay()).await.unwrap()
+}
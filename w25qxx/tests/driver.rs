@@ -0,0 +1,433 @@
+//! The async driver against the simulator: NOR semantics, timing, addressing
+//! and injected faults.
+
+mod common;
+
+use embedded_hal_async::spi::{Operation as SpiOperation, SpiDevice};
+use w25qxx::sim::{FlashSim, SimError};
+use w25qxx::{
+    AddressMode, Error, Persistence, ReadMode, SecurityRegister, Status, Status2, PAGE_SIZE,
+    SECTOR_SIZE,
+};
+
+use common::{block_on, W25Q256, W25Q80};
+
+const MS: u64 = 1_000_000;
+
+#[test]
+fn probes_geometry() {
+    let sim = FlashSim::new(W25Q80);
+    let flash = block_on(common::driver(&sim));
+    assert_eq!(flash.info().capacity(), 1 << 20);
+    assert_eq!(flash.address_mode(), AddressMode::ThreeByte);
+
+    let sim = FlashSim::new(W25Q256);
+    let flash = block_on(common::driver(&sim));
+    assert_eq!(flash.info().capacity(), 32 << 20);
+    assert_eq!(flash.address_mode(), AddressMode::FourByte);
+}
+
+#[test]
+fn program_only_clears_bits() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.write(0x10, &[0xF0, 0xAA]).await.unwrap();
+        flash.write(0x10, &[0x0F, 0xFF]).await.unwrap();
+
+        let mut buf = [0; 2];
+        flash.read(0x10, &mut buf).await.unwrap();
+        assert_eq!(buf, [0x00, 0xAA]);
+
+        // Only an erase sets them again, a whole sector at a time.
+        flash.erase_sector(0x10).await.unwrap();
+        flash.read(0x10, &mut buf).await.unwrap();
+        assert_eq!(buf, [0xFF; 2]);
+    });
+}
+
+#[test]
+fn writes_are_split_on_pages() {
+    let sim = FlashSim::new(W25Q80);
+    let data: Vec<u8> = (0..600).map(|i| i as u8).collect();
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.write(0x1F0, &data).await.unwrap();
+    });
+    assert_eq!(sim.read(0x1F0, data.len()), data);
+    // One program per page touched.
+    let programs = sim.commands().iter().filter(|&&c| c == 0x02).count();
+    assert_eq!(programs, 4);
+}
+
+#[test]
+fn page_program_wraps_within_the_page() {
+    let mut sim = FlashSim::new(W25Q80);
+    let data: Vec<u8> = (0..PAGE_SIZE as u32 + 4).map(|i| i as u8).collect();
+    block_on(async {
+        sim.write(&[0x06]).await.unwrap();
+        let cmd = [0x02, 0x00, 0x01, 0x00];
+        sim.transaction(&mut [SpiOperation::Write(&cmd), SpiOperation::Write(&data)])
+            .await
+            .unwrap();
+    });
+    // The first four bytes were overwritten by the last four.
+    let page = sim.read(0x100, PAGE_SIZE);
+    assert_eq!(page[..4], data[PAGE_SIZE..]);
+    assert_eq!(page[4..], data[4..PAGE_SIZE]);
+    assert_eq!(sim.read(0x200, 4), [0xFF; 4]);
+}
+
+#[test]
+fn write_enable_is_consumed() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.write(0, &[0]).await.unwrap();
+        assert!(!flash.read_status().await.unwrap().contains(Status::WEL));
+        flash.erase_sector(0).await.unwrap();
+        assert!(!flash.read_status().await.unwrap().contains(Status::WEL));
+    });
+    // Every program and erase was preceded by its own Write Enable.
+    let commands = sim.commands();
+    for (i, &command) in commands.iter().enumerate() {
+        if matches!(command, 0x02 | 0x20) {
+            assert_eq!(commands[i - 1], 0x06);
+        }
+    }
+}
+
+#[test]
+fn busy_lasts_for_the_operation() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.start_erase_sector(0).await.unwrap();
+        assert!(flash.read_status().await.unwrap().contains(Status::BUSY));
+        sim.advance(40 * MS);
+        assert!(flash.read_status().await.unwrap().contains(Status::BUSY));
+        sim.advance(5 * MS);
+        assert!(!flash.read_status().await.unwrap().contains(Status::BUSY));
+    });
+}
+
+#[test]
+fn polls_back_off() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        sim.clear_commands();
+        let before = sim.now_ns();
+        flash.erase_sector(0).await.unwrap();
+        let elapsed = sim.now_ns() - before;
+
+        // Noticed within half the typical time after it finished.
+        assert!((45 * MS..45 * MS + 45 * MS / 2).contains(&elapsed));
+    });
+    let polls = sim.commands().iter().filter(|&&c| c == 0x05).count();
+    assert!(polls <= 6, "{polls} polls");
+}
+
+#[test]
+fn timeout() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        let result = flash
+            .start_erase_block(0)
+            .await
+            .unwrap()
+            .with_timeout(10 * MS)
+            .wait()
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::Timeout {
+                timeout_ns: 10_000_000,
+                ..
+            })
+        ));
+        // The driver still knows about it and waits it out next time.
+        flash.wait_done().await.unwrap();
+        assert_eq!(flash.in_flight(), None);
+    });
+}
+
+#[test]
+fn erase_range_uses_largest_erases() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        sim.clear_commands();
+        // One sector, a half block, a block and two more sectors.
+        flash
+            .erase_range(0x7000, 0x1000 + 0x8000 + 0x10000 + 0x2000)
+            .await
+            .unwrap();
+        let erases: Vec<u8> = sim
+            .commands()
+            .into_iter()
+            .filter(|c| matches!(c, 0x20 | 0x52 | 0xD8 | 0xC7))
+            .collect();
+        assert_eq!(erases, [0x20, 0x52, 0xD8, 0x20, 0x20]);
+
+        assert!(matches!(
+            flash.erase_range(0x800, SECTOR_SIZE).await,
+            Err(Error::Unaligned { addr: 0x800, .. })
+        ));
+    });
+}
+
+#[test]
+fn out_of_range() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        let mut buf = [0; 2];
+        assert!(matches!(
+            flash.read(0xF_FFFF, &mut buf).await,
+            Err(Error::OutOfRange {
+                addr: 0xF_FFFF,
+                len: 2
+            })
+        ));
+        assert!(matches!(
+            flash.write(0x10_0000, &[0]).await,
+            Err(Error::OutOfRange { .. })
+        ));
+    });
+}
+
+#[test]
+fn four_byte_addresses() {
+    let sim = FlashSim::new(W25Q256);
+    let addr = 0x01FF_FF00;
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.erase_sector(addr).await.unwrap();
+        flash.write(addr, b"top").await.unwrap();
+        let mut buf = [0; 3];
+        flash.read(addr, &mut buf).await.unwrap();
+        assert_eq!(&buf, b"top");
+    });
+    assert_eq!(sim.read(addr, 3), b"top");
+    // The dedicated 4-byte opcodes.
+    let commands = sim.commands();
+    assert!(commands.contains(&0x21) && commands.contains(&0x12) && commands.contains(&0x13));
+}
+
+#[test]
+fn read_modes() {
+    let sim = FlashSim::new(W25Q80);
+    sim.load(0x400, b"wavetable");
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        let mut buf = [0; 9];
+        flash.set_read_mode(ReadMode::Fast);
+        flash.read(0x400, &mut buf).await.unwrap();
+        assert_eq!(&buf, b"wavetable");
+
+        flash.read_dual_output(0x400, &mut buf).await.unwrap();
+        assert_eq!(&buf, b"wavetable");
+
+        // Without QE the data lines are not driven.
+        flash.read_quad_output(0x400, &mut buf).await.unwrap();
+        assert_eq!(buf, [0xFF; 9]);
+        flash
+            .set_quad_enable(true, Persistence::Volatile)
+            .await
+            .unwrap();
+        flash.read_quad_output(0x400, &mut buf).await.unwrap();
+        assert_eq!(&buf, b"wavetable");
+    });
+    let commands = sim.commands();
+    assert!(commands.contains(&0x0B) && commands.contains(&0x3B) && commands.contains(&0x6B));
+}
+
+#[test]
+fn verify_catches_stuck_bits() {
+    let sim = FlashSim::new(W25Q80);
+    sim.stick_bit(0x1003, 0, true);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.set_verify(true);
+        flash.write(0x1000, &[0x00; 2]).await.unwrap();
+        assert!(matches!(
+            flash.write(0x1002, &[0x00; 2]).await,
+            Err(Error::VerifyFailed {
+                addr: 0x1003,
+                expected: 0x00,
+                found: 0x01
+            })
+        ));
+
+        sim.stick_bit(0x2010, 7, false);
+        flash.erase_sector(0x2000).await.unwrap();
+        assert!(matches!(
+            flash.verify_erased(0x2000, SECTOR_SIZE).await,
+            Err(Error::VerifyFailed {
+                addr: 0x2010,
+                expected: 0xFF,
+                found: 0x7F
+            })
+        ));
+    });
+}
+
+#[test]
+fn crc_catches_flipped_bits() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.write(0x3000, &[0x42; 1000]).await.unwrap();
+        let crc = flash.crc32_range(0x3000, 1000).await.unwrap();
+        assert_eq!(crc, w25qxx::crc32(&[0x42; 1000]));
+
+        sim.flip_bit(0x3123, 4);
+        assert_ne!(flash.crc32_range(0x3000, 1000).await.unwrap(), crc);
+    });
+}
+
+#[test]
+fn power_loss_mid_program() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        sim.arm_power_loss(100);
+        let result = flash.write(0x0000, &[0x00; PAGE_SIZE]).await;
+        assert!(matches!(result, Err(Error::Spi(SimError::PoweredOff))));
+    });
+    assert!(!sim.is_powered());
+
+    // Only what was programmed before the cut landed.
+    sim.power_cycle();
+    assert_eq!(sim.read(0, 100), [0x00; 100]);
+    assert_eq!(sim.read(100, PAGE_SIZE - 100), [0xFF; PAGE_SIZE - 100]);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        assert_eq!(flash.read_status().await.unwrap(), Status::empty());
+    });
+}
+
+#[test]
+fn recovers_from_a_busy_device() {
+    let sim = FlashSim::new(W25Q80);
+    sim.load(0x1000, &[0x00; 4]);
+    block_on(async {
+        // As after a reset of the MCU alone, mid-erase.
+        common::driver(&sim)
+            .await
+            .start_erase_block(0x0000)
+            .await
+            .unwrap();
+        sim.clear_commands();
+
+        let mut flash = common::driver(&sim).await;
+        assert!(sim.commands().contains(&0x99));
+        assert_eq!(flash.in_flight(), None);
+        assert!(!flash.read_status().await.unwrap().contains(Status::BUSY));
+    });
+}
+
+#[test]
+fn power_down_and_wake() {
+    let sim = FlashSim::new(W25Q80);
+    sim.load(0, b"asleep");
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.power_down().await.unwrap();
+        assert!(flash.is_powered_down());
+
+        // The next command wakes the device and waits out tRES1.
+        sim.clear_commands();
+        let mut buf = [0; 6];
+        flash.read(0, &mut buf).await.unwrap();
+        assert_eq!(&buf, b"asleep");
+        assert!(!flash.is_powered_down());
+        assert_eq!(sim.commands(), [0xAB, 0x03]);
+    });
+}
+
+#[test]
+fn reset_restores_power_on_state() {
+    let sim = FlashSim::new(W25Q256);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash
+            .set_quad_enable(true, Persistence::Volatile)
+            .await
+            .unwrap();
+        flash.reset().await.unwrap();
+        assert_eq!(flash.address_mode(), AddressMode::ThreeByte);
+        assert!(!flash.read_status2().await.unwrap().contains(Status2::QE));
+
+        // recover() puts the driver back in 4-byte mode.
+        flash.recover().await.unwrap();
+        assert_eq!(flash.address_mode(), AddressMode::FourByte);
+    });
+}
+
+#[test]
+fn unique_id() {
+    let sim = FlashSim::new(W25Q80).with_unique_id(0x0123_4567_89AB_CDEF);
+    let id = block_on(async { common::driver(&sim).await.read_unique_id().await }).unwrap();
+    assert_eq!(id, 0x0123_4567_89AB_CDEF);
+
+    let sim = FlashSim::new(W25Q256).with_unique_id(0x0123_4567_89AB_CDEF);
+    let id = block_on(async { common::driver(&sim).await.read_unique_id().await }).unwrap();
+    assert_eq!(id, 0x0123_4567_89AB_CDEF);
+}
+
+#[test]
+fn security_registers() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        let register = SecurityRegister::Two;
+        flash
+            .program_security_register(register, 0x10, b"serial")
+            .await
+            .unwrap();
+        let mut buf = [0; 6];
+        flash
+            .read_security_register(register, 0x10, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(&buf, b"serial");
+
+        flash.lock_security_register(register).await.unwrap();
+        assert!(flash.is_security_register_locked(register).await.unwrap());
+        assert!(matches!(
+            flash.erase_security_register(register).await,
+            Err(Error::WriteProtected { .. })
+        ));
+        flash
+            .read_security_register(register, 0x10, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(&buf, b"serial");
+        assert!(!flash
+            .is_security_register_locked(SecurityRegister::One)
+            .await
+            .unwrap());
+    });
+}
+
+#[cfg(feature = "time")]
+#[test]
+fn auto_sleep() {
+    use embassy_time::Duration;
+
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        assert_eq!(flash.idle_deadline(), None);
+        assert!(!flash.sleep_if_idle().await.unwrap());
+
+        flash.set_auto_sleep(Some(Duration::from_ticks(0)));
+        assert!(flash.idle_deadline().is_some());
+        assert!(flash.sleep_if_idle().await.unwrap());
+        assert!(flash.is_powered_down());
+        assert_eq!(flash.idle_deadline(), None);
+    });
+}
@@ -0,0 +1,212 @@
+//! SFDP parsing, from dumps and through the simulator.
+
+mod common;
+
+use w25qxx::sfdp::{
+    AddressBytes, BasicFlashParameters, EraseType, FastRead, FourByteEntry, ParameterHeader,
+    SfdpError, SfdpHeader, HEADER_LEN,
+};
+use w25qxx::sim::FlashSim;
+use w25qxx::{Error, FlashInfo, Identification};
+
+use common::{block_on, W25Q80};
+
+/// SFDP space of a W25Q128: one parameter header, pointing to a 16 DWORD
+/// Basic Flash Parameter Table at 0x80.
+fn w25q128_dump() -> Vec<u8> {
+    let mut dump = vec![0xFF; 0xC0];
+    dump[..16].copy_from_slice(&[
+        0x53, 0x46, 0x44, 0x50, 0x05, 0x01, 0x00, 0xFF, //
+        0x00, 0x05, 0x01, 0x10, 0x80, 0x00, 0x00, 0xFF,
+    ]);
+    dump[0x80..].copy_from_slice(&[
+        0xE5, 0x20, 0xF9, 0xFF, 0xFF, 0xFF, 0xFF, 0x07, 0x44, 0xEB, 0x08, 0x6B, 0x08, 0x3B, 0x42,
+        0xBB, 0xFE, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xFF, 0xFF, 0x40, 0xEB, 0x0C, 0x20,
+        0x0F, 0x52, 0x10, 0xD8, 0x00, 0x00, 0x36, 0x02, 0xA6, 0x00, 0x82, 0xEA, 0x14, 0xC9, 0xE9,
+        0x63, 0x76, 0x33, 0x7A, 0x75, 0x7A, 0x75, 0xF7, 0xA2, 0xD5, 0x5C, 0x19, 0xF7, 0x4D, 0xFF,
+        0xE9, 0x30, 0xF8, 0x80,
+    ]);
+    dump
+}
+
+/// The Basic Flash Parameter Table of `dump`, as located by its first header.
+fn basic_table(dump: &[u8]) -> &[u8] {
+    let header = ParameterHeader::parse(dump[8..16].try_into().unwrap());
+    let start = header.pointer as usize;
+    &dump[start..start + header.length as usize * 4]
+}
+
+fn header(dump: &[u8]) -> [u8; HEADER_LEN] {
+    dump[..HEADER_LEN].try_into().unwrap()
+}
+
+#[test]
+fn parses_w25q128_dump() {
+    let dump = w25q128_dump();
+
+    let header = SfdpHeader::parse(&header(&dump)).unwrap();
+    assert_eq!((header.major, header.minor), (1, 5));
+    assert_eq!(header.parameter_headers, 1);
+
+    let parameter_header = ParameterHeader::parse(dump[8..16].try_into().unwrap());
+    assert!(parameter_header.is_basic_flash());
+    assert_eq!(parameter_header.length, 16);
+    assert_eq!(parameter_header.pointer, 0x80);
+
+    let basic = BasicFlashParameters::parse(basic_table(&dump)).unwrap();
+    assert_eq!(basic.capacity(), 16 << 20);
+    assert_eq!(basic.page_size, 256);
+    assert_eq!(basic.address_bytes, AddressBytes::ThreeOnly);
+    assert_eq!(basic.four_byte_entry, FourByteEntry::empty());
+    assert_eq!(
+        basic.erase_types,
+        [
+            Some(EraseType {
+                size: 0x1000,
+                opcode: 0x20
+            }),
+            Some(EraseType {
+                size: 0x8000,
+                opcode: 0x52
+            }),
+            Some(EraseType {
+                size: 0x10000,
+                opcode: 0xD8
+            }),
+            None,
+        ]
+    );
+    assert_eq!(
+        basic.fast_read.fast_read_1_1_4,
+        Some(FastRead {
+            opcode: 0x6B,
+            dummy_cycles: 8,
+            mode_cycles: 0
+        })
+    );
+    assert_eq!(
+        basic.fast_read.fast_read_1_4_4,
+        Some(FastRead {
+            opcode: 0xEB,
+            dummy_cycles: 4,
+            mode_cycles: 2
+        })
+    );
+    assert_eq!(basic.fast_read.fast_read_2_2_2, None);
+}
+
+#[test]
+fn geometry_from_dump() {
+    let dump = w25q128_dump();
+    let basic = BasicFlashParameters::parse(basic_table(&dump)).unwrap();
+
+    // Any vendor with the W25Q page, sector and block layout is accepted.
+    let id = Identification::from_jedec_id(&[0xC2, 0x20, 0x18]);
+    let info = FlashInfo::from_sfdp(&id, &basic).unwrap();
+    assert_eq!(info.capacity(), 16 << 20);
+
+    let mut other_page = basic;
+    other_page.page_size = 512;
+    assert!(FlashInfo::from_sfdp(&id, &other_page).is_none());
+}
+
+#[test]
+fn jesd216_table_without_page_size() {
+    let dump = w25q128_dump();
+    // The original 9 DWORD table only states a write granularity of 64 bytes.
+    let basic = BasicFlashParameters::parse(&basic_table(&dump)[..9 * 4]).unwrap();
+    assert_eq!(basic.page_size, 64);
+    assert_eq!(basic.capacity(), 16 << 20);
+
+    assert_eq!(
+        BasicFlashParameters::parse(&basic_table(&dump)[..8 * 4]),
+        Err(SfdpError::TableTooShort)
+    );
+}
+
+#[test]
+fn rejects_garbage() {
+    let mut dump = w25q128_dump();
+    dump[0] = 0x00;
+    assert_eq!(
+        SfdpHeader::parse(&header(&dump)),
+        Err(SfdpError::BadSignature)
+    );
+
+    // Density given as 2^N bits with N too large for the density field.
+    let mut table = basic_table(&w25q128_dump()).to_vec();
+    table[4..8].copy_from_slice(&0x8000_0040u32.to_le_bytes());
+    assert_eq!(
+        BasicFlashParameters::parse(&table),
+        Err(SfdpError::BadDensity)
+    );
+
+    // Erase sizes past 2^31 bytes are ignored rather than overflowing.
+    let mut table = basic_table(&w25q128_dump()).to_vec();
+    table[28] = 0x40;
+    let basic = BasicFlashParameters::parse(&table).unwrap();
+    assert_eq!(basic.erase_types[0], None);
+}
+
+#[test]
+fn most_parameter_headers() {
+    let mut dump = w25q128_dump();
+    // NPH is the number of headers minus one, so 0xFF means 256 headers.
+    dump[6] = 0xFF;
+    assert_eq!(
+        SfdpHeader::parse(&header(&dump)).unwrap().parameter_headers,
+        256
+    );
+
+    // The blank headers past the dump are skipped.
+    let sim = FlashSim::new(W25Q80).with_sfdp(&dump);
+    let sfdp = block_on(async { common::driver(&sim).await.read_sfdp().await }).unwrap();
+    assert_eq!(sfdp.header.parameter_headers, 256);
+    assert_eq!(sfdp.basic.capacity(), 16 << 20);
+}
+
+#[test]
+fn reads_dump_from_device() {
+    let dump = w25q128_dump();
+    let sim = FlashSim::new(W25Q80).with_sfdp(&dump);
+    let sfdp = block_on(async { common::driver(&sim).await.read_sfdp().await }).unwrap();
+
+    assert_eq!(sfdp.header, SfdpHeader::parse(&header(&dump)).unwrap());
+    assert_eq!(
+        sfdp.basic,
+        BasicFlashParameters::parse(basic_table(&dump)).unwrap()
+    );
+}
+
+#[test]
+fn picks_newest_basic_table() {
+    let mut dump = w25q128_dump();
+    dump.resize(0x100, 0xFF);
+    // Two headers: the original 1.0 table at 0xC0, then the 1.5 table at 0x80.
+    dump[6] = 0x01;
+    dump[8..24].copy_from_slice(&[
+        0x00, 0x00, 0x01, 0x09, 0xC0, 0x00, 0x00, 0xFF, //
+        0x00, 0x05, 0x01, 0x10, 0x80, 0x00, 0x00, 0xFF,
+    ]);
+    let old = basic_table(&w25q128_dump())[..9 * 4].to_vec();
+    dump[0xC0..0xC0 + old.len()].copy_from_slice(&old);
+
+    let sim = FlashSim::new(W25Q80).with_sfdp(&dump);
+    let sfdp = block_on(async { common::driver(&sim).await.read_sfdp().await }).unwrap();
+    assert_eq!(sfdp.header.parameter_headers, 2);
+    assert_eq!(sfdp.basic.page_size, 256);
+}
+
+#[test]
+fn missing_sfdp() {
+    let sim = FlashSim::new(W25Q80);
+    let result = block_on(async { common::driver(&sim).await.read_sfdp().await });
+    assert!(matches!(result, Err(Error::Sfdp(SfdpError::BadSignature))));
+
+    let mut dump = w25q128_dump();
+    // The only header describes some other table.
+    dump[15] = 0x01;
+    let sim = FlashSim::new(W25Q80).with_sfdp(&dump);
+    let result = block_on(async { common::driver(&sim).await.read_sfdp().await });
+    assert!(matches!(result, Err(Error::Sfdp(SfdpError::NoBasicTable))));
+}
@@ -0,0 +1,277 @@
+//! Status registers and block protection against the simulated registers.
+
+mod common;
+
+use w25qxx::sim::FlashSim;
+use w25qxx::{BlockProtection, Error, Persistence, Status, Status2, Status3};
+
+use common::{block_on, W25Q256, W25Q80};
+
+const MIB: u32 = 1 << 20;
+
+#[test]
+fn non_volatile_writes_survive_power_cycle() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        let before = sim.now_ns();
+        flash
+            .write_status(Status::BP0 | Status::TB, Persistence::NonVolatile)
+            .await
+            .unwrap();
+        flash
+            .write_status3(Status3::WPS, Persistence::NonVolatile)
+            .await
+            .unwrap();
+        // Each write waits out tW.
+        assert!(sim.now_ns() - before >= 2 * 10_000_000);
+        assert!(!flash.read_status().await.unwrap().contains(Status::BUSY));
+    });
+    // Write Enable, not the volatile one.
+    assert!(sim.commands().contains(&0x06));
+    assert!(!sim.commands().contains(&0x50));
+
+    sim.power_cycle();
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        assert_eq!(flash.read_status().await.unwrap(), Status::BP0 | Status::TB);
+        assert!(flash.read_status3().await.unwrap().contains(Status3::WPS));
+    });
+}
+
+#[test]
+fn volatile_writes_are_lost_on_power_cycle() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        let before = sim.now_ns();
+        flash
+            .set_quad_enable(true, Persistence::Volatile)
+            .await
+            .unwrap();
+        // No tW for volatile writes: only the cost of the transactions.
+        assert!(sim.now_ns() - before < 1_000_000);
+        assert!(flash.read_status2().await.unwrap().contains(Status2::QE));
+    });
+    assert!(sim.commands().contains(&0x50));
+
+    sim.power_cycle();
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        assert!(!flash.read_status2().await.unwrap().contains(Status2::QE));
+    });
+}
+
+#[test]
+fn read_only_bits_are_ignored() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash
+            .write_status(
+                Status::BUSY | Status::WEL | Status::BP1,
+                Persistence::Volatile,
+            )
+            .await
+            .unwrap();
+        assert_eq!(flash.read_status().await.unwrap(), Status::BP1);
+    });
+}
+
+#[test]
+fn protected_ranges_of_small_parts() {
+    let sim = FlashSim::new(W25Q80);
+    let flash = block_on(common::driver(&sim));
+    let info = flash.info();
+    let protection = |bp, bottom, sector, complement| BlockProtection {
+        bp,
+        bottom,
+        sector,
+        complement,
+    };
+
+    assert_eq!(BlockProtection::NONE.protected_range(info), MIB..MIB);
+    // Blocks of 64 KiB from the top, doubling with each step.
+    assert_eq!(
+        protection(1, false, false, false).protected_range(info),
+        0xF_0000..MIB
+    );
+    assert_eq!(
+        protection(3, false, false, false).protected_range(info),
+        0xC_0000..MIB
+    );
+    assert_eq!(
+        protection(2, true, false, false).protected_range(info),
+        0..0x2_0000
+    );
+    assert_eq!(
+        protection(7, false, false, false).protected_range(info),
+        0..MIB
+    );
+    // Sectors of 4 KiB, capped at 32 KiB.
+    assert_eq!(
+        protection(1, true, true, false).protected_range(info),
+        0..0x1000
+    );
+    assert_eq!(
+        protection(6, false, true, false).protected_range(info),
+        0xF_8000..MIB
+    );
+    // The complement protects everything else.
+    assert_eq!(
+        protection(1, true, true, true).protected_range(info),
+        0x1000..MIB
+    );
+    assert_eq!(
+        protection(0, false, false, true).protected_range(info),
+        0..MIB
+    );
+}
+
+#[test]
+fn protected_ranges_of_large_parts() {
+    let sim = FlashSim::new(W25Q256);
+    let flash = block_on(common::driver(&sim));
+    let info = flash.info();
+    let capacity = info.capacity();
+
+    // Four BP bits, always in 64 KiB blocks, and TB moves to bit 6.
+    let top = BlockProtection {
+        bp: 9,
+        ..BlockProtection::NONE
+    };
+    assert_eq!(top.protected_range(info), capacity - 16 * MIB..capacity);
+    let bottom = BlockProtection {
+        bp: 1,
+        bottom: true,
+        ..BlockProtection::NONE
+    };
+    assert_eq!(bottom.protected_range(info), 0..0x1_0000);
+
+    let (status, status2) = bottom.apply(Status::empty(), Status2::empty(), info);
+    assert_eq!(status.bits(), 0b0100_0100);
+    assert_eq!(BlockProtection::from_status(status, status2, info), bottom);
+}
+
+#[test]
+fn covering_finds_exact_regions() {
+    let sim = FlashSim::new(W25Q80);
+    let flash = block_on(common::driver(&sim));
+    let info = flash.info();
+
+    let calibration = BlockProtection::covering(0x2000, true, info).unwrap();
+    assert!(calibration.sector);
+    assert_eq!(calibration.protected_range(info), 0..0x2000);
+    assert_eq!(
+        BlockProtection::covering(0x4_0000, false, info)
+            .unwrap()
+            .protected_range(info),
+        0xC_0000..MIB
+    );
+    assert!(BlockProtection::covering(0x3000, true, info).is_none());
+
+    assert!(calibration.protects(0x1FFF, 1, info));
+    assert!(!calibration.protects(0x2000, 0x1000, info));
+    assert!(!calibration.protects(0x1000, 0, info));
+}
+
+#[test]
+fn set_block_protection_round_trips() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash
+            .set_quad_enable(true, Persistence::NonVolatile)
+            .await
+            .unwrap();
+
+        let protection = BlockProtection {
+            bp: 2,
+            bottom: true,
+            sector: true,
+            complement: true,
+        };
+        flash
+            .set_block_protection(protection, Persistence::NonVolatile)
+            .await
+            .unwrap();
+        assert_eq!(flash.read_block_protection().await.unwrap(), protection);
+        assert_eq!(protection.protected_range(flash.info()), 0x2000..MIB);
+        // The other bits of the registers are left alone.
+        assert!(flash.read_status2().await.unwrap().contains(Status2::QE));
+
+        flash
+            .set_block_protection(BlockProtection::NONE, Persistence::NonVolatile)
+            .await
+            .unwrap();
+        assert_eq!(
+            flash.read_block_protection().await.unwrap(),
+            BlockProtection::NONE
+        );
+        assert!(flash.read_status2().await.unwrap().contains(Status2::QE));
+    });
+}
+
+#[test]
+fn unchanged_protection_skips_the_write() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        sim.clear_commands();
+        flash
+            .set_block_protection(BlockProtection::NONE, Persistence::NonVolatile)
+            .await
+            .unwrap();
+    });
+    assert_eq!(sim.commands(), [0x05, 0x35]);
+}
+
+#[test]
+fn protected_writes_fail() {
+    let sim = FlashSim::new(W25Q80);
+    sim.load(0x1000, &[0x00; 4]);
+    sim.load(0x3000, &[0x00; 4]);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        let calibration = BlockProtection::covering(0x2000, true, flash.info()).unwrap();
+        flash
+            .set_block_protection(calibration, Persistence::Volatile)
+            .await
+            .unwrap();
+        sim.clear_commands();
+
+        // Refused before anything reaches the device.
+        assert!(matches!(
+            flash.write(0x1FFE, &[0x12; 4]).await,
+            Err(Error::WriteProtected {
+                addr: 0x1FFE,
+                len: 4
+            })
+        ));
+        assert!(matches!(
+            flash.erase_sector(0x1800).await,
+            Err(Error::WriteProtected {
+                addr: 0x1000,
+                len: 0x1000
+            })
+        ));
+        assert!(matches!(
+            flash.erase_range(0x0000, 0x4000).await,
+            Err(Error::WriteProtected { .. })
+        ));
+        assert!(matches!(
+            flash.erase_all().await,
+            Err(Error::WriteProtected { .. })
+        ));
+        for opcode in [0x06, 0x02, 0x20, 0x52, 0xD8, 0xC7] {
+            assert!(!sim.commands().contains(&opcode));
+        }
+
+        // Right past the protected sectors.
+        flash.write(0x2000, &[0x34; 4]).await.unwrap();
+        flash.erase_sector(0x3000).await.unwrap();
+    });
+    assert_eq!(sim.read(0x1000, 4), [0x00; 4]);
+    assert_eq!(sim.read(0x2000, 4), [0x34; 4]);
+    assert_eq!(sim.read(0x3000, 4), [0xFF; 4]);
+}
@@ -0,0 +1,162 @@
+//! Erase and program suspend against the simulated suspend timing.
+
+mod common;
+
+use w25qxx::sim::{FlashSim, SimTiming};
+use w25qxx::{Operation, Status, Status2};
+
+use common::{block_on, W25Q80};
+
+const MS: u64 = 1_000_000;
+const SUSPEND: u8 = 0x75;
+const RESUME: u8 = 0x7A;
+const READ: u8 = 0x03;
+
+#[test]
+fn read_suspends_an_erase() {
+    let sim = FlashSim::new(W25Q80);
+    sim.load(0x0000, b"calibration");
+    sim.load(0x1000, &[0x00; 16]);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.start_erase_sector(0x1000).await.unwrap();
+
+        sim.clear_commands();
+        let mut buf = [0; 11];
+        flash.read(0x0000, &mut buf).await.unwrap();
+        assert_eq!(&buf, b"calibration");
+
+        // Suspended around the read and resumed right after it.
+        let commands = sim.commands();
+        let suspend = commands.iter().position(|&c| c == SUSPEND).unwrap();
+        let read = commands.iter().position(|&c| c == READ).unwrap();
+        let resume = commands.iter().position(|&c| c == RESUME).unwrap();
+        assert!(suspend < read && read < resume);
+        assert_eq!(flash.in_flight(), Some(Operation::SectorErase));
+        assert!(!flash.is_suspended());
+
+        flash.wait_done().await.unwrap();
+        assert_eq!(flash.in_flight(), None);
+    });
+    assert_eq!(sim.read(0x1000, 16), [0xFF; 16]);
+}
+
+#[test]
+fn suspend_waits_for_latency() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.start_erase_sector(0x1000).await.unwrap();
+
+        let before = sim.now_ns();
+        assert!(flash.suspend().await.unwrap());
+        assert!(sim.now_ns() - before >= 20_000);
+        assert!(flash.is_suspended());
+        assert!(flash.read_status2().await.unwrap().contains(Status2::SUS));
+        assert!(!flash.read_status().await.unwrap().contains(Status::BUSY));
+
+        // A second suspend is a no-op.
+        sim.clear_commands();
+        assert!(flash.suspend().await.unwrap());
+        assert!(sim.commands().is_empty());
+    });
+}
+
+#[test]
+fn suspend_after_resume_waits_for_trs() {
+    let sim = FlashSim::new(W25Q80);
+    let timing = SimTiming::default();
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.start_erase_sector(0x1000).await.unwrap();
+        assert!(flash.suspend().await.unwrap());
+        flash.resume().await.unwrap();
+        assert!(!flash.is_suspended());
+
+        // A suspend issued before tRS has passed would only take effect late.
+        let before = sim.now_ns();
+        assert!(flash.suspend().await.unwrap());
+        assert!(sim.now_ns() - before >= timing.resume_to_suspend_ns + timing.suspend_latency_ns);
+        flash.wait_done().await.unwrap();
+    });
+}
+
+#[test]
+fn suspended_time_does_not_count() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.start_erase_sector(0x1000).await.unwrap();
+        sim.advance(20 * MS);
+        assert!(flash.suspend().await.unwrap());
+
+        // Far longer than the erase, but it stays suspended.
+        sim.advance(500 * MS);
+        assert!(flash.read_status2().await.unwrap().contains(Status2::SUS));
+        flash.resume().await.unwrap();
+
+        // About 25 ms of the erase are left.
+        sim.advance(20 * MS);
+        assert!(flash.read_status().await.unwrap().contains(Status::BUSY));
+        sim.advance(10 * MS);
+        assert!(!flash.read_status().await.unwrap().contains(Status::BUSY));
+    });
+}
+
+#[test]
+fn suspend_after_completion() {
+    let sim = FlashSim::new(W25Q80);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.start_erase_sector(0x1000).await.unwrap();
+        sim.advance(100 * MS);
+
+        // The erase is over: nothing is suspended and the driver knows it.
+        assert!(!flash.suspend().await.unwrap());
+        assert!(!flash.is_suspended());
+        assert_eq!(flash.in_flight(), None);
+    });
+}
+
+#[test]
+fn chip_erase_is_not_suspended() {
+    let sim = FlashSim::new(W25Q80);
+    sim.load(0x8000, &[0x00; 4]);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.start_erase_all().await.unwrap();
+        sim.clear_commands();
+        assert!(!flash.suspend().await.unwrap());
+        assert!(sim.commands().is_empty());
+
+        // The read waits for the erase instead.
+        let mut buf = [0; 4];
+        flash.read(0x8000, &mut buf).await.unwrap();
+        assert_eq!(buf, [0xFF; 4]);
+        assert!(!sim.commands().contains(&SUSPEND));
+        assert_eq!(flash.in_flight(), None);
+    });
+}
+
+#[test]
+fn write_finishes_a_suspended_erase() {
+    let sim = FlashSim::new(W25Q80);
+    sim.load(0x0000, &[0x5A; 4]);
+    block_on(async {
+        let mut flash = common::driver(&sim).await;
+        flash.erase_sector(0x1000).await.unwrap();
+        flash.start_erase_sector(0x2000).await.unwrap();
+        flash.suspend().await.unwrap();
+
+        // Writes finish the suspended erase before programming.
+        flash.write(0x1000, &[0x12; 4]).await.unwrap();
+        assert_eq!(flash.in_flight(), None);
+        assert!(!flash.is_suspended());
+
+        let mut buf = [0; 4];
+        flash.read(0x0000, &mut buf).await.unwrap();
+        assert_eq!(buf, [0x5A; 4]);
+    });
+    assert_eq!(sim.read(0x1000, 4), [0x12; 4]);
+    assert_eq!(sim.read(0x2000, 4), [0xFF; 4]);
+}